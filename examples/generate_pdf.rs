@@ -1,7 +1,6 @@
 use lopdf::{Document, Object, Stream, Dictionary};
 use lopdf::content::{Content, Operation};
 use image::{ImageBuffer, Rgb};
-use std::io::Cursor;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut doc = Document::with_version("1.5");
@@ -62,7 +61,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // High quality (95) to simulate an unoptimized source
     {
         let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg_data, 95);
-        encoder.encode(img_buf.as_bytes(), width, height, image::ColorType::Rgb8).unwrap();
+        encoder.encode(img_buf.as_raw(), width, height, image::ExtendedColorType::Rgb8).unwrap();
     }
     
     let image_stream = Stream::new(
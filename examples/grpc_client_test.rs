@@ -1,4 +1,7 @@
-use rustpdf::grpc::pb::{compression_service_client::CompressionServiceClient, CompressRequest, CompressionConfig};
+use rustpdf::grpc::pb::{
+    compress_stream_request, compress_stream_response, compression_service_client::CompressionServiceClient, CompressRequest,
+    CompressStreamRequest, CompressionConfig,
+};
 use std::fs;
 
 #[tokio::main]
@@ -33,6 +36,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             jpeg_quality: Some(30),
             max_dimension: Some(600),
             remove_metadata: Some(true),
+            ..Default::default()
         }),
     });
 
@@ -49,5 +53,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     fs::write(output_path, &result.compressed_pdf_data)?;
     println!("\nCompressed PDF saved to: {}", output_path);
 
+    // Same settings through the streaming RPC: the config first, then the PDF in 64 KB chunks
+    println!("\nSending the same PDF in chunks...");
+    let mut messages = vec![CompressStreamRequest {
+        payload: Some(compress_stream_request::Payload::Config(CompressionConfig {
+            jpeg_quality: Some(30),
+            max_dimension: Some(600),
+            remove_metadata: Some(true),
+            ..Default::default()
+        })),
+    }];
+    messages.extend(pdf_data.chunks(64 * 1024).map(|chunk| CompressStreamRequest {
+        payload: Some(compress_stream_request::Payload::Chunk(chunk.to_vec())),
+    }));
+
+    let mut responses = client.compress_pdf_stream(tokio_stream::iter(messages)).await?.into_inner();
+    let mut streamed = Vec::new();
+    while let Some(message) = responses.message().await? {
+        match message.payload {
+            Some(compress_stream_response::Payload::Chunk(chunk)) => streamed.extend_from_slice(&chunk),
+            Some(compress_stream_response::Payload::Stats(stats)) => {
+                println!("Streamed: {} bytes -> {} bytes ({:.2}%)", stats.original_size, stats.compressed_size, stats.compression_ratio);
+            }
+            None => {}
+        }
+    }
+    println!("Streamed output matches: {}", streamed == result.compressed_pdf_data);
+
     Ok(())
 }
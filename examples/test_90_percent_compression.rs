@@ -1,4 +1,4 @@
-use rustpdf::compression::{compress_pdf_with_config, CompressionConfig};
+use rustpdf::compression::{compress_pdf_with_config, compress_pdf_with_output, CompressionConfig, MetadataMode};
 use std::fs;
 
 fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -37,7 +37,8 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let config2 = CompressionConfig {
         jpeg_quality: 20,
         max_dimension: 400,
-        remove_metadata: true,
+        metadata: MetadataMode::Strip,
+        ..CompressionConfig::default()
     };
     let compressed2 = compress_pdf_with_config(&input_data, config2)?;
     let size2 = compressed2.len();
@@ -54,7 +55,8 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let config3 = CompressionConfig {
         jpeg_quality: 50,
         max_dimension: 1000,
-        remove_metadata: true,
+        metadata: MetadataMode::Strip,
+        ..CompressionConfig::default()
     };
     let compressed3 = compress_pdf_with_config(&input_data, config3)?;
     let size3 = compressed3.len();
@@ -66,11 +68,29 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     println!("  Saved to: examples/test_70_moderate.pdf");
     println!();
 
+    // Test 4: Search for a 90% reduction instead of fixing the settings
+    println!("Test 4: Target Ratio 90% (quality floor 10)");
+    let config4 = CompressionConfig {
+        target_ratio: Some(90.0),
+        ..CompressionConfig::default()
+    };
+    let output4 = compress_pdf_with_output(&input_data, config4)?;
+    let size4 = output4.data.len();
+    
+    fs::write("examples/test_90_target.pdf", &output4.data)?;
+    println!("  Compressed size: {} bytes ({:.2} MB)", size4, size4 as f64 / 1_048_576.0);
+    println!("  Compression ratio: {:.2}%", output4.achieved_ratio);
+    println!("  Final quality: {}, max dimension: {}", output4.final_config.jpeg_quality, output4.final_config.max_dimension);
+    println!("  Target reached: {:?}", output4.target_reached);
+    println!("  Saved to: examples/test_90_target.pdf");
+    println!();
+
     println!("=== Summary ===");
     println!("Original: {:.2} MB", original_size as f64 / 1_048_576.0);
     println!("Default (90% target): {:.2} MB ({:.2}% reduction)", size1 as f64 / 1_048_576.0, ratio1);
     println!("Ultra (95% target): {:.2} MB ({:.2}% reduction)", size2 as f64 / 1_048_576.0, ratio2);
     println!("Moderate (70% target): {:.2} MB ({:.2}% reduction)", size3 as f64 / 1_048_576.0, ratio3);
+    println!("Target ratio (90% target): {:.2} MB ({:.2}% reduction)", size4 as f64 / 1_048_576.0, output4.achieved_ratio);
 
     Ok(())
 }
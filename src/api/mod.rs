@@ -1,23 +1,36 @@
 use axum::{
-    extract::{Multipart, Query},
+    extract::{Multipart, Path, Query, State},
     routing::{post, get},
-    Router,
+    Json, Router,
     response::{IntoResponse, Response},
-    body::Body,
+    body::{Body, Bytes},
 };
 use axum::http::{StatusCode, header};
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
-use crate::compression::{compress_pdf_with_config, CompressionConfig};
+use crate::compression::{
+    compress_pdf_with_output, lossless_mode, CmykHandling, CompressionConfig, CompressionError, CompressionOutput,
+    CompressionPreset, CompressionReport, FlateEffort, ImageAction, ImageReport, MetadataMode,
+};
+use crate::jobs::{JobResult, JobState, JobStatus, JobStore};
+use std::sync::Arc;
+use base64::Engine;
 use serde::{Deserialize, Serialize};
 
 #[derive(OpenApi)]
 #[openapi(
     paths(
         compress_handler_multipart,
+        create_job_handler,
+        job_status_handler,
+        job_result_handler,
+        cancel_job_handler,
     ),
     components(
-        schemas(CompressionQueryParams, CompressionStats)
+        schemas(
+            CompressionQueryParams, CompressionPreset, FlateEffort, MetadataMode, CompressionStats, CompressionResult,
+            CompressionReport, ImageReport, ImageAction, JobStatus, JobState
+        )
     ),
     tags(
         (name = "compression", description = "PDF Compression API - Target 90% size reduction")
@@ -27,38 +40,219 @@ pub struct ApiDoc;
 
 use axum::extract::DefaultBodyLimit;
 
-pub fn app_router() -> Router {
+/// Routes of the REST API. `jobs` holds the jobs created with `POST /jobs`
+pub fn app_router(jobs: Arc<JobStore>) -> Router {
     Router::new()
         .merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", ApiDoc::openapi()))
         .route("/compress", post(compress_handler_multipart))
+        .route("/jobs", post(create_job_handler))
+        .route("/jobs/:id", get(job_status_handler).delete(cancel_job_handler))
+        .route("/jobs/:id/result", get(job_result_handler))
         .route("/health", get(|| async { "OK" }))
         .layer(DefaultBodyLimit::max(50 * 1024 * 1024)) // 50MB limit
+        .with_state(jobs)
 }
 
-#[derive(Debug, Deserialize, utoipa::IntoParams, utoipa::ToSchema)]
+/// Query parameters for compression. Fields that are set override the preset
+/// (or the defaults when no preset is given).
+#[derive(Debug, Default, Deserialize, utoipa::IntoParams, utoipa::ToSchema)]
 pub struct CompressionQueryParams {
+    /// Named settings bundle: screen, ebook, printer, prepress or archive
+    #[serde(default)]
+    pub preset: Option<CompressionPreset>,
     /// JPEG quality (1-100). Lower = smaller file. Default: 30 for 90% reduction
-    #[serde(default = "default_quality")]
-    pub quality: u8,
+    #[serde(default)]
+    pub quality: Option<u8>,
     /// Maximum dimension in pixels. Default: 600 for 90% reduction
-    #[serde(default = "default_max_dim")]
-    pub max_dimension: u32,
-    /// Remove metadata from PDF. Default: true
-    #[serde(default = "default_remove_metadata")]
-    pub remove_metadata: bool,
+    #[serde(default)]
+    pub max_dimension: Option<u32>,
+    /// Remove metadata from PDF (strip) or keep it as is (preserve). Default: true
+    #[serde(default)]
+    pub remove_metadata: Option<bool>,
+    /// Metadata handling: strip, preserve or rewrite. Overrides remove_metadata. Default: strip
+    #[serde(default)]
+    pub metadata: Option<MetadataMode>,
+    /// Comma-separated Info keys to keep, e.g. "Title,Author"
+    #[serde(default)]
+    pub keep_info_keys: Option<String>,
+    /// Title to set when rewriting metadata
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Author to set when rewriting metadata
+    #[serde(default)]
+    pub author: Option<String>,
+    /// Producer to set when rewriting metadata
+    #[serde(default)]
+    pub producer: Option<String>,
+    /// Keep CMYK images as CMYK JPEGs instead of converting them to RGB. Default: false
+    #[serde(default)]
+    pub preserve_cmyk: Option<bool>,
+    /// Minimum fraction (0.0-1.0) an image must shrink by to be replaced. Default: 0.05
+    #[serde(default)]
+    pub min_image_savings: Option<f32>,
+    /// Re-encode images losslessly (Flate) instead of as JPEG. Unset: only images with few colors
+    #[serde(default)]
+    pub lossless: Option<bool>,
+    /// Convert grayscale images to 1-bit CCITT G4, for black-and-white scans. Default: false
+    #[serde(default)]
+    pub threshold_grayscale: Option<bool>,
+    /// Downsample images to this resolution at their size on the page, instead of max_dimension
+    #[serde(default)]
+    pub target_dpi: Option<u32>,
+    /// Output size in bytes to aim for; quality and then resolution are lowered until it fits
+    #[serde(default)]
+    pub target_size: Option<u64>,
+    /// Size reduction in percent (0-100) to aim for, e.g. 90 for a tenth of the original size
+    #[serde(default)]
+    pub target_ratio: Option<f32>,
+    /// Lowest JPEG quality (1-100) the target size search may use. Default: 10
+    #[serde(default)]
+    pub min_quality: Option<u8>,
+    /// Drop unused fonts and subset embedded TrueType, OpenType, CFF and Type 1 fonts. Default: true
+    #[serde(default)]
+    pub subset_fonts: Option<bool>,
+    /// Merge identical images, fonts and other objects stored more than once. Default: true
+    #[serde(default)]
+    pub deduplicate: Option<bool>,
+    /// Write PDF 1.5 object streams and a cross-reference stream. Default: true
+    #[serde(default)]
+    pub object_streams: Option<bool>,
+    /// Remove redundant operators from page and form content streams. Default: true
+    #[serde(default)]
+    pub optimize_content: Option<bool>,
+    /// Decimal places content stream coordinates are rounded to, from 0 (whole
+    /// units) to 6; larger values are treated as 6. Default: 3
+    #[serde(default)]
+    pub content_decimals: Option<u8>,
+    /// Recompression of non-image Flate streams: off, best or exhaustive (Zopfli). Default: best
+    #[serde(default)]
+    pub flate_effort: Option<FlateEffort>,
+    /// Remove page thumbnails. Default: true
+    #[serde(default)]
+    pub remove_thumbnails: Option<bool>,
+    /// Remove resource entries no content stream uses. Default: true
+    #[serde(default)]
+    pub remove_unused_resources: Option<bool>,
+    /// Remove application private data (/PieceInfo). Default: true
+    #[serde(default)]
+    pub remove_piece_info: Option<bool>,
+    /// Remove document scripts and JavaScript actions. Default: true
+    #[serde(default)]
+    pub remove_javascript: Option<bool>,
+    /// Remove form XObjects that are never painted. Default: true
+    #[serde(default)]
+    pub remove_orphan_forms: Option<bool>,
+    /// Worker threads used to recompress images. Default: one per CPU core
+    #[serde(default)]
+    pub threads: Option<usize>,
+    /// Respond with JSON holding the statistics, a per-image report and the PDF
+    /// (base64) instead of the bare PDF. Default: false
+    #[serde(default)]
+    pub report: Option<bool>,
 }
 
-fn default_quality() -> u8 { 30 }
-fn default_max_dim() -> u32 { 600 }
-fn default_remove_metadata() -> bool { true }
-
-impl Default for CompressionQueryParams {
-    fn default() -> Self {
-        Self {
-            quality: 30,
-            max_dimension: 600,
-            remove_metadata: true,
+impl CompressionQueryParams {
+    /// Start from the preset (or defaults) and apply every parameter that was given
+    fn to_config(&self) -> CompressionConfig {
+        let mut config = self.preset.map(CompressionPreset::config).unwrap_or_default();
+        if let Some(quality) = self.quality {
+            config.jpeg_quality = quality.clamp(1, 100);
+        }
+        if let Some(max_dimension) = self.max_dimension {
+            config.max_dimension = max_dimension;
+        }
+        if let Some(remove_metadata) = self.remove_metadata {
+            config.metadata = if remove_metadata { MetadataMode::Strip } else { MetadataMode::Preserve };
+        }
+        if let Some(metadata) = self.metadata {
+            config.metadata = metadata;
+        }
+        if let Some(keep_info_keys) = &self.keep_info_keys {
+            config.keep_info_keys =
+                keep_info_keys.split(',').map(str::trim).filter(|key| !key.is_empty()).map(String::from).collect();
+        }
+        if self.title.is_some() {
+            config.title = self.title.clone();
+        }
+        if self.author.is_some() {
+            config.author = self.author.clone();
+        }
+        if self.producer.is_some() {
+            config.producer = self.producer.clone();
+        }
+        if let Some(preserve_cmyk) = self.preserve_cmyk {
+            config.cmyk_handling = if preserve_cmyk { CmykHandling::Preserve } else { CmykHandling::ConvertToRgb };
+        }
+        if let Some(min_image_savings) = self.min_image_savings {
+            config.min_image_savings = min_image_savings;
+        }
+        if self.lossless.is_some() {
+            config.lossless = lossless_mode(self.lossless);
+        }
+        if let Some(threshold_grayscale) = self.threshold_grayscale {
+            config.threshold_grayscale = threshold_grayscale;
+        }
+        if self.target_dpi.is_some() {
+            config.target_dpi = self.target_dpi;
+        }
+        if self.target_size.is_some() {
+            config.target_size = self.target_size;
+        }
+        if self.target_ratio.is_some() {
+            config.target_ratio = self.target_ratio;
+        }
+        if let Some(min_quality) = self.min_quality {
+            config.min_jpeg_quality = min_quality.clamp(1, 100);
+        }
+        if let Some(subset_fonts) = self.subset_fonts {
+            config.subset_fonts = subset_fonts;
+        }
+        if let Some(deduplicate) = self.deduplicate {
+            config.deduplicate = deduplicate;
+        }
+        if let Some(object_streams) = self.object_streams {
+            config.object_streams = object_streams;
+        }
+        if let Some(optimize_content) = self.optimize_content {
+            config.optimize_content = optimize_content;
+        }
+        if let Some(content_decimals) = self.content_decimals {
+            config.content_decimals = content_decimals.min(6);
         }
+        if let Some(flate_effort) = self.flate_effort {
+            config.flate_effort = flate_effort;
+        }
+        if let Some(thumbnails) = self.remove_thumbnails {
+            config.cleanup.thumbnails = thumbnails;
+        }
+        if let Some(unused_resources) = self.remove_unused_resources {
+            config.cleanup.unused_resources = unused_resources;
+        }
+        if let Some(piece_info) = self.remove_piece_info {
+            config.cleanup.piece_info = piece_info;
+        }
+        if let Some(javascript) = self.remove_javascript {
+            config.cleanup.javascript = javascript;
+        }
+        if let Some(orphan_forms) = self.remove_orphan_forms {
+            config.cleanup.orphan_forms = orphan_forms;
+        }
+        if self.threads.is_some_and(|threads| threads > 0) {
+            config.threads = self.threads;
+        }
+        config
+    }
+}
+
+/// HTTP status for a failed compression: the client's document is at fault for
+/// everything but I/O errors and cancellation
+fn error_status(error: &CompressionError) -> StatusCode {
+    match error {
+        CompressionError::MalformedPdf(_) => StatusCode::BAD_REQUEST,
+        CompressionError::Encrypted | CompressionError::Unsupported(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        CompressionError::ResourceLimit(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        CompressionError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        CompressionError::Cancelled => StatusCode::GONE,
     }
 }
 
@@ -67,6 +261,28 @@ pub struct CompressionStats {
     pub original_size: u64,
     pub compressed_size: u64,
     pub compression_ratio: f32,
+    /// Thumbnails, unused resources, scripts and other objects removed by the cleanup pass
+    pub removed_items: u64,
+    /// Encoded size of the duplicate objects merged into one copy
+    pub bytes_reclaimed: u64,
+}
+
+/// Response body with `report=true`
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct CompressionResult {
+    pub stats: CompressionStats,
+    pub report: CompressionReport,
+    /// The compressed PDF, base64 encoded
+    pub pdf: String,
+}
+
+/// Query parameters for downloading a job's result
+#[derive(Debug, Default, Deserialize, utoipa::IntoParams)]
+pub struct JobResultParams {
+    /// Respond with JSON holding the statistics, a per-image report and the PDF
+    /// (base64) instead of the bare PDF. Default: false
+    #[serde(default)]
+    pub report: Option<bool>,
 }
 
 /// Compress a PDF file uploaded via multipart form
@@ -77,55 +293,202 @@ pub struct CompressionStats {
     request_body(content = String, description = "PDF file", content_type = "multipart/form-data"), 
     responses(
         (status = 200, description = "Compressed PDF with statistics in headers", body = String, content_type = "application/pdf"),
-        (status = 400, description = "Bad Request"),
+        (status = 200, description = "With report=true: statistics, per-image report and the base64 PDF", body = CompressionResult, content_type = "application/json"),
+        (status = 400, description = "Missing file field or malformed PDF"),
+        (status = 413, description = "Upload or document exceeds a size limit"),
+        (status = 422, description = "Encrypted PDF or unsupported PDF feature"),
         (status = 500, description = "Internal Server Error")
     )
 )]
 async fn compress_handler_multipart(
     Query(params): Query<CompressionQueryParams>,
-    mut multipart: Multipart
+    multipart: Multipart
+) -> Result<Response, (StatusCode, String)> {
+    let data = read_file_field(multipart).await?;
+    let original_size = data.len() as u64;
+
+    // Create compression config from query params
+    let config = params.to_config();
+
+    // Offload to blocking thread
+    let output = tokio::task::spawn_blocking(move || {
+        compress_pdf_with_output(&data, config)
+    }).await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (error_status(&e), e.to_string()))?;
+
+    compression_response(original_size, output, params.report.unwrap_or(false))
+}
+
+/// Queue a PDF for compression and return immediately
+#[utoipa::path(
+    post,
+    path = "/jobs",
+    params(CompressionQueryParams),
+    request_body(content = String, description = "PDF file", content_type = "multipart/form-data"),
+    responses(
+        (status = 202, description = "Job queued; poll its status at the Location header", body = JobStatus),
+        (status = 400, description = "Missing file field"),
+        (status = 413, description = "Upload exceeds the body limit"),
+        (status = 503, description = "Too many unfinished jobs")
+    )
+)]
+async fn create_job_handler(
+    State(jobs): State<Arc<JobStore>>,
+    Query(params): Query<CompressionQueryParams>,
+    multipart: Multipart,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
-    // Basic multipart handling: look for a field named "file" or just take the first file
-    while let Some(field) = multipart.next_field().await.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))? {
-        let name = field.name().unwrap_or("").to_string();
-        
-        if name == "file" {
-            let data = field.bytes().await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-            let original_size = data.len() as u64;
-            
-            // Create compression config from query params
-            let config = CompressionConfig {
-                jpeg_quality: params.quality,
-                max_dimension: params.max_dimension,
-                remove_metadata: params.remove_metadata,
-            };
-            
-            // Offload to blocking thread
-            let compressed = tokio::task::spawn_blocking(move || {
-                compress_pdf_with_config(&data, config)
-            }).await
-            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-
-            let compressed_size = compressed.len() as u64;
-            let compression_ratio = if original_size > 0 {
-                ((original_size - compressed_size) as f32 / original_size as f32) * 100.0
-            } else {
-                0.0
-            };
-
-            // Return as PDF with compression statistics in headers
-            return Ok(Response::builder()
-                .status(StatusCode::OK)
-                .header(header::CONTENT_TYPE, "application/pdf")
-                .header(header::CONTENT_DISPOSITION, "attachment; filename=\"compressed.pdf\"")
-                .header("X-Original-Size", original_size.to_string())
-                .header("X-Compressed-Size", compressed_size.to_string())
-                .header("X-Compression-Ratio", format!("{:.2}", compression_ratio))
-                .body(Body::from(compressed))
-                .unwrap());
+    let data = read_file_field(multipart).await?;
+    let status = jobs
+        .submit(data.to_vec(), params.to_config())
+        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e.to_string()))?;
+    let location = format!("/jobs/{}", status.id);
+    Ok((StatusCode::ACCEPTED, [(header::LOCATION, location)], Json(status)))
+}
+
+/// State and progress of a job
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    params(("id" = String, Path, description = "Job id")),
+    responses(
+        (status = 200, description = "Job status", body = JobStatus),
+        (status = 404, description = "Unknown, cancelled or evicted job")
+    )
+)]
+async fn job_status_handler(
+    State(jobs): State<Arc<JobStore>>,
+    Path(id): Path<String>,
+) -> Result<Json<JobStatus>, (StatusCode, String)> {
+    jobs.status(&id).map(Json).ok_or_else(|| job_not_found(&id))
+}
+
+/// Download the compressed PDF of a completed job
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}/result",
+    params(("id" = String, Path, description = "Job id"), JobResultParams),
+    responses(
+        (status = 200, description = "Compressed PDF with statistics in headers", body = String, content_type = "application/pdf"),
+        (status = 200, description = "With report=true: statistics, per-image report and the base64 PDF", body = CompressionResult, content_type = "application/json"),
+        (status = 404, description = "Unknown, cancelled or evicted job"),
+        (status = 409, description = "Job is still queued or running"),
+        (status = 400, description = "The job failed: malformed PDF (other codes as for /compress)")
+    )
+)]
+async fn job_result_handler(
+    State(jobs): State<Arc<JobStore>>,
+    Path(id): Path<String>,
+    Query(params): Query<JobResultParams>,
+) -> Result<Response, (StatusCode, String)> {
+    match jobs.result(&id).ok_or_else(|| job_not_found(&id))? {
+        JobResult::Pending => Err((StatusCode::CONFLICT, format!("Job {} is not finished", id))),
+        // The job's error, with the status /compress would have answered
+        JobResult::Failed(error) => Err((error_status(&error), error.to_string())),
+        JobResult::Completed { original_size, output } => {
+            compression_response(original_size, Arc::unwrap_or_clone(output), params.report.unwrap_or(false))
         }
     }
+}
+
+/// Cancel a job if it is unfinished and discard it with its result
+#[utoipa::path(
+    delete,
+    path = "/jobs/{id}",
+    params(("id" = String, Path, description = "Job id")),
+    responses(
+        (status = 204, description = "Job cancelled or discarded"),
+        (status = 404, description = "Unknown, cancelled or evicted job")
+    )
+)]
+async fn cancel_job_handler(
+    State(jobs): State<Arc<JobStore>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !jobs.cancel(&id) {
+        return Err(job_not_found(&id));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
 
+fn job_not_found(id: &str) -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, format!("No job with id {}", id))
+}
+
+/// Contents of the multipart field named "file"
+async fn read_file_field(mut multipart: Multipart) -> Result<Bytes, (StatusCode, String)> {
+    while let Some(field) = multipart.next_field().await.map_err(|e| (e.status(), e.body_text()))? {
+        if field.name() == Some("file") {
+            // Uploads over the body limit fail here with 413
+            return field.bytes().await.map_err(|e| (e.status(), e.body_text()));
+        }
+    }
     Err((StatusCode::BAD_REQUEST, "No file field found".to_string()))
 }
+
+/// The compressed PDF (or JSON with a report) with compression statistics in headers
+fn compression_response(original_size: u64, output: CompressionOutput, report: bool) -> Result<Response, (StatusCode, String)> {
+    let compressed = output.data;
+    let compressed_size = compressed.len() as u64;
+    let compression_ratio = if original_size > 0 {
+        ((original_size - compressed_size) as f32 / original_size as f32) * 100.0
+    } else {
+        0.0
+    };
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header("X-Original-Size", original_size.to_string())
+        .header("X-Compressed-Size", compressed_size.to_string())
+        .header("X-Compression-Ratio", format!("{:.2}", compression_ratio))
+        .header("X-Removed-Items", output.cleanup.total().to_string())
+        .header("X-Bytes-Reclaimed", output.bytes_reclaimed.to_string());
+    if output.report.grew {
+        response = response.header("X-Output-Grew", "true");
+    }
+    if let Some(target_reached) = output.target_reached {
+        response = response
+            .header("X-Target-Reached", target_reached.to_string())
+            .header("X-Final-Quality", output.final_config.jpeg_quality.to_string())
+            .header("X-Achieved-Ratio", format!("{:.2}", output.achieved_ratio));
+    }
+    if report {
+        let result = CompressionResult {
+            stats: CompressionStats {
+                original_size,
+                compressed_size,
+                compression_ratio,
+                removed_items: output.cleanup.total() as u64,
+                bytes_reclaimed: output.bytes_reclaimed as u64,
+            },
+            report: output.report,
+            pdf: base64::engine::general_purpose::STANDARD.encode(&compressed),
+        };
+        let body = serde_json::to_vec(&result).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        return Ok(response.header(header::CONTENT_TYPE, "application/json").body(Body::from(body)).unwrap());
+    }
+    response = response
+        .header(header::CONTENT_TYPE, "application/pdf")
+        .header(header::CONTENT_DISPOSITION, "attachment; filename=\"compressed.pdf\"");
+    Ok(response.body(Body::from(compressed)).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_map_to_status_codes() {
+        let cases = [
+            (CompressionError::MalformedPdf("no header".to_string()), StatusCode::BAD_REQUEST),
+            (CompressionError::Encrypted, StatusCode::UNPROCESSABLE_ENTITY),
+            (CompressionError::Unsupported("PubSec security handler".to_string()), StatusCode::UNPROCESSABLE_ENTITY),
+            (CompressionError::ResourceLimit("too large".to_string()), StatusCode::PAYLOAD_TOO_LARGE),
+            (CompressionError::Io(std::io::Error::other("disk full")), StatusCode::INTERNAL_SERVER_ERROR),
+            (CompressionError::Cancelled, StatusCode::GONE),
+        ];
+        for (error, status) in cases {
+            assert_eq!(error_status(&error), status, "{}", error);
+        }
+    }
+}
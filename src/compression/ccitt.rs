@@ -0,0 +1,143 @@
+//! CCITT Group 4 (T.6) coding of bilevel images, built on the `fax` crate.
+//!
+//! Samples are packed 1 bit per pixel, MSB first, with rows padded to a byte, the
+//! same layout as an uncompressed 1-bit PDF image. With the default /BlackIs1 false,
+//! 0 bits are fax black and 1 bits are fax white.
+
+use fax::decoder::{decode_g4, pels};
+use fax::encoder::Encoder;
+use fax::{Color, VecWriter};
+use super::report::SkipReason;
+use lopdf::Dictionary;
+
+type CcittResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Encode packed 1-bit rows as Group 4 data for `/CCITTFaxDecode` with `/K -1`
+pub(crate) fn encode_g4(packed: &[u8], width: u32, height: u32) -> CcittResult<Vec<u8>> {
+    let width = u16::try_from(width).map_err(|_| "Image is too wide for CCITT encoding")?;
+    let row_bytes = (width as usize).div_ceil(8);
+    let mut encoder = Encoder::new(VecWriter::new());
+    for row in packed.chunks(row_bytes).take(height as usize) {
+        let pels = (0..width as usize).map(|x| {
+            if row.get(x / 8).is_some_and(|byte| byte & (0x80 >> (x % 8)) != 0) {
+                Color::White
+            } else {
+                Color::Black
+            }
+        });
+        encoder.encode_line(pels, width)?;
+    }
+    Ok(encoder.finish()?.finish())
+}
+
+/// Decode a Group 4 `/CCITTFaxDecode` stream into packed 1-bit rows
+pub(crate) fn decode(data: &[u8], parms: Option<&Dictionary>, width: u32, height: u32) -> CcittResult<Vec<u8>> {
+    let param = |key: &[u8], default: i64| parms.and_then(|p| p.get(key).and_then(|v| v.as_i64()).ok()).unwrap_or(default);
+    let flag = |key: &[u8]| parms.and_then(|p| p.get(key).and_then(|v| v.as_bool()).ok()).unwrap_or(false);
+
+    if param(b"K", 0) >= 0 {
+        return Err(SkipReason("CCITT Group 3 images are not supported".to_string()).into());
+    }
+    if flag(b"EncodedByteAlign") {
+        return Err(SkipReason("Byte-aligned CCITT data is not supported".to_string()).into());
+    }
+    let columns = param(b"Columns", width as i64);
+    if columns != width as i64 {
+        return Err(format!("CCITT Columns {} does not match image width {}", columns, width).into());
+    }
+    let width = u16::try_from(width).map_err(|_| "Image is too wide for CCITT decoding")?;
+    let height = u16::try_from(height).map_err(|_| "Image is too tall for CCITT decoding")?;
+    let black_is_1 = flag(b"BlackIs1");
+
+    let row_bytes = (width as usize).div_ceil(8);
+    let mut out = Vec::with_capacity(row_bytes * height as usize);
+    decode_g4(data.iter().copied(), width, Some(height), |transitions| {
+        let mut row = vec![0u8; row_bytes];
+        for (x, color) in pels(transitions, width).enumerate() {
+            if (color == Color::White) != black_is_1 {
+                row[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        out.extend_from_slice(&row);
+    })
+    .ok_or("Invalid CCITT Group 4 data")?;
+    out.truncate(row_bytes * height as usize);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{dictionary, Object};
+
+    const WIDTH: u32 = 37;
+    const HEIGHT: u32 = 20;
+
+    /// Packed rows of a pattern with runs of different lengths, padding bits clear
+    fn image() -> Vec<u8> {
+        let row_bytes = (WIDTH as usize).div_ceil(8);
+        let mut packed = vec![0u8; row_bytes * HEIGHT as usize];
+        for y in 0..HEIGHT as usize {
+            for x in 0..WIDTH as usize {
+                let white = (x + y) % 7 < 3 || (x * y) % 11 == 0 || y == 5;
+                if white {
+                    packed[y * row_bytes + x / 8] |= 0x80 >> (x % 8);
+                }
+            }
+        }
+        packed
+    }
+
+    fn g4_parms() -> Dictionary {
+        dictionary! { "K" => -1, "Columns" => WIDTH as i64, "Rows" => HEIGHT as i64 }
+    }
+
+    #[test]
+    fn group_4_round_trip() {
+        let packed = image();
+        let encoded = encode_g4(&packed, WIDTH, HEIGHT).unwrap();
+        assert_eq!(decode(&encoded, Some(&g4_parms()), WIDTH, HEIGHT).unwrap(), packed);
+    }
+
+    #[test]
+    fn black_is_1_inverts_the_samples() {
+        let packed = image();
+        let encoded = encode_g4(&packed, WIDTH, HEIGHT).unwrap();
+        let mut parms = g4_parms();
+        parms.set("BlackIs1", Object::Boolean(true));
+
+        let decoded = decode(&encoded, Some(&parms), WIDTH, HEIGHT).unwrap();
+
+        let row_bytes = (WIDTH as usize).div_ceil(8);
+        for y in 0..HEIGHT as usize {
+            for x in 0..WIDTH as usize {
+                let bit = |data: &[u8]| data[y * row_bytes + x / 8] & (0x80 >> (x % 8)) != 0;
+                assert_ne!(bit(&decoded), bit(&packed), "pixel {} {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn group_3_and_byte_aligned_data_are_skipped() {
+        let encoded = encode_g4(&image(), WIDTH, HEIGHT).unwrap();
+        let mut group_3 = g4_parms();
+        group_3.set("K", 0);
+        let mut aligned = g4_parms();
+        aligned.set("EncodedByteAlign", Object::Boolean(true));
+
+        for parms in [group_3, aligned] {
+            let error = decode(&encoded, Some(&parms), WIDTH, HEIGHT).unwrap_err();
+            assert!(error.downcast_ref::<SkipReason>().is_some(), "{}", error);
+        }
+    }
+
+    #[test]
+    fn columns_must_match_the_width() {
+        let encoded = encode_g4(&image(), WIDTH, HEIGHT).unwrap();
+        let mut parms = g4_parms();
+        parms.set("Columns", 40);
+
+        let error = decode(&encoded, Some(&parms), WIDTH, HEIGHT).unwrap_err();
+        assert!(error.downcast_ref::<SkipReason>().is_none());
+    }
+}
@@ -0,0 +1,456 @@
+//! Cleanup: page thumbnails, application private data (/PieceInfo), JavaScript
+//! and resource entries no content stream uses are removed. The objects they
+//! pointed to are left for `prune_objects`.
+//!
+//! Resource usage is collected the same way as for fonts, and a resource
+//! dictionary is only cleaned when every content stream that can see it was
+//! parsed completely and nothing else (a Type 3 font, form field defaults) uses it.
+
+use super::content::decode_complete;
+use super::{CleanupOptions, CleanupStats, MAX_FORM_DEPTH};
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::collections::BTreeSet;
+
+/// Resource categories whose entries are selected by name from content streams
+const NAMED_CATEGORIES: [&[u8]; 7] = [b"Font", b"XObject", b"ExtGState", b"ColorSpace", b"Pattern", b"Shading", b"Properties"];
+
+/// Where a resource dictionary lives: an object of its own, or inline in the
+/// dictionary of the page or stream that owns it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum ResourceKey {
+    Indirect(ObjectId),
+    Inline(ObjectId),
+}
+
+/// Resource names content streams select, per resource dictionary
+#[derive(Default)]
+struct ResourceUsage {
+    used: BTreeSet<(ResourceKey, &'static [u8], Vec<u8>)>,
+    walked: BTreeSet<ResourceKey>,
+    unknown: BTreeSet<ResourceKey>,
+    walked_owners: BTreeSet<ObjectId>,
+}
+
+/// Run the cleanup steps enabled in `options`
+pub(crate) fn clean_up(doc: &mut Document, options: &CleanupOptions) -> CleanupStats {
+    let mut stats = CleanupStats::default();
+    if options.thumbnails {
+        stats.thumbnails = remove_thumbnails(doc);
+    }
+    if options.piece_info {
+        stats.piece_info = remove_piece_info(doc);
+    }
+    if options.javascript {
+        stats.javascript = remove_javascript(doc);
+    }
+    if options.unused_resources || options.orphan_forms {
+        let (unused, forms) = remove_unused_resources(doc, options);
+        stats.unused_resources = unused;
+        stats.orphan_forms = forms;
+    }
+    stats
+}
+
+fn remove_thumbnails(doc: &mut Document) -> usize {
+    let mut removed = 0;
+    for page_id in doc.get_pages().into_values() {
+        if let Ok(page) = doc.get_dictionary_mut(page_id) {
+            removed += page.remove(b"Thumb").is_some() as usize;
+        }
+    }
+    removed
+}
+
+/// Drop /PieceInfo from every dictionary and stream; it only means something to
+/// the application that wrote it
+fn remove_piece_info(doc: &mut Document) -> usize {
+    let mut removed = 0;
+    for object in doc.objects.values_mut() {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => &mut stream.dict,
+            _ => continue,
+        };
+        removed += dict.remove(b"PieceInfo").is_some() as usize;
+    }
+    removed
+}
+
+// ---------------------------------------------------------------------------
+// JavaScript
+
+/// Remove the document-level script name tree and every JavaScript action:
+/// open actions, link and widget actions, additional actions and action chains
+fn remove_javascript(doc: &mut Document) -> usize {
+    let mut removed = 0;
+
+    let names_id = doc.catalog().ok().and_then(|c| c.get(b"Names").ok()).and_then(|n| n.as_reference().ok());
+    let names = match names_id {
+        Some(id) => doc.get_dictionary_mut(id).ok(),
+        None => doc.catalog_mut().ok().and_then(|c| c.get_mut(b"Names").ok()).and_then(|n| n.as_dict_mut().ok()),
+    };
+    if let Some(names) = names {
+        removed += names.remove(b"JavaScript").is_some() as usize;
+    }
+
+    let scripts: BTreeSet<ObjectId> = doc
+        .objects
+        .iter()
+        .filter(|(_, object)| object.as_dict().is_ok_and(is_javascript_action))
+        .map(|(id, _)| *id)
+        .collect();
+    for object in doc.objects.values_mut() {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => &mut stream.dict,
+            _ => continue,
+        };
+        removed += remove_actions(dict, &scripts);
+    }
+    removed
+}
+
+fn is_javascript_action(dict: &Dictionary) -> bool {
+    // Rendition actions can carry a script too
+    matches!(dict.get(b"S").and_then(Object::as_name), Ok(b"JavaScript")) || dict.has(b"JS")
+}
+
+/// Remove action entries that run scripts from `dict` and the dictionaries nested
+/// in it. `scripts` are the indirect JavaScript action objects.
+fn remove_actions(dict: &mut Dictionary, scripts: &BTreeSet<ObjectId>) -> usize {
+    let is_script = |value: &Object| match value {
+        Object::Reference(id) => scripts.contains(id),
+        Object::Dictionary(action) => is_javascript_action(action),
+        _ => false,
+    };
+    let mut removed = 0;
+    for key in [b"A".as_slice(), b"OpenAction", b"Next"] {
+        if dict.get(key).is_ok_and(is_script) {
+            dict.remove(key);
+            removed += 1;
+        }
+    }
+    if let Ok(Object::Dictionary(additional)) = dict.get_mut(b"AA") {
+        let triggers: Vec<Vec<u8>> = additional.iter().filter(|(_, a)| is_script(a)).map(|(k, _)| k.clone()).collect();
+        for trigger in &triggers {
+            additional.remove(trigger);
+        }
+        removed += triggers.len();
+        if additional.is_empty() {
+            dict.remove(b"AA");
+        }
+    }
+    // Inline annotations, additional actions and action chains
+    for (_, value) in dict.iter_mut() {
+        removed += match value {
+            Object::Dictionary(nested) => remove_actions(nested, scripts),
+            Object::Array(items) => items
+                .iter_mut()
+                .filter_map(|item| item.as_dict_mut().ok())
+                .map(|nested| remove_actions(nested, scripts))
+                .sum(),
+            _ => 0,
+        };
+    }
+    removed
+}
+
+// ---------------------------------------------------------------------------
+// Unused resources
+
+/// Remove named resources nothing selects. Returns the number of entries removed
+/// and, separately, how many of them were form XObjects.
+fn remove_unused_resources(doc: &mut Document, options: &CleanupOptions) -> (usize, usize) {
+    let usage = collect_usage(doc);
+
+    let mut removals: Vec<(ResourceKey, &'static [u8], Vec<u8>)> = Vec::new();
+    let (mut unused, mut forms) = (0, 0);
+    for &key in usage.walked.difference(&usage.unknown) {
+        let Some(resources) = resource_dict(doc, key) else { continue };
+        for category in NAMED_CATEGORIES {
+            // Category dictionaries of their own may be shared with resources we did not see
+            let Ok(Object::Dictionary(entries)) = resources.get(category) else { continue };
+            for (name, value) in entries.iter() {
+                if usage.used.contains(&(key, category, name.clone())) {
+                    continue;
+                }
+                let is_form = category == b"XObject"
+                    && doc
+                        .dereference(value)
+                        .ok()
+                        .and_then(|(_, v)| v.as_stream().ok())
+                        .is_some_and(|s| matches!(s.dict.get(b"Subtype").and_then(Object::as_name), Ok(b"Form")));
+                let enabled = if is_form { options.orphan_forms } else { options.unused_resources };
+                if !enabled {
+                    continue;
+                }
+                if is_form {
+                    forms += 1;
+                } else {
+                    unused += 1;
+                }
+                removals.push((key, category, name.clone()));
+            }
+        }
+    }
+
+    for (key, category, name) in removals {
+        let resources = match key {
+            ResourceKey::Indirect(id) => doc.get_dictionary_mut(id).ok(),
+            ResourceKey::Inline(owner) => owner_dict_mut(doc, owner)
+                .and_then(|o| o.get_mut(b"Resources").ok())
+                .and_then(|r| r.as_dict_mut().ok()),
+        };
+        if let Some(Ok(Object::Dictionary(entries))) = resources.map(|r| r.get_mut(category)) {
+            entries.remove(&name);
+        }
+    }
+    (unused, forms)
+}
+
+fn collect_usage(doc: &Document) -> ResourceUsage {
+    let mut usage = ResourceUsage::default();
+
+    for page_id in doc.get_pages().into_values() {
+        let scope = page_scope(doc, page_id, &mut usage.walked_owners);
+        let parts: Option<Vec<Vec<u8>>> = doc
+            .get_page_contents(page_id)
+            .iter()
+            .map(|id| doc.get_object(*id).and_then(Object::as_stream).ok())
+            .map(|s| s.map(|s| s.decompressed_content().unwrap_or_else(|_| s.content.clone())))
+            .collect();
+        match parts {
+            // Streams split at token boundaries and are read as if separated by whitespace
+            Some(parts) => walk_content(doc, &parts.join(&b'\n'), &scope, &mut BTreeSet::new(), &mut usage),
+            None => mark_unknown(&scope, &mut usage),
+        }
+    }
+
+    // Form XObjects, patterns and annotation appearances that carry their own
+    // resources, wherever they are referenced from
+    for (id, object) in &doc.objects {
+        let Ok(stream) = object.as_stream() else { continue };
+        if matches!(stream.dict.get(b"Subtype").and_then(Object::as_name), Ok(b"Image")) {
+            continue;
+        }
+        let Some(scope) = own_scope(doc, *id, &stream.dict) else { continue };
+        usage.walked_owners.insert(*id);
+        let data = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+        walk_content(doc, &data, &[scope], &mut BTreeSet::from([*id]), &mut usage);
+    }
+
+    // Anything else with resources (Type 3 fonts, form field defaults) may select
+    // any of them, so resource dictionaries shared with it are left alone
+    for (id, object) in &doc.objects {
+        if !usage.walked_owners.contains(id) {
+            mark_foreign_resources(object, &mut usage.unknown);
+        }
+    }
+
+    usage
+}
+
+/// The resource dictionaries a page sees: its own, then those inherited from
+/// its ancestors in the page tree
+fn page_scope<'a>(doc: &'a Document, page_id: ObjectId, owners: &mut BTreeSet<ObjectId>) -> Vec<(ResourceKey, &'a Dictionary)> {
+    let mut scope = Vec::new();
+    let mut node_id = Some(page_id);
+    let mut seen = BTreeSet::new();
+    while let Some(id) = node_id.filter(|id| seen.insert(*id)) {
+        let Ok(node) = doc.get_dictionary(id) else { break };
+        owners.insert(id);
+        scope.extend(own_scope(doc, id, node));
+        node_id = node.get(b"Parent").and_then(Object::as_reference).ok();
+    }
+    scope
+}
+
+fn own_scope<'a>(doc: &'a Document, owner: ObjectId, dict: &'a Dictionary) -> Option<(ResourceKey, &'a Dictionary)> {
+    match dict.get(b"Resources").ok()? {
+        Object::Reference(id) => Some((ResourceKey::Indirect(*id), doc.get_dictionary(*id).ok()?)),
+        Object::Dictionary(resources) => Some((ResourceKey::Inline(owner), resources)),
+        _ => None,
+    }
+}
+
+fn resource_dict(doc: &Document, key: ResourceKey) -> Option<&Dictionary> {
+    match key {
+        ResourceKey::Indirect(id) => doc.get_dictionary(id).ok(),
+        ResourceKey::Inline(owner) => match doc.get_object(owner).ok()? {
+            Object::Dictionary(dict) => dict.get(b"Resources").ok()?.as_dict().ok(),
+            Object::Stream(stream) => stream.dict.get(b"Resources").ok()?.as_dict().ok(),
+            _ => None,
+        },
+    }
+}
+
+fn owner_dict_mut(doc: &mut Document, owner: ObjectId) -> Option<&mut Dictionary> {
+    match doc.get_object_mut(owner).ok()? {
+        Object::Dictionary(dict) => Some(dict),
+        Object::Stream(stream) => Some(&mut stream.dict),
+        _ => None,
+    }
+}
+
+fn walk_content(
+    doc: &Document,
+    content: &[u8],
+    scope: &[(ResourceKey, &Dictionary)],
+    active_forms: &mut BTreeSet<ObjectId>,
+    usage: &mut ResourceUsage,
+) {
+    usage.walked.extend(scope.iter().map(|(key, _)| *key));
+    let Some(content) = decode_complete(content) else {
+        // Without the operators we cannot tell which resources are selected
+        mark_unknown(scope, usage);
+        return;
+    };
+
+    for operation in &content.operations {
+        let operands = &operation.operands;
+        let (category, name): (&'static [u8], _) = match operation.operator.as_str() {
+            "Tf" => (b"Font", operands.first()),
+            "Do" => (b"XObject", operands.first()),
+            "gs" => (b"ExtGState", operands.first()),
+            "cs" | "CS" => (b"ColorSpace", operands.first()),
+            "scn" | "SCN" => (b"Pattern", operands.last()),
+            "sh" => (b"Shading", operands.first()),
+            "BDC" | "DP" => (b"Properties", operands.get(1)),
+            _ => continue,
+        };
+        let Some(Ok(name)) = name.map(Object::as_name) else { continue };
+        let Some((key, value)) = lookup(doc, scope, category, name) else { continue };
+        usage.used.insert((key, category, name.to_vec()));
+
+        // Forms without resources of their own use the resources of whoever paints them
+        if category != b"XObject" {
+            continue;
+        }
+        let Ok(xobject_id) = value.as_reference() else { continue };
+        let Ok(stream) = doc.get_object(xobject_id).and_then(Object::as_stream) else { continue };
+        let is_form = matches!(stream.dict.get(b"Subtype").and_then(Object::as_name), Ok(b"Form"));
+        if is_form && !stream.dict.has(b"Resources") && active_forms.len() < MAX_FORM_DEPTH && active_forms.insert(xobject_id) {
+            let data = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+            walk_content(doc, &data, scope, active_forms, usage);
+            active_forms.remove(&xobject_id);
+        }
+    }
+}
+
+/// The first resource dictionary in scope that defines `name`, and the entry
+fn lookup<'a>(doc: &'a Document, scope: &[(ResourceKey, &'a Dictionary)], category: &[u8], name: &[u8]) -> Option<(ResourceKey, &'a Object)> {
+    scope.iter().find_map(|(key, resources)| {
+        let (_, entries) = doc.dereference(resources.get(category).ok()?).ok()?;
+        Some((*key, entries.as_dict().ok()?.get(name).ok()?))
+    })
+}
+
+fn mark_unknown(scope: &[(ResourceKey, &Dictionary)], usage: &mut ResourceUsage) {
+    usage.unknown.extend(scope.iter().map(|(key, _)| *key));
+}
+
+/// Mark resource dictionaries referenced from an object we did not walk
+fn mark_foreign_resources(object: &Object, unknown: &mut BTreeSet<ResourceKey>) {
+    match object {
+        Object::Dictionary(dict) => mark_foreign_dict(dict, unknown),
+        Object::Stream(stream) => mark_foreign_dict(&stream.dict, unknown),
+        Object::Array(items) => {
+            for item in items {
+                mark_foreign_resources(item, unknown);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn mark_foreign_dict(dict: &Dictionary, unknown: &mut BTreeSet<ResourceKey>) {
+    for (key, value) in dict.iter() {
+        match value {
+            Object::Reference(id) if key == b"Resources" || key == b"DR" => {
+                unknown.insert(ResourceKey::Indirect(*id));
+            }
+            _ => mark_foreign_resources(value, unknown),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{dictionary, Stream};
+
+    fn form(content: &str, resources: Option<Dictionary>) -> Stream {
+        let mut dict = dictionary! { "Type" => "XObject", "Subtype" => "Form", "BBox" => vec![0.into(), 0.into(), 10.into(), 10.into()] };
+        if let Some(resources) = resources {
+            dict.set("Resources", resources);
+        }
+        Stream::new(dict, content.as_bytes().to_vec())
+    }
+
+    fn font(doc: &mut Document, name: &str) -> ObjectId {
+        doc.add_object(dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => name })
+    }
+
+    /// Add a page painting /X1 with `resources`, making `doc` a one page document
+    fn add_page(doc: &mut Document, resources: Dictionary) -> ObjectId {
+        let content = doc.add_object(Stream::new(Dictionary::new(), b"q /X1 Do Q".to_vec()));
+        let pages_id = doc.new_object_id();
+        let page = doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id, "Contents" => content, "Resources" => resources });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+        page
+    }
+
+    fn names(resources: &Dictionary, category: &[u8]) -> Vec<String> {
+        let entries = resources.get(category).and_then(Object::as_dict).unwrap();
+        entries.iter().map(|(name, _)| String::from_utf8_lossy(name).into_owned()).collect()
+    }
+
+    #[test]
+    fn resources_used_by_nested_forms_without_resources_are_kept() {
+        let mut doc = Document::with_version("1.5");
+        let used = font(&mut doc, "Helvetica");
+        let unused = font(&mut doc, "Courier");
+        let inner = doc.add_object(form("/GS1 gs BT /F1 12 Tf (x) Tj ET", None));
+        let outer = doc.add_object(form("/X2 Do", None));
+        let gs = doc.add_object(dictionary! { "Type" => "ExtGState", "CA" => 0.5 });
+        let resources = dictionary! {
+            "Font" => dictionary! { "F1" => used, "F9" => unused },
+            "XObject" => dictionary! { "X1" => outer, "X2" => inner },
+            "ExtGState" => dictionary! { "GS1" => gs },
+        };
+        let page = add_page(&mut doc, resources);
+
+        let stats = clean_up(&mut doc, &CleanupOptions::default());
+
+        assert_eq!(stats.unused_resources, 1);
+        assert_eq!(stats.orphan_forms, 0);
+        let resources = doc.get_dictionary(page).unwrap().get(b"Resources").and_then(Object::as_dict).unwrap();
+        assert_eq!(names(resources, b"Font"), ["F1"]);
+        assert_eq!(names(resources, b"XObject"), ["X1", "X2"]);
+        assert_eq!(names(resources, b"ExtGState"), ["GS1"]);
+    }
+
+    #[test]
+    fn resources_of_a_form_used_by_the_form_it_paints_are_kept() {
+        let mut doc = Document::with_version("1.5");
+        let used = font(&mut doc, "Helvetica");
+        let unused = font(&mut doc, "Courier");
+        let inner = doc.add_object(form("BT /F2 12 Tf (x) Tj ET", None));
+        let outer = doc.add_object(form(
+            "/X2 Do",
+            Some(dictionary! { "Font" => dictionary! { "F2" => used, "F8" => unused }, "XObject" => dictionary! { "X2" => inner } }),
+        ));
+        let page = add_page(&mut doc, dictionary! { "XObject" => dictionary! { "X1" => outer } });
+
+        let stats = clean_up(&mut doc, &CleanupOptions::default());
+
+        assert_eq!(stats.unused_resources, 1);
+        let page_resources = doc.get_dictionary(page).unwrap().get(b"Resources").and_then(Object::as_dict).unwrap();
+        assert_eq!(names(page_resources, b"XObject"), ["X1"]);
+        let form_resources = doc.get_object(outer).and_then(Object::as_stream).unwrap().dict.get(b"Resources").and_then(Object::as_dict).unwrap();
+        assert_eq!(names(form_resources, b"Font"), ["F2"]);
+        assert_eq!(names(form_resources, b"XObject"), ["X2"]);
+    }
+}
@@ -0,0 +1,240 @@
+use super::report::SkipReason;
+use lopdf::{Dictionary, Document, Object};
+
+/// Whether the image's /Decode array inverts every component ([1 0 1 0 ...]).
+pub(crate) fn decode_is_inverted(dict: &Dictionary) -> bool {
+    let Ok(Object::Array(decode)) = dict.get(b"Decode") else { return false };
+    !decode.is_empty()
+        && decode.chunks(2).all(|pair| {
+            let lo = pair.first().and_then(|v| v.as_float().ok());
+            let hi = pair.get(1).and_then(|v| v.as_float().ok());
+            lo == Some(1.0) && hi == Some(0.0)
+        })
+}
+
+pub(crate) fn invert(samples: &mut [u8]) {
+    for s in samples {
+        *s = 255 - *s;
+    }
+}
+
+/// Naive CMYK to RGB conversion (no ICC profile), 4 bytes in, 3 bytes out per pixel.
+pub(crate) fn cmyk_to_rgb(cmyk: &[u8]) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(cmyk.len() / 4 * 3);
+    for px in cmyk.chunks_exact(4) {
+        let k = 255 - px[3] as u16;
+        rgb.push(((255 - px[0] as u16) * k / 255) as u8);
+        rgb.push(((255 - px[1] as u16) * k / 255) as u8);
+        rgb.push(((255 - px[2] as u16) * k / 255) as u8);
+    }
+    rgb
+}
+
+/// An image color space resolved from the PDF object graph
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ColorSpace {
+    Gray,
+    Rgb,
+    Cmyk,
+    /// Palette image: each sample is an index into `lookup`, which holds
+    /// `hival + 1` colors in the `base` color space
+    Indexed { base: Box<ColorSpace>, hival: u8, lookup: Vec<u8> },
+}
+
+impl ColorSpace {
+    /// Number of color components per sample
+    pub(crate) fn components(&self) -> usize {
+        match self {
+            ColorSpace::Gray | ColorSpace::Indexed { .. } => 1,
+            ColorSpace::Rgb => 3,
+            ColorSpace::Cmyk => 4,
+        }
+    }
+}
+
+type ColorResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Resolve an image's /ColorSpace entry, following references, reading /N from
+/// ICC profile streams and loading Indexed lookup tables.
+pub(crate) fn resolve_color_space(doc: &Document, object: &Object) -> ColorResult<ColorSpace> {
+    let (_, object) = doc.dereference(object)?;
+    match object {
+        Object::Name(name) => device_color_space(name),
+        Object::Array(arr) => {
+            let family = match arr.first().map(|f| doc.dereference(f)) {
+                Some(Ok((_, Object::Name(name)))) => name.as_slice(),
+                _ => return Err("Color space array without a family name".into()),
+            };
+            match family {
+                b"ICCBased" => {
+                    let profile = arr.get(1).ok_or("ICCBased color space without profile")?;
+                    let (_, profile) = doc.dereference(profile)?;
+                    let dict = &profile.as_stream()?.dict;
+                    match dict.get(b"N").and_then(|n| n.as_i64()) {
+                        Ok(1) => Ok(ColorSpace::Gray),
+                        Ok(3) => Ok(ColorSpace::Rgb),
+                        Ok(4) => Ok(ColorSpace::Cmyk),
+                        _ => match dict.get(b"Alternate") {
+                            Ok(alternate) => resolve_color_space(doc, alternate),
+                            Err(_) => Err("ICC profile without a valid /N".into()),
+                        },
+                    }
+                }
+                b"Indexed" | b"I" => {
+                    let base = resolve_color_space(doc, arr.get(1).ok_or("Indexed color space without base")?)?;
+                    if matches!(base, ColorSpace::Indexed { .. }) {
+                        return Err("Indexed color space cannot have an Indexed base".into());
+                    }
+                    let hival = doc
+                        .dereference(arr.get(2).ok_or("Indexed color space without hival")?)?
+                        .1
+                        .as_i64()?
+                        .clamp(0, 255) as u8;
+                    let lookup = match doc.dereference(arr.get(3).ok_or("Indexed color space without lookup")?)?.1 {
+                        Object::String(bytes, _) => bytes.clone(),
+                        Object::Stream(stream) => stream.decompressed_content().unwrap_or_else(|_| stream.content.clone()),
+                        _ => return Err("Invalid Indexed lookup table".into()),
+                    };
+                    let needed = (hival as usize + 1) * base.components();
+                    if lookup.len() < needed {
+                        return Err(format!("Indexed lookup has {} bytes, expected {}", lookup.len(), needed).into());
+                    }
+                    Ok(ColorSpace::Indexed { base: Box::new(base), hival, lookup })
+                }
+                b"CalGray" => Ok(ColorSpace::Gray),
+                b"CalRGB" => Ok(ColorSpace::Rgb),
+                b"DeviceN" if is_process_cmyk(doc, arr.get(1)) => Ok(ColorSpace::Cmyk),
+                // Spot colors and Lab are valid, they just have no JPEG equivalent
+                b"DeviceN" | b"Separation" | b"Lab" => {
+                    Err(SkipReason(format!("{} color spaces are not supported", String::from_utf8_lossy(family))).into())
+                }
+                // Single-element arrays such as [/DeviceRGB]
+                other if arr.len() == 1 => device_color_space(other),
+                other => Err(format!("Unsupported color space: {}", String::from_utf8_lossy(other)).into()),
+            }
+        }
+        _ => Err("Invalid color space object".into()),
+    }
+}
+
+fn device_color_space(name: &[u8]) -> ColorResult<ColorSpace> {
+    match name {
+        b"DeviceGray" | b"G" | b"CalGray" => Ok(ColorSpace::Gray),
+        b"DeviceRGB" | b"RGB" | b"CalRGB" => Ok(ColorSpace::Rgb),
+        b"DeviceCMYK" | b"CMYK" => Ok(ColorSpace::Cmyk),
+        other => Err(format!("Unsupported color space: {}", String::from_utf8_lossy(other)).into()),
+    }
+}
+
+/// DeviceN spaces naming exactly the four process colorants are plain CMYK
+fn is_process_cmyk(doc: &Document, names: Option<&Object>) -> bool {
+    let Some(Ok((_, Object::Array(names)))) = names.map(|n| doc.dereference(n)) else { return false };
+    let expected: [&[u8]; 4] = [b"Cyan", b"Magenta", b"Yellow", b"Black"];
+    names.len() == 4 && names.iter().zip(expected).all(|(n, e)| n.as_name().ok() == Some(e))
+}
+
+/// Replace palette indices with the colors they refer to in the base color space
+pub(crate) fn expand_indexed(indices: &[u8], base_components: usize, hival: u8, lookup: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(indices.len() * base_components);
+    for &index in indices {
+        let start = index.min(hival) as usize * base_components;
+        out.extend_from_slice(&lookup[start..start + base_components]);
+    }
+    out
+}
+
+/// The image's /Decode array if it has one entry pair per component and differs
+/// from the default [0 1 0 1 ...] mapping
+pub(crate) fn decode_array(dict: &Dictionary, components: usize) -> Option<Vec<f32>> {
+    let Ok(Object::Array(decode)) = dict.get(b"Decode") else { return None };
+    let values: Vec<f32> = decode.iter().filter_map(|v| v.as_float().ok()).collect();
+    let is_default = values.chunks(2).all(|pair| pair == [0.0, 1.0]);
+    (values.len() == components * 2 && !is_default).then_some(values)
+}
+
+/// Map interleaved samples through a /Decode array so they can be stored with the default mapping
+pub(crate) fn apply_decode<T>(samples: &mut [T], decode: &[f32], max: f32)
+where
+    T: Copy + Into<f32> + TryFrom<u32>,
+{
+    let components = decode.len() / 2;
+    for px in samples.chunks_exact_mut(components) {
+        for (c, sample) in px.iter_mut().enumerate() {
+            let (dmin, dmax) = (decode[2 * c], decode[2 * c + 1]);
+            let value = dmin + (*sample).into() / max * (dmax - dmin);
+            let mapped = (value.clamp(0.0, 1.0) * max).round() as u32;
+            if let Ok(mapped) = T::try_from(mapped) {
+                *sample = mapped;
+            }
+        }
+    }
+}
+
+/// Color space for re-encoded samples with `components` channels. The original
+/// space is kept when it still describes them (ICC and calibrated spaces keep
+/// their profile); otherwise the matching device space is used.
+pub(crate) fn output_color_space(doc: &Document, original: Option<&Object>, components: usize) -> Object {
+    if let Some(original) = original {
+        if let Ok(resolved) = resolve_color_space(doc, original) {
+            if !matches!(resolved, ColorSpace::Indexed { .. }) && resolved.components() == components {
+                return original.clone();
+            }
+        }
+    }
+    match components {
+        1 => Object::Name(b"DeviceGray".to_vec()),
+        4 => Object::Name(b"DeviceCMYK".to_vec()),
+        _ => Object::Name(b"DeviceRGB".to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::Stream;
+
+    fn names(names: &[&str]) -> Object {
+        Object::Array(names.iter().map(|name| Object::Name(name.as_bytes().to_vec())).collect())
+    }
+
+    /// A spot color space over `alternate`, with a tint transform that is never evaluated
+    fn spot(doc: &mut Document, family: &str, colorants: Object, alternate: &str) -> Object {
+        let function = doc.add_object(Stream::new(Dictionary::new(), b"{}".to_vec()));
+        Object::Array(vec![Object::Name(family.as_bytes().to_vec()), colorants, Object::Name(alternate.as_bytes().to_vec()), function.into()])
+    }
+
+    fn is_skipped(result: ColorResult<ColorSpace>) -> bool {
+        result.is_err_and(|error| error.downcast_ref::<SkipReason>().is_some())
+    }
+
+    #[test]
+    fn process_color_device_n_is_cmyk() {
+        let mut doc = Document::with_version("1.5");
+        let space = spot(&mut doc, "DeviceN", names(&["Cyan", "Magenta", "Yellow", "Black"]), "DeviceCMYK");
+        assert_eq!(resolve_color_space(&doc, &space).unwrap(), ColorSpace::Cmyk);
+    }
+
+    #[test]
+    fn spot_color_spaces_are_skipped() {
+        let mut doc = Document::with_version("1.5");
+        let separation = spot(&mut doc, "Separation", Object::Name(b"PANTONE 185 C".to_vec()), "DeviceCMYK");
+        let device_n = spot(&mut doc, "DeviceN", names(&["Cyan", "Magenta", "Yellow", "Black", "Varnish"]), "DeviceCMYK");
+        let indexed = Object::Array(vec![
+            Object::Name(b"Indexed".to_vec()),
+            separation.clone(),
+            Object::Integer(1),
+            Object::string_literal(vec![0u8, 255]),
+        ]);
+
+        assert!(is_skipped(resolve_color_space(&doc, &separation)));
+        assert!(is_skipped(resolve_color_space(&doc, &device_n)));
+        assert!(is_skipped(resolve_color_space(&doc, &indexed)));
+    }
+
+    #[test]
+    fn malformed_color_spaces_fail() {
+        let doc = Document::with_version("1.5");
+        let without_profile = names(&["ICCBased"]);
+        assert!(resolve_color_space(&doc, &without_profile).is_err_and(|error| error.downcast_ref::<SkipReason>().is_none()));
+    }
+}
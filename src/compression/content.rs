@@ -0,0 +1,431 @@
+//! Content stream optimisation: page and form content is parsed, cleaned of
+//! redundant operators and rewritten with shorter numbers.
+//!
+//! Every rewrite keeps the rendered output the same, apart from coordinates being
+//! rounded to the configured number of decimals. Only the operands of positioning
+//! and path operators count as coordinates; colours, dash patterns, text state and
+//! kerning keep their exact values.
+
+use lopdf::content::{Content, Operation};
+use lopdf::{Document, Object, ObjectId, Stream};
+use std::collections::BTreeMap;
+
+/// Extra decimals kept for the scale and rotation part of `cm`/`Tm`, whose
+/// rounding errors are multiplied by every coordinate drawn afterwards
+const MATRIX_EXTRA_DECIMALS: u8 = 3;
+
+/// Operators whose operands are all coordinates or transformation matrices
+const COORDINATE_OPERATORS: [&str; 10] = ["cm", "Tm", "Td", "TD", "m", "l", "c", "v", "y", "re"];
+
+/// Operators that replace a single piece of graphics or text state outright, so an
+/// operator immediately followed by the same one has no effect
+const OVERRIDING_OPERATORS: [&str; 21] = [
+    "w", "J", "j", "M", "d", "ri", "i", "Tc", "Tw", "Tz", "TL", "Ts", "Tr", "Tf", "g", "G", "rg", "RG", "k", "K", "CS",
+];
+
+/// Result of the content stream pass
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ContentStats {
+    pub streams_optimized: usize,
+    pub bytes_saved: usize,
+}
+
+/// Parse a content stream, or None if lopdf cannot parse all of it.
+///
+/// `Content::decode` stops quietly at the first thing it does not understand
+/// (inline images, for example) and returns the operations before it, so the
+/// result is checked against a count of the operators in `data`.
+pub(crate) fn decode_complete(data: &[u8]) -> Option<Content> {
+    let content = Content::decode(data).ok()?;
+    (count_operators(data)? == content.operations.len()).then_some(content)
+}
+
+/// Number of operators in a content stream, or None for inline images
+fn count_operators(data: &[u8]) -> Option<usize> {
+    let is_delimiter = |b: u8| b"()<>[]{}/%".contains(&b);
+    let is_regular = |b: u8| !b.is_ascii_whitespace() && b != 0 && !is_delimiter(b);
+    let mut count = 0;
+    let mut i = 0;
+    while i < data.len() {
+        match data[i] {
+            b'%' => {
+                while i < data.len() && data[i] != b'\n' && data[i] != b'\r' {
+                    i += 1;
+                }
+            }
+            b'(' => {
+                let mut depth = 0;
+                while i < data.len() {
+                    match data[i] {
+                        b'\\' => i += 1,
+                        b'(' => depth += 1,
+                        b')' => depth -= 1,
+                        _ => {}
+                    }
+                    i += 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            }
+            b'<' | b'>' if data.get(i + 1) == Some(&data[i]) => i += 2,
+            b'<' => {
+                while i < data.len() && data[i] != b'>' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            b'/' => {
+                i += 1;
+                while i < data.len() && is_regular(data[i]) {
+                    i += 1;
+                }
+            }
+            b if is_regular(b) => {
+                let start = i;
+                while i < data.len() && is_regular(data[i]) {
+                    i += 1;
+                }
+                match &data[start..i] {
+                    // Inline image data is binary and cannot be tokenised
+                    b"ID" => return None,
+                    b"true" | b"false" | b"null" => {}
+                    token if token.iter().all(|c| b"+-.0123456789".contains(c)) => {}
+                    _ => count += 1,
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    Some(count)
+}
+
+/// Optimise the content of every page and form XObject
+pub(crate) fn optimize_content_streams(doc: &mut Document, decimals: u8) -> ContentStats {
+    let mut stats = ContentStats::default();
+
+    // Pages whose content is split over several streams get one merged stream;
+    // pages sharing the same streams share the result
+    let mut merged: BTreeMap<Vec<ObjectId>, Option<ObjectId>> = BTreeMap::new();
+    for page_id in doc.get_pages().into_values() {
+        let stream_ids = doc.get_page_contents(page_id);
+        if stream_ids.is_empty() {
+            continue;
+        }
+        if let Some(result) = merged.get(&stream_ids) {
+            if let (Some(new_id), Ok(page)) = (result, doc.get_dictionary_mut(page_id)) {
+                page.set(b"Contents", Object::Reference(*new_id));
+            }
+            continue;
+        }
+
+        let parts: Vec<Vec<u8>> = stream_ids
+            .iter()
+            .filter_map(|id| doc.get_object(*id).and_then(Object::as_stream).ok())
+            .map(|s| s.decompressed_content().unwrap_or_else(|_| s.content.clone()))
+            .collect();
+        // Streams split at token boundaries and are read as if separated by whitespace
+        let data = parts.join(&b'\n');
+        let Some(optimized) = optimize(&data, decimals) else {
+            merged.insert(stream_ids, None);
+            continue;
+        };
+        stats.streams_optimized += 1;
+        stats.bytes_saved += data.len() - optimized.len();
+
+        if let [stream_id] = stream_ids[..] {
+            if let Ok(stream) = doc.get_object_mut(stream_id).and_then(Object::as_stream_mut) {
+                stream.set_plain_content(optimized);
+            }
+            merged.insert(stream_ids, None);
+        } else {
+            let new_id = doc.add_object(Stream::new(lopdf::Dictionary::new(), optimized));
+            if let Ok(page) = doc.get_dictionary_mut(page_id) {
+                page.set(b"Contents", Object::Reference(new_id));
+            }
+            merged.insert(stream_ids, Some(new_id));
+        }
+    }
+
+    let form_ids: Vec<ObjectId> = doc
+        .objects
+        .iter()
+        .filter(|(_, o)| {
+            o.as_stream().is_ok_and(|s| matches!(s.dict.get(b"Subtype").and_then(Object::as_name), Ok(b"Form")))
+        })
+        .map(|(id, _)| *id)
+        .collect();
+    for form_id in form_ids {
+        let Ok(stream) = doc.get_object_mut(form_id).and_then(Object::as_stream_mut) else { continue };
+        let data = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+        if let Some(optimized) = optimize(&data, decimals) {
+            stats.streams_optimized += 1;
+            stats.bytes_saved += data.len() - optimized.len();
+            stream.set_plain_content(optimized);
+        }
+    }
+
+    stats
+}
+
+/// Optimised content, or None if it cannot be parsed or would not get smaller
+fn optimize(data: &[u8], decimals: u8) -> Option<Vec<u8>> {
+    let content = decode_complete(data)?;
+    let operations = optimize_operations(content.operations, decimals);
+    let encoded = Content { operations }.encode().ok()?;
+    (encoded.len() < data.len()).then_some(encoded)
+}
+
+fn optimize_operations(mut operations: Vec<Operation>, decimals: u8) -> Vec<Operation> {
+    for operation in &mut operations {
+        round_operands(operation, decimals);
+    }
+    let mut operations = fold_text_positioning(operations, decimals);
+    loop {
+        let before = operations.len();
+        operations = remove_no_ops(operations);
+        operations = remove_redundant_saves(operations);
+        if operations.len() == before {
+            break;
+        }
+    }
+    operations
+}
+
+/// Round the coordinates of a positioning or path operator; other operators are
+/// left as they are
+fn round_operands(operation: &mut Operation, decimals: u8) {
+    if !COORDINATE_OPERATORS.contains(&operation.operator.as_str()) {
+        return;
+    }
+    let is_matrix = matches!(operation.operator.as_str(), "cm" | "Tm");
+    for (index, operand) in operation.operands.iter_mut().enumerate() {
+        let places = if is_matrix && index < 4 { decimals.saturating_add(MATRIX_EXTRA_DECIMALS) } else { decimals };
+        if let Object::Real(value) = operand {
+            *operand = rounded(*value, places);
+        }
+    }
+}
+
+/// `value` rounded to `decimals` places, as an integer when it is whole
+fn rounded(value: f32, decimals: u8) -> Object {
+    let scale = 10f64.powi(decimals as i32);
+    let value = (value as f64 * scale).round() / scale;
+    if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+        Object::Integer(value as i64)
+    } else {
+        Object::Real(value as f32)
+    }
+}
+
+fn number(object: &Object) -> Option<f64> {
+    match object {
+        Object::Integer(value) => Some(*value as f64),
+        Object::Real(value) => Some(*value as f64),
+        _ => None,
+    }
+}
+
+fn numbers<const N: usize>(operation: &Operation) -> Option<[f64; N]> {
+    let values: Vec<f64> = operation.operands.iter().map(number).collect::<Option<_>>()?;
+    values.try_into().ok()
+}
+
+fn numeric_operation(operator: &str, values: &[f64], decimals: u8) -> Operation {
+    let operands = values.iter().map(|v| rounded(*v as f32, decimals)).collect();
+    Operation::new(operator, operands)
+}
+
+/// Merge text positioning operators that follow each other directly: two `Td`s add
+/// up, a `Td` after a `Tm` moves the `Tm`, and a `Tm` makes any positioning right
+/// before it irrelevant
+fn fold_text_positioning(operations: Vec<Operation>, decimals: u8) -> Vec<Operation> {
+    let mut out: Vec<Operation> = Vec::with_capacity(operations.len());
+    for operation in operations {
+        let previous = out.last();
+        let folded = match (previous.map(|p| p.operator.as_str()), operation.operator.as_str()) {
+            (Some("Td"), "Td") => previous.and_then(numbers::<2>).zip(numbers::<2>(&operation)).map(|([x1, y1], [x2, y2])| {
+                numeric_operation("Td", &[x1 + x2, y1 + y2], decimals)
+            }),
+            (Some("Tm"), "Td") => previous.and_then(numbers::<6>).zip(numbers::<2>(&operation)).map(|(m, [tx, ty])| {
+                let [a, b, c, d, e, f] = m;
+                let moved = [a, b, c, d, tx * a + ty * c + e, tx * b + ty * d + f];
+                let mut tm = numeric_operation("Tm", &moved, decimals);
+                tm.operands[..4].clone_from_slice(&previous.expect("matched above").operands[..4]);
+                tm
+            }),
+            (Some("Td" | "Tm"), "Tm") => Some(operation.clone()),
+            _ => None,
+        };
+        match folded {
+            Some(replacement) => *out.last_mut().expect("matched above") = replacement,
+            None => out.push(operation),
+        }
+    }
+    out
+}
+
+/// Drop operators without visible effect: identity transforms, empty text objects
+/// and state that is overwritten straight away. `0 0 Td` is kept, as it moves back
+/// to the start of the current line
+fn remove_no_ops(operations: Vec<Operation>) -> Vec<Operation> {
+    let mut out: Vec<Operation> = Vec::with_capacity(operations.len());
+    for operation in operations {
+        let values: Vec<Option<f64>> = operation.operands.iter().map(number).collect();
+        let is_no_op = match operation.operator.as_str() {
+            "cm" => values == [Some(1.0), Some(0.0), Some(0.0), Some(1.0), Some(0.0), Some(0.0)],
+            _ => false,
+        };
+        if is_no_op {
+            continue;
+        }
+        match out.last().map(|p| p.operator.as_str()) {
+            Some("BT") if operation.operator == "ET" => {
+                out.pop();
+                continue;
+            }
+            Some(previous) if previous == operation.operator && OVERRIDING_OPERATORS.contains(&previous) => {
+                out.pop();
+            }
+            _ => {}
+        }
+        out.push(operation);
+    }
+    out
+}
+
+/// Remove `q`/`Q` pairs that save nothing: empty pairs, a pair wrapped directly in
+/// another pair, and a pair around the whole stream (the state after a content
+/// stream ends is discarded anyway). Unbalanced streams are left alone.
+fn remove_redundant_saves(operations: Vec<Operation>) -> Vec<Operation> {
+    let mut closing = BTreeMap::new();
+    let mut open = Vec::new();
+    for (index, operation) in operations.iter().enumerate() {
+        match operation.operator.as_str() {
+            "q" => open.push(index),
+            "Q" => match open.pop() {
+                Some(start) => {
+                    closing.insert(start, index);
+                }
+                None => return operations,
+            },
+            _ => {}
+        }
+    }
+    if !open.is_empty() {
+        return operations;
+    }
+
+    let mut removed = vec![false; operations.len()];
+    for (&start, &end) in &closing {
+        let empty = end == start + 1;
+        let doubled = closing.get(&(start + 1)) == Some(&(end - 1)) && !removed[start + 1];
+        let whole = start == 0 && end == operations.len() - 1;
+        if empty || doubled || whole {
+            removed[start] = true;
+            removed[end] = true;
+        }
+    }
+    operations.into_iter().zip(removed).filter(|(_, removed)| !removed).map(|(op, _)| op).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `content` after the rewrites, with one space between tokens
+    fn rewrite(content: &str, decimals: u8) -> String {
+        let operations = decode_complete(content.as_bytes()).expect("test content parses").operations;
+        let encoded = Content { operations: optimize_operations(operations, decimals) }.encode().unwrap();
+        String::from_utf8(encoded).unwrap().split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    #[test]
+    fn inline_images_are_not_parsed() {
+        assert!(decode_complete(b"q BI /W 1 /H 1 /BPC 8 /CS /G ID \x00 EI Q").is_none());
+        assert!(decode_complete(b"q 1 0 0 1 0 0 cm Q").is_some());
+    }
+
+    #[test]
+    fn coordinates_are_rounded() {
+        assert_eq!(rewrite("10.12345 20.6789 m 1.5 2.25 l S", 2), "10.12 20.68 m 1.5 2.25 l S");
+        assert_eq!(rewrite("0.4 0.6 5.5 5.5 re f", 0), "0 1 6 6 re f");
+    }
+
+    #[test]
+    fn matrices_keep_extra_decimals_for_scale() {
+        assert_eq!(rewrite("q 0.1234567 0 0 0.1234567 10.126 20.004 cm Q S", 2), "q 0.12346 0 0 0.12346 10.13 20 cm Q S");
+    }
+
+    #[test]
+    fn colours_and_state_are_not_rounded() {
+        let content = "0.44 g 0.123 0.456 0.789 rg 0.1 0.2 0.3 0.4 k 0.25 scn [2.5 1.5] 0.5 d 95.5 Tz 0.75 w S";
+        assert_eq!(rewrite(content, 0), content);
+    }
+
+    #[test]
+    fn kerning_is_not_rounded() {
+        assert_eq!(rewrite("BT [(A) -12.5 (B)] TJ ET", 0), "BT [(A) -12.5(B)] TJ ET");
+    }
+
+    #[test]
+    fn consecutive_moves_are_added() {
+        assert_eq!(rewrite("BT 10 20 Td 5 -2 Td (x) Tj ET", 3), "BT 15 18 Td (x) Tj ET");
+    }
+
+    #[test]
+    fn move_after_matrix_is_folded_into_it() {
+        assert_eq!(rewrite("BT 2 0 0 2 100 200 Tm 10 5 Td (x) Tj ET", 3), "BT 2 0 0 2 120 210 Tm (x) Tj ET");
+    }
+
+    #[test]
+    fn positioning_before_matrix_is_dropped() {
+        assert_eq!(rewrite("BT 10 20 Td 1 0 0 1 5 5 Tm (x) Tj ET", 3), "BT 1 0 0 1 5 5 Tm (x) Tj ET");
+    }
+
+    #[test]
+    fn zero_move_returns_to_line_start() {
+        let content = "BT 100 700 Td (Hello) Tj 0 0 Td (World) Tj ET";
+        assert_eq!(rewrite(content, 3), content);
+    }
+
+    #[test]
+    fn identity_matrix_is_removed() {
+        assert_eq!(rewrite("1 0 0 1 0 0 cm 0 0 m 1 1 l S", 3), "0 0 m 1 1 l S");
+    }
+
+    #[test]
+    fn empty_text_objects_are_removed() {
+        assert_eq!(rewrite("BT ET 0 0 m S", 3), "0 0 m S");
+    }
+
+    #[test]
+    fn overwritten_state_is_removed() {
+        assert_eq!(rewrite("1 w 2 w 0.5 g 0.25 g 0 0 m S", 3), "2 w 0.25 g 0 0 m S");
+        // Only direct repeats: the first width is used by the stroke in between
+        assert_eq!(rewrite("1 w 0 0 m S 2 w 0 0 m S", 3), "1 w 0 0 m S 2 w 0 0 m S");
+    }
+
+    #[test]
+    fn redundant_saves_are_removed() {
+        // Empty pair
+        assert_eq!(rewrite("0 0 m S q Q 1 1 m S", 3), "0 0 m S 1 1 m S");
+        // Pair directly inside another pair
+        assert_eq!(rewrite("0 0 m S q q 2 w 0 0 m S Q Q 1 1 m S", 3), "0 0 m S q 2 w 0 0 m S Q 1 1 m S");
+        // Pair around the whole stream
+        assert_eq!(rewrite("q 2 w 0 0 m S Q", 3), "2 w 0 0 m S");
+        // A pair that restores state for what follows is kept
+        assert_eq!(rewrite("q 2 w 0 0 m S Q 0 0 m S", 3), "q 2 w 0 0 m S Q 0 0 m S");
+    }
+
+    #[test]
+    fn unbalanced_saves_are_left_alone() {
+        assert_eq!(rewrite("q q 0 0 m S Q", 3), "q q 0 0 m S Q");
+    }
+
+    #[test]
+    fn output_that_is_not_smaller_is_rejected() {
+        assert!(optimize(b"0 0 m S", 3).is_none());
+        assert!(optimize(b"q 1 0 0 1 0 0 cm 0 0 m S Q", 3).is_some());
+    }
+}
@@ -0,0 +1,384 @@
+//! Deduplication: streams and dictionaries that are identical once their filters
+//! are undone are merged into one object, and every reference is pointed at it.
+//!
+//! Merging happens in rounds, since objects that differed only in which copy of
+//! a duplicate they referenced (a font and its descriptor, say) become identical
+//! once the copies below them are merged.
+
+use super::filters;
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+/// Upper bound on merge rounds; each round only goes one level up the object graph
+const MAX_ROUNDS: usize = 8;
+
+/// Dictionary types whose identity matters even when their contents match
+const DISTINCT_TYPES: [&[u8]; 9] =
+    [b"Catalog", b"Pages", b"Page", b"Annot", b"Outlines", b"StructTreeRoot", b"StructElem", b"OCG", b"Sig"];
+
+/// Keys that tie a dictionary to one place in a tree (a parent, page or children)
+const TREE_KEYS: [&[u8]; 3] = [b"Parent", b"P", b"Kids"];
+
+/// Result of the deduplication pass
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct DedupStats {
+    pub objects_merged: usize,
+    pub bytes_reclaimed: usize,
+}
+
+/// Merge identical streams and dictionaries, rewriting references to the copy kept
+pub(crate) fn deduplicate(doc: &mut Document) -> DedupStats {
+    let mut stats = DedupStats::default();
+    // Merging only rewrites references, never stream data, so each stream is
+    // decoded once however many rounds it takes part in
+    let mut digests = HashMap::new();
+
+    for _ in 0..MAX_ROUNDS {
+        let replacements = find_duplicates(doc, &mut digests);
+        if replacements.is_empty() {
+            break;
+        }
+        for duplicate in replacements.keys() {
+            if let Some(object) = doc.objects.remove(duplicate) {
+                stats.objects_merged += 1;
+                stats.bytes_reclaimed += encoded_size(&object);
+            }
+        }
+        for object in doc.objects.values_mut() {
+            replace_references(object, &replacements);
+        }
+        for (_, object) in doc.trailer.iter_mut() {
+            replace_references(object, &replacements);
+        }
+    }
+
+    stats
+}
+
+/// Hash of a stream's data, and whether its filters could be undone
+#[derive(Debug, Clone, Copy)]
+struct StreamDigest {
+    decoded: bool,
+    hash: u64,
+}
+
+impl StreamDigest {
+    fn of(stream: &Stream) -> Self {
+        let (decoded, data) = stream_data(stream);
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        Self { decoded, hash: hasher.finish() }
+    }
+}
+
+/// Map each duplicate object to the copy that replaces it
+fn find_duplicates(doc: &Document, digests: &mut HashMap<ObjectId, StreamDigest>) -> BTreeMap<ObjectId, ObjectId> {
+    let mut buckets: HashMap<u64, Vec<ObjectId>> = HashMap::new();
+    for (id, object) in &doc.objects {
+        let mut hasher = DefaultHasher::new();
+        match object {
+            Object::Dictionary(dict) if is_mergeable(dict) => dictionary_key(object, false).hash(&mut hasher),
+            Object::Stream(stream) if is_mergeable(&stream.dict) => {
+                let digest = *digests.entry(*id).or_insert_with(|| StreamDigest::of(stream));
+                dictionary_key(object, digest.decoded).hash(&mut hasher);
+                digest.hash.hash(&mut hasher);
+            }
+            _ => continue,
+        }
+        buckets.entry(hasher.finish()).or_default().push(*id);
+    }
+
+    let mut replacements = BTreeMap::new();
+    for ids in buckets.into_values().filter(|ids| ids.len() > 1) {
+        // Hashes can collide, so group the bucket by the full key before merging
+        let mut groups: Vec<(Vec<u8>, Vec<ObjectId>)> = Vec::new();
+        for id in ids {
+            let Some(key) = doc.objects.get(&id).and_then(canonical_key) else { continue };
+            match groups.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, members)) => members.push(id),
+                None => groups.push((key, vec![id])),
+            }
+        }
+        for (_, members) in groups.into_iter().filter(|(_, members)| members.len() > 1) {
+            // Keep the smallest encoding, which may be better compressed than the others
+            let keep = *members
+                .iter()
+                .min_by_key(|id| (doc.objects.get(id).map_or(usize::MAX, encoded_size), **id))
+                .expect("group is not empty");
+            replacements.extend(members.into_iter().filter(|id| *id != keep).map(|id| (id, keep)));
+        }
+    }
+    replacements
+}
+
+/// Bytes that identify an object's meaning, or None if it must not be merged.
+/// Streams are compared by decoded data, so the same image or font stored with
+/// different compression still matches.
+fn canonical_key(object: &Object) -> Option<Vec<u8>> {
+    match object {
+        Object::Dictionary(dict) if is_mergeable(dict) => Some(dictionary_key(object, false)),
+        Object::Stream(stream) if is_mergeable(&stream.dict) => {
+            let (decoded, data) = stream_data(stream);
+            let mut key = dictionary_key(object, decoded);
+            key.extend_from_slice(&data);
+            Some(key)
+        }
+        _ => None,
+    }
+}
+
+/// The dictionary part of a canonical key. The filter entries of a `decoded`
+/// stream are left out, since its data is compared without them
+fn dictionary_key(object: &Object, decoded: bool) -> Vec<u8> {
+    let mut key = Vec::new();
+    match object {
+        Object::Stream(stream) => {
+            key.push(b'S');
+            let skip: &[&[u8]] = if decoded { &[b"Length", b"Filter", b"DecodeParms"] } else { &[b"Length"] };
+            write_dictionary(&stream.dict, skip, &mut key);
+        }
+        other => {
+            key.push(b'D');
+            write_object(other, &mut key);
+        }
+    }
+    key
+}
+
+/// A stream's data with its filters undone, along with the image codec left in
+/// place, or the raw content if the filters can't be undone. The flag says which.
+fn stream_data(stream: &Stream) -> (bool, Vec<u8>) {
+    match filters::decode_image_stream(stream) {
+        Ok(decoded) => {
+            let mut data = Vec::new();
+            // Image codecs are not decoded, so their parameters still matter
+            if let Some(codec) = &decoded.codec {
+                write_object(&Object::Name(codec.clone()), &mut data);
+            }
+            if let Some(parms) = &decoded.codec_parms {
+                write_dictionary(parms, &[], &mut data);
+            }
+            data.extend_from_slice(&decoded.data);
+            (true, data)
+        }
+        Err(_) => (false, stream.content.clone()),
+    }
+}
+
+fn is_mergeable(dict: &Dictionary) -> bool {
+    let distinct_type = dict.get(b"Type").and_then(Object::as_name).is_ok_and(|t| DISTINCT_TYPES.contains(&t));
+    !distinct_type && !TREE_KEYS.iter().any(|key| dict.has(key))
+}
+
+/// Serialize an object deterministically: dictionary keys are sorted and numbers
+/// are written by value, so equal objects give equal bytes
+fn write_object(object: &Object, out: &mut Vec<u8>) {
+    match object {
+        Object::Null => out.extend_from_slice(b"null "),
+        Object::Boolean(value) => out.extend_from_slice(if *value { b"true " } else { b"false " }),
+        Object::Integer(value) => out.extend_from_slice(format!("{} ", value).as_bytes()),
+        Object::Real(value) => out.extend_from_slice(format!("{}r ", value).as_bytes()),
+        Object::Name(name) => {
+            out.push(b'/');
+            out.extend_from_slice(&(name.len() as u32).to_be_bytes());
+            out.extend_from_slice(name);
+        }
+        Object::String(bytes, _) => {
+            out.push(b'(');
+            out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            out.extend_from_slice(bytes);
+        }
+        Object::Array(items) => {
+            out.push(b'[');
+            for item in items {
+                write_object(item, out);
+            }
+            out.push(b']');
+        }
+        Object::Dictionary(dict) => write_dictionary(dict, &[], out),
+        Object::Stream(stream) => {
+            write_dictionary(&stream.dict, &[], out);
+            out.extend_from_slice(&stream.content);
+        }
+        Object::Reference((number, generation)) => out.extend_from_slice(format!("{} {} R ", number, generation).as_bytes()),
+    }
+}
+
+fn write_dictionary(dict: &Dictionary, skip: &[&[u8]], out: &mut Vec<u8>) {
+    let mut entries: Vec<(&Vec<u8>, &Object)> = dict.iter().filter(|(key, _)| !skip.contains(&key.as_slice())).collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    out.push(b'<');
+    for (key, value) in entries {
+        write_object(&Object::Name(key.clone()), out);
+        write_object(value, out);
+    }
+    out.push(b'>');
+}
+
+/// Approximate size of an object in the saved file
+fn encoded_size(object: &Object) -> usize {
+    match object {
+        Object::Stream(stream) => {
+            let mut dict = Vec::new();
+            write_dictionary(&stream.dict, &[], &mut dict);
+            dict.len() + stream.content.len()
+        }
+        other => {
+            let mut bytes = Vec::new();
+            write_object(other, &mut bytes);
+            bytes.len()
+        }
+    }
+}
+
+fn replace_references(object: &mut Object, replacements: &BTreeMap<ObjectId, ObjectId>) {
+    match object {
+        Object::Reference(id) => {
+            if let Some(keep) = replacements.get(id) {
+                *id = *keep;
+            }
+        }
+        Object::Array(items) => {
+            for item in items {
+                replace_references(item, replacements);
+            }
+        }
+        Object::Dictionary(dict) => {
+            for (_, value) in dict.iter_mut() {
+                replace_references(value, replacements);
+            }
+        }
+        Object::Stream(stream) => {
+            for (_, value) in stream.dict.iter_mut() {
+                replace_references(value, replacements);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use lopdf::dictionary;
+    use std::io::Write;
+
+    fn image(content: Vec<u8>, filter: Option<&str>) -> Stream {
+        let mut dict = dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Image",
+            "Width" => 8,
+            "Height" => 8,
+            "ColorSpace" => "DeviceGray",
+            "BitsPerComponent" => 8,
+        };
+        if let Some(filter) = filter {
+            dict.set("Filter", filter);
+        }
+        Stream::new(dict, content)
+    }
+
+    fn xobject(doc: &Document, page: ObjectId, name: &[u8]) -> ObjectId {
+        let resources = doc.get_dictionary(page).unwrap().get(b"Resources").unwrap().as_dict().unwrap();
+        resources.get(b"XObject").unwrap().as_dict().unwrap().get(name).unwrap().as_reference().unwrap()
+    }
+
+    #[test]
+    fn identical_images_are_merged() {
+        let pixels: Vec<u8> = (0..64).map(|i| (i * 4) as u8).collect();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&pixels).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut doc = Document::with_version("1.5");
+        let raw = doc.add_object(image(pixels.clone(), None));
+        let flate = doc.add_object(image(compressed, Some("FlateDecode")));
+        let copy = doc.add_object(image(pixels, None));
+        let page = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Resources" => dictionary! { "XObject" => dictionary! { "Im1" => raw, "Im2" => flate, "Im3" => copy } },
+        });
+
+        let stats = deduplicate(&mut doc);
+
+        assert_eq!(stats.objects_merged, 2);
+        assert!(stats.bytes_reclaimed > 0);
+        let kept = xobject(&doc, page, b"Im1");
+        assert_eq!(xobject(&doc, page, b"Im2"), kept);
+        assert_eq!(xobject(&doc, page, b"Im3"), kept);
+        // Only one of the three copies is left
+        assert_eq!([raw, flate, copy].iter().filter(|id| doc.objects.contains_key(id)).count(), 1);
+    }
+
+    #[test]
+    fn structural_objects_are_never_merged() {
+        let mut doc = Document::with_version("1.5");
+        let parent = doc.add_object(dictionary! { "Type" => "Pages" });
+        for name in DISTINCT_TYPES {
+            for _ in 0..2 {
+                doc.add_object(dictionary! { "Type" => Object::Name(name.to_vec()) });
+            }
+        }
+        for key in TREE_KEYS {
+            for _ in 0..2 {
+                let mut dict = Dictionary::new();
+                dict.set(key, parent);
+                doc.add_object(dict);
+            }
+        }
+        // Identical pages of one parent, as produced by duplicating a blank page
+        for _ in 0..2 {
+            doc.add_object(dictionary! { "Type" => "Page", "Parent" => parent, "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()] });
+        }
+        let objects = doc.objects.len();
+
+        let stats = deduplicate(&mut doc);
+
+        assert_eq!(stats.objects_merged, 0);
+        assert_eq!(doc.objects.len(), objects);
+    }
+
+    #[test]
+    fn references_in_arrays_and_nested_dictionaries_are_rewritten() {
+        let mut doc = Document::with_version("1.5");
+        let first = doc.add_object(dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Helvetica" });
+        let second = doc.add_object(dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Helvetica" });
+        let holder = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Fonts" => vec![second.into(), Object::Array(vec![second.into()])],
+            "Nested" => dictionary! { "Deeper" => dictionary! { "F" => second } },
+        });
+        doc.trailer.set("Font", second);
+
+        let stats = deduplicate(&mut doc);
+
+        assert_eq!(stats.objects_merged, 1);
+        assert!(!doc.objects.contains_key(&second));
+        let holder = doc.get_dictionary(holder).unwrap();
+        let fonts = holder.get(b"Fonts").unwrap().as_array().unwrap();
+        assert_eq!(fonts[0].as_reference().unwrap(), first);
+        assert_eq!(fonts[1].as_array().unwrap()[0].as_reference().unwrap(), first);
+        let nested = holder.get(b"Nested").unwrap().as_dict().unwrap().get(b"Deeper").unwrap().as_dict().unwrap();
+        assert_eq!(nested.get(b"F").unwrap().as_reference().unwrap(), first);
+        assert_eq!(doc.trailer.get(b"Font").unwrap().as_reference().unwrap(), first);
+    }
+
+    #[test]
+    fn objects_equal_after_a_merge_are_merged_in_the_next_round() {
+        let mut doc = Document::with_version("1.5");
+        let first = doc.add_object(dictionary! { "Type" => "FontDescriptor", "FontName" => "Helvetica" });
+        let second = doc.add_object(dictionary! { "Type" => "FontDescriptor", "FontName" => "Helvetica" });
+        let font_a = doc.add_object(dictionary! { "Type" => "Font", "FontDescriptor" => first });
+        let font_b = doc.add_object(dictionary! { "Type" => "Font", "FontDescriptor" => second });
+
+        let stats = deduplicate(&mut doc);
+
+        assert_eq!(stats.objects_merged, 2);
+        assert!(doc.objects.contains_key(&font_a));
+        assert!(!doc.objects.contains_key(&font_b));
+    }
+}
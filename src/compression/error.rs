@@ -0,0 +1,62 @@
+//! Errors returned by the public compression functions.
+//!
+//! Problems with a single image or font never fail a document; those parts are
+//! left as they were. Only the whole-document conditions below are errors.
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum CompressionError {
+    /// The input is not a PDF lopdf can read (bad header, broken cross-reference
+    /// table, truncated file)
+    MalformedPdf(String),
+    /// The document is password protected; it has to be decrypted first
+    Encrypted,
+    /// The document uses something the compressor cannot handle, such as a
+    /// certificate-based security handler
+    Unsupported(String),
+    /// The input exceeds a configured limit
+    ResourceLimit(String),
+    /// Reading or writing the document failed
+    Io(std::io::Error),
+    /// The caller cancelled the compression through its `Progress`
+    Cancelled,
+}
+
+impl fmt::Display for CompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompressionError::MalformedPdf(reason) => write!(f, "Malformed PDF: {}", reason),
+            CompressionError::Encrypted => write!(f, "Encrypted PDFs are not supported; remove the password first"),
+            CompressionError::Unsupported(feature) => write!(f, "Unsupported PDF feature: {}", feature),
+            CompressionError::ResourceLimit(limit) => write!(f, "Resource limit exceeded: {}", limit),
+            CompressionError::Io(e) => write!(f, "I/O error: {}", e),
+            CompressionError::Cancelled => write!(f, "Compression was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for CompressionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CompressionError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for CompressionError {
+    fn from(e: std::io::Error) -> Self {
+        CompressionError::Io(e)
+    }
+}
+
+impl From<lopdf::Error> for CompressionError {
+    fn from(e: lopdf::Error) -> Self {
+        match e {
+            lopdf::Error::IO(e) => CompressionError::Io(e),
+            lopdf::Error::Decryption(_) => CompressionError::Encrypted,
+            other => CompressionError::MalformedPdf(other.to_string()),
+        }
+    }
+}
@@ -0,0 +1,1442 @@
+//! Font optimisation: embedded TrueType, OpenType, CFF (`Type1C` and
+//! `CIDFontType0C`) and Type 1 programs are reduced to the glyphs the document's
+//! text actually shows, and fonts no content stream selects are dropped from
+//! resource dictionaries. Type 1 programs are handled in `type1`, the others by
+//! the `subsetter` crate.
+//!
+//! Glyph IDs are kept stable (unused outlines are dropped, not renumbered), so
+//! content streams and CIDToGIDMaps stay valid. Widths and ToUnicode maps are
+//! trimmed to the character codes in use.
+//!
+//! Subsetting is conservative: a font program is left alone if any font using it
+//! has usage we cannot account for (unparsable content, form field fonts,
+//! unsupported encodings or font types), or if a CFF subset would draw any kept
+//! glyph differently.
+
+use super::content::decode_complete;
+use super::filters;
+use super::type1::Type1Program;
+use super::MAX_FORM_DEPTH;
+use super::placement::{find_resource, page_resources};
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+
+/// Result of the font pass
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct FontSubsetStats {
+    pub fonts_subset: usize,
+    pub bytes_saved: usize,
+    pub fonts_removed: usize,
+}
+
+/// Character codes shown with each font dictionary, plus fonts whose usage is unknown
+#[derive(Default)]
+struct FontUsage {
+    codes: BTreeMap<ObjectId, BTreeSet<u16>>,
+    unknown: BTreeSet<ObjectId>,
+    /// Forms with their own resources already walked with an inherited font
+    inherited_fonts: BTreeSet<(ObjectId, ObjectId)>,
+}
+
+/// How a font dictionary maps its character codes to glyphs
+struct FontPlan {
+    program_id: ObjectId,
+    to_unicode_id: Option<ObjectId>,
+    /// Bytes per character code: 1 for simple fonts, 2 for Identity-encoded Type0
+    code_bytes: usize,
+    /// The CIDFont whose /W the codes index, for Type0 fonts
+    descendant_id: Option<ObjectId>,
+    gids: BTreeSet<u16>,
+}
+
+/// Remove fonts that are never selected and subset every embedded TrueType, OpenType,
+/// CFF and Type 1 font program to the glyphs in use. Removed fonts are left for `prune_objects`.
+pub(crate) fn subset_fonts(doc: &mut Document) -> FontSubsetStats {
+    let usage = collect_usage(doc);
+    let mut stats = FontSubsetStats::default();
+
+    let font_ids: Vec<ObjectId> = doc
+        .objects
+        .iter()
+        .filter(|(_, o)| matches!(o.as_dict().and_then(|d| d.get(b"Type")), Ok(Object::Name(n)) if n == b"Font"))
+        .map(|(id, _)| *id)
+        .collect();
+
+    // Union the glyphs every font needs from each program; None marks a program
+    // (or ToUnicode map) that must be kept whole
+    let mut programs: BTreeMap<ObjectId, Option<BTreeSet<u16>>> = BTreeMap::new();
+    let mut to_unicode: BTreeMap<ObjectId, Option<(usize, BTreeSet<u16>)>> = BTreeMap::new();
+    // Type0 fonts may share a descendant CIDFont, whose /W covers all of them
+    let mut descendants: BTreeMap<ObjectId, Option<BTreeSet<u16>>> = BTreeMap::new();
+    let mut plans = BTreeMap::new();
+    let mut unused = BTreeSet::new();
+    for font_id in font_ids {
+        let Ok(font) = doc.get_dictionary(font_id) else { continue };
+        // Descendant CIDFonts are planned with the Type0 font that uses them
+        if matches!(font.get(b"Subtype").and_then(Object::as_name), Ok(b"CIDFontType0" | b"CIDFontType2")) {
+            continue;
+        }
+        if !usage.codes.contains_key(&font_id) && !usage.unknown.contains(&font_id) {
+            unused.insert(font_id);
+            continue;
+        }
+        let codes = match usage.codes.get(&font_id) {
+            Some(codes) if !usage.unknown.contains(&font_id) => Some(codes),
+            _ => None,
+        };
+        match codes.and_then(|codes| plan_font(doc, font, codes)) {
+            Some(plan) => {
+                if let Some(gids) = programs.entry(plan.program_id).or_insert_with(|| Some(BTreeSet::new())) {
+                    gids.extend(&plan.gids);
+                }
+                if let Some(id) = plan.to_unicode_id {
+                    let entry = to_unicode.entry(id).or_insert_with(|| Some((plan.code_bytes, BTreeSet::new())));
+                    match entry {
+                        Some((bytes, used)) if *bytes == plan.code_bytes => used.extend(&usage.codes[&font_id]),
+                        _ => *entry = None,
+                    }
+                }
+                if let Some(id) = plan.descendant_id {
+                    if let Some(codes) = descendants.entry(id).or_insert_with(|| Some(BTreeSet::new())) {
+                        codes.extend(&usage.codes[&font_id]);
+                    }
+                }
+                plans.insert(font_id, plan);
+            }
+            None => {
+                if let Some(id) = descendant_id(doc, font) {
+                    descendants.insert(id, None);
+                }
+                for id in font_program_ids(doc, font) {
+                    programs.insert(id, None);
+                }
+                if let Ok(id) = font.get(b"ToUnicode").and_then(Object::as_reference) {
+                    to_unicode.insert(id, None);
+                }
+            }
+        }
+    }
+
+    stats.fonts_removed = remove_font_resources(doc, &unused);
+
+    let mut subset_programs = BTreeSet::new();
+    for (program_id, gids) in &programs {
+        let Some(gids) = gids else { continue };
+        match subset_program(doc, *program_id, gids) {
+            Ok(saved) if saved > 0 => {
+                stats.fonts_subset += 1;
+                stats.bytes_saved += saved;
+                subset_programs.insert(*program_id);
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!(object = program_id.0, error = %e, "Failed to subset font program"),
+        }
+    }
+
+    for (font_id, plan) in &plans {
+        if !subset_programs.contains(&plan.program_id) {
+            // The descendant's widths stay whole for a font that was not subset
+            if let Some(id) = plan.descendant_id {
+                descendants.insert(id, None);
+            }
+            continue;
+        }
+        if plan.descendant_id.is_none() {
+            trim_widths(doc, *font_id, &usage.codes[font_id]);
+        }
+        tag_font_names(doc, *font_id, &programs[&plan.program_id]);
+    }
+    for (id, codes) in &descendants {
+        if let Some(codes) = codes {
+            trim_cid_widths(doc, *id, codes);
+        }
+    }
+    for (id, entry) in &to_unicode {
+        if let Some((code_bytes, codes)) = entry {
+            trim_to_unicode(doc, *id, *code_bytes, codes);
+        }
+    }
+
+    stats
+}
+
+// ---------------------------------------------------------------------------
+// Usage collection
+
+fn collect_usage(doc: &Document) -> FontUsage {
+    let mut usage = FontUsage::default();
+
+    for page_id in doc.get_pages().into_values() {
+        let resources = page_resources(doc, page_id);
+        match doc.get_page_content(page_id) {
+            Ok(content) => walk_content(doc, &content, &resources, None, &mut BTreeSet::new(), &mut usage),
+            Err(_) => mark_all_unknown(doc, &resources, &mut usage),
+        }
+    }
+
+    // Form XObjects, patterns and annotation appearances that carry their own
+    // resources, wherever they are referenced from
+    for (id, object) in &doc.objects {
+        let Ok(stream) = object.as_stream() else { continue };
+        let Some(resources) = own_resources(doc, &stream.dict) else { continue };
+        if matches!(stream.dict.get(b"Subtype").and_then(Object::as_name), Ok(b"Image")) {
+            continue;
+        }
+        let data = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+        walk_content(doc, &data, &[resources], None, &mut BTreeSet::from([*id]), &mut usage);
+    }
+
+    // Interactive form fields can type any character with their default resources
+    if let Some(form_resources) = acroform_resources(doc) {
+        mark_all_unknown(doc, &[form_resources], &mut usage);
+    }
+
+    // Type 3 glyph procedures may select fonts from the Type 3 font's resources
+    for object in doc.objects.values() {
+        let Ok(font) = object.as_dict() else { continue };
+        if matches!(font.get(b"Subtype").and_then(Object::as_name), Ok(b"Type3")) {
+            if let Some(resources) = own_resources(doc, font) {
+                mark_all_unknown(doc, &[resources], &mut usage);
+            }
+        }
+    }
+
+    usage
+}
+
+/// Record the codes shown by `content`. `font` is the font selected when the
+/// content starts, which forms inherit from whoever paints them.
+fn walk_content(
+    doc: &Document,
+    content: &[u8],
+    resources: &[&Dictionary],
+    mut font: Option<ObjectId>,
+    active_forms: &mut BTreeSet<ObjectId>,
+    usage: &mut FontUsage,
+) {
+    let Some(content) = decode_complete(content) else {
+        // Without the operators we cannot tell which characters are shown
+        mark_all_unknown(doc, resources, usage);
+        if let Some(font_id) = font {
+            usage.unknown.insert(font_id);
+        }
+        return;
+    };
+    let mut stack = Vec::new();
+
+    for operation in &content.operations {
+        let operands = &operation.operands;
+        match operation.operator.as_str() {
+            "q" => stack.push(font),
+            "Q" => font = stack.pop().flatten(),
+            "Tf" => {
+                let name = operands.first().and_then(|o| o.as_name().ok());
+                font = name.and_then(|name| find_resource(doc, resources, b"Font", name));
+                if let Some(font_id) = font {
+                    usage.codes.entry(font_id).or_default();
+                }
+            }
+            "Tj" | "'" | "\"" => {
+                if let Some(Object::String(bytes, _)) = operands.last() {
+                    record_text(doc, resources, font, bytes, usage);
+                }
+            }
+            "TJ" => {
+                if let Some(Object::Array(items)) = operands.first() {
+                    for item in items {
+                        if let Object::String(bytes, _) = item {
+                            record_text(doc, resources, font, bytes, usage);
+                        }
+                    }
+                }
+            }
+            "Do" => {
+                let Some(name) = operands.first().and_then(|o| o.as_name().ok()) else { continue };
+                let Some(xobject_id) = find_resource(doc, resources, b"XObject", name) else { continue };
+                let Ok(stream) = doc.get_object(xobject_id).and_then(Object::as_stream) else { continue };
+                if !matches!(stream.dict.get(b"Subtype").and_then(Object::as_name), Ok(b"Form")) {
+                    continue;
+                }
+                // Forms with their own resources are walked on their own, and again
+                // here only for text shown before they select a font. The others use
+                // the resources of whoever paints them
+                let form_resources = own_resources(doc, &stream.dict);
+                let walk = match (form_resources, font) {
+                    (None, _) => true,
+                    (Some(_), Some(font_id)) => usage.inherited_fonts.insert((xobject_id, font_id)),
+                    (Some(_), None) => false,
+                };
+                if walk && active_forms.len() < MAX_FORM_DEPTH && active_forms.insert(xobject_id) {
+                    let data = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+                    match form_resources {
+                        Some(form_resources) => walk_content(doc, &data, &[form_resources], font, active_forms, usage),
+                        None => walk_content(doc, &data, resources, font, active_forms, usage),
+                    }
+                    active_forms.remove(&xobject_id);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn record_text(doc: &Document, resources: &[&Dictionary], font: Option<ObjectId>, bytes: &[u8], usage: &mut FontUsage) {
+    let Some(font_id) = font else {
+        // Text shown before any font is selected here, e.g. in a form walked on its
+        // own that relies on the font of whoever paints it
+        mark_all_unknown(doc, resources, usage);
+        return;
+    };
+    let codes = usage.codes.entry(font_id).or_default();
+    match doc.get_dictionary(font_id).ok().and_then(code_bytes) {
+        Some(1) => codes.extend(bytes.iter().map(|&b| b as u16)),
+        Some(_) => codes.extend(bytes.chunks(2).map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]))),
+        None => {
+            usage.unknown.insert(font_id);
+        }
+    }
+}
+
+fn mark_all_unknown(doc: &Document, resources: &[&Dictionary], usage: &mut FontUsage) {
+    for dict in resources {
+        let Some(fonts) = dict.get(b"Font").ok().and_then(|f| doc.dereference(f).ok()).and_then(|(_, f)| f.as_dict().ok()) else {
+            continue;
+        };
+        usage.unknown.extend(fonts.iter().filter_map(|(_, f)| f.as_reference().ok()));
+    }
+}
+
+fn own_resources<'a>(doc: &'a Document, dict: &'a Dictionary) -> Option<&'a Dictionary> {
+    let (_, resources) = doc.dereference(dict.get(b"Resources").ok()?).ok()?;
+    resources.as_dict().ok()
+}
+
+fn acroform_resources(doc: &Document) -> Option<&Dictionary> {
+    let catalog = doc.catalog().ok()?;
+    let (_, acroform) = doc.dereference(catalog.get(b"AcroForm").ok()?).ok()?;
+    let (_, resources) = doc.dereference(acroform.as_dict().ok()?.get(b"DR").ok()?).ok()?;
+    resources.as_dict().ok()
+}
+
+/// Bytes per character code, or None for encodings we cannot split into codes
+fn code_bytes(font: &Dictionary) -> Option<usize> {
+    match font.get(b"Subtype").and_then(Object::as_name) {
+        Ok(b"Type0") => match font.get(b"Encoding").and_then(Object::as_name) {
+            Ok(b"Identity-H" | b"Identity-V") => Some(2),
+            _ => None,
+        },
+        Ok(_) => Some(1),
+        Err(_) => None,
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Code to glyph mapping
+
+fn plan_font(doc: &Document, font: &Dictionary, codes: &BTreeSet<u16>) -> Option<FontPlan> {
+    // Fonts that are already subsets gain little and often use ad hoc glyph names
+    if font.get(b"BaseFont").and_then(Object::as_name).is_ok_and(has_subset_tag) {
+        return None;
+    }
+    let to_unicode_id = font.get(b"ToUnicode").and_then(Object::as_reference).ok();
+
+    match font.get(b"Subtype").and_then(Object::as_name).ok()? {
+        b"Type0" => {
+            if code_bytes(font) != Some(2) {
+                return None;
+            }
+            let descendants = doc.dereference(font.get(b"DescendantFonts").ok()?).ok()?.1.as_array().ok()?;
+            let cid_font = doc.dereference(descendants.first()?).ok()?.1.as_dict().ok()?;
+            let program_id = embedded_program(doc, cid_font)?;
+            let gids = match (cid_font.get(b"Subtype").and_then(Object::as_name).ok()?, cid_font.get(b"CIDToGIDMap")) {
+                (b"CIDFontType0", _) => {
+                    let (data, format) = program_data(doc, program_id)?;
+                    let cff = cff_table(&data, format)?;
+                    // CID-keyed programs map CIDs to glyphs with their charset; in the
+                    // others CIDs are glyph IDs
+                    let cid_gids: BTreeMap<u16, u16> =
+                        (0..cff.number_of_glyphs()).filter_map(|gid| Some((cff.glyph_cid(ttf_parser::GlyphId(gid))?, gid))).collect();
+                    if cid_gids.is_empty() {
+                        codes.clone()
+                    } else {
+                        codes.iter().filter_map(|cid| cid_gids.get(cid)).copied().collect()
+                    }
+                }
+                (b"CIDFontType2", Err(_)) => codes.clone(),
+                (b"CIDFontType2", Ok(Object::Name(name))) if name == b"Identity" => codes.clone(),
+                (b"CIDFontType2", Ok(map)) => {
+                    let stream = doc.dereference(map).ok()?.1.as_stream().ok()?;
+                    let data = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+                    codes
+                        .iter()
+                        .filter_map(|&cid| {
+                            let i = cid as usize * 2;
+                            data.get(i..i + 2).map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                        })
+                        .collect()
+                }
+                _ => return None,
+            };
+            Some(FontPlan { program_id, to_unicode_id, code_bytes: 2, descendant_id: descendant_id(doc, font), gids })
+        }
+        b"TrueType" | b"Type1" => {
+            let program_id = embedded_program(doc, font)?;
+            let (data, format) = program_data(doc, program_id)?;
+            let gids = match format {
+                ProgramFormat::Sfnt => simple_font_gids(doc, font, &data, codes)?,
+                ProgramFormat::Cff => {
+                    let cff = cff_table(&data, format)?;
+                    let by_name = |name: &str| cff.glyph_index_by_name(name).map(|gid| gid.0);
+                    named_simple_font_gids(doc, font, codes, by_name, |code| cff.glyph_index(code).map(|gid| gid.0))?
+                }
+                ProgramFormat::Type1 => {
+                    let program = doc.get_object(program_id).ok()?.as_stream().ok()?;
+                    let (length1, length2) = type1_lengths(doc, &program.dict)?;
+                    let type1 = Type1Program::parse(&data, length1, length2).ok()?;
+                    let by_name = |name: &str| type1.glyph_index_by_name(name);
+                    named_simple_font_gids(doc, font, codes, by_name, |code| type1.glyph_index(code))?
+                }
+            };
+            Some(FontPlan { program_id, to_unicode_id, code_bytes: 1, descendant_id: None, gids })
+        }
+        _ => None,
+    }
+}
+
+/// How an embedded font program is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProgramFormat {
+    /// TrueType or OpenType: FontFile2, or FontFile3 with Subtype OpenType
+    Sfnt,
+    /// FontFile3 with Subtype Type1C or CIDFontType0C
+    Cff,
+    /// FontFile, the only program stream with a /Length2
+    Type1,
+}
+
+fn program_format(program: &Dictionary) -> ProgramFormat {
+    match program.get(b"Subtype").and_then(Object::as_name) {
+        Ok(b"Type1C" | b"CIDFontType0C") => ProgramFormat::Cff,
+        _ if program.has(b"Length2") => ProgramFormat::Type1,
+        _ => ProgramFormat::Sfnt,
+    }
+}
+
+/// The font's embedded program if we can subset it: FontFile, FontFile2, or
+/// FontFile3 with Subtype OpenType, Type1C or CIDFontType0C
+fn embedded_program(doc: &Document, font: &Dictionary) -> Option<ObjectId> {
+    let descriptor = doc.dereference(font.get(b"FontDescriptor").ok()?).ok()?.1.as_dict().ok()?;
+    if let Ok(id) = descriptor.get(b"FontFile2").and_then(Object::as_reference) {
+        return Some(id);
+    }
+    if let Ok(id) = descriptor.get(b"FontFile").and_then(Object::as_reference) {
+        return Some(id);
+    }
+    let id = descriptor.get(b"FontFile3").and_then(Object::as_reference).ok()?;
+    let stream = doc.get_object(id).ok()?.as_stream().ok()?;
+    matches!(stream.dict.get(b"Subtype").and_then(Object::as_name).ok()?, b"OpenType" | b"Type1C" | b"CIDFontType0C").then_some(id)
+}
+
+/// Decompressed font program and its format
+fn program_data(doc: &Document, program_id: ObjectId) -> Option<(Vec<u8>, ProgramFormat)> {
+    let stream = doc.get_object(program_id).ok()?.as_stream().ok()?;
+    let data = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+    Some((data, program_format(&stream.dict)))
+}
+
+/// Length1 and Length2 of a Type 1 program: its cleartext and encrypted parts
+fn type1_lengths(doc: &Document, program: &Dictionary) -> Option<(usize, usize)> {
+    let length = |key: &[u8]| doc.dereference(program.get(key).ok()?).ok()?.1.as_i64().ok().and_then(|n| usize::try_from(n).ok());
+    Some((length(b"Length1")?, length(b"Length2")?))
+}
+
+/// The CFF outlines of a bare CFF program or of an OpenType program, if it has them
+fn cff_table(data: &[u8], format: ProgramFormat) -> Option<ttf_parser::cff::Table<'_>> {
+    match format {
+        ProgramFormat::Cff => ttf_parser::cff::Table::parse(data),
+        ProgramFormat::Sfnt => ttf_parser::Face::parse(data, 0).ok()?.tables().cff,
+        ProgramFormat::Type1 => None,
+    }
+}
+
+/// Every embedded program a font (or its descendant) refers to
+fn font_program_ids(doc: &Document, font: &Dictionary) -> Vec<ObjectId> {
+    let mut fonts = vec![font];
+    if let Some(descendants) = font.get(b"DescendantFonts").ok().and_then(|d| doc.dereference(d).ok()).and_then(|(_, d)| d.as_array().ok()) {
+        fonts.extend(descendants.iter().filter_map(|d| doc.dereference(d).ok()).filter_map(|(_, d)| d.as_dict().ok()));
+    }
+    fonts
+        .into_iter()
+        .filter_map(|f| doc.dereference(f.get(b"FontDescriptor").ok()?).ok()?.1.as_dict().ok())
+        .flat_map(|descriptor| {
+            [&b"FontFile"[..], b"FontFile2", b"FontFile3"]
+                .into_iter()
+                .filter_map(|key| descriptor.get(key).and_then(Object::as_reference).ok())
+        })
+        .collect()
+}
+
+/// Glyphs a simple (single byte) font may show for `codes`. Viewers differ in how
+/// they pick a cmap subtable, so every plausible interpretation is included.
+fn simple_font_gids(doc: &Document, font: &Dictionary, program: &[u8], codes: &BTreeSet<u16>) -> Option<BTreeSet<u16>> {
+    let face = ttf_parser::Face::parse(program, 0).ok()?;
+    let differences = encoding_differences(doc, font);
+    let mut gids = BTreeSet::new();
+
+    for &code in codes {
+        let mut candidates = vec![code as u32, 0xF000 + code as u32];
+        candidates.extend(WIN_ANSI_UNICODE.get(code as usize).copied().flatten().map(|c| c as u32));
+        candidates.extend(MAC_ROMAN_UNICODE.get(code as usize).copied().flatten().map(|c| c as u32));
+
+        if let Some(name) = differences.get(&(code as u8)) {
+            match face.glyph_index_by_name(name) {
+                Some(gid) => {
+                    gids.insert(gid.0);
+                }
+                None => match glyph_name_unicode(name) {
+                    Some(unicode) => candidates.push(unicode),
+                    // A name we cannot resolve might map to any glyph
+                    None => return None,
+                },
+            }
+        }
+
+        let subtables = face.tables().cmap?.subtables;
+        for subtable in subtables {
+            for &candidate in &candidates {
+                if let Some(gid) = subtable.glyph_index(candidate) {
+                    gids.insert(gid.0);
+                }
+            }
+        }
+    }
+    Some(gids)
+}
+
+/// Glyphs a simple font with a bare CFF or Type 1 program may show for `codes`,
+/// which select glyphs by name. As for sfnt programs, every plausible encoding is
+/// included: /Differences, the base encoding and the program's built-in encoding.
+fn named_simple_font_gids(
+    doc: &Document,
+    font: &Dictionary,
+    codes: &BTreeSet<u16>,
+    by_name: impl Fn(&str) -> Option<u16>,
+    builtin: impl Fn(u8) -> Option<u16>,
+) -> Option<BTreeSet<u16>> {
+    let base_encoding = match font.get(b"Encoding").ok().map(|e| doc.dereference(e)) {
+        Some(Ok((_, Object::Name(name)))) => Some(name.as_slice()),
+        Some(Ok((_, Object::Dictionary(encoding)))) => encoding.get(b"BaseEncoding").and_then(Object::as_name).ok(),
+        _ => None,
+    };
+    let base_name: fn(u8) -> Option<&'static str> = match base_encoding {
+        None | Some(b"StandardEncoding") => standard_encoding_name,
+        Some(b"WinAnsiEncoding") => win_ansi_name,
+        // MacRomanEncoding and MacExpertEncoding programs are kept whole
+        Some(_) => return None,
+    };
+    let differences = encoding_differences(doc, font);
+    let mut gids = BTreeSet::new();
+
+    for &code in codes {
+        let code = u8::try_from(code).ok()?;
+        let names = [differences.get(&code).map(String::as_str), base_name(code)];
+        gids.extend(names.into_iter().flatten().filter_map(&by_name));
+        gids.extend(builtin(code));
+    }
+    Some(gids)
+}
+
+/// Code to glyph name entries from the font's /Encoding /Differences array
+fn encoding_differences(doc: &Document, font: &Dictionary) -> BTreeMap<u8, String> {
+    let mut names = BTreeMap::new();
+    let Some(encoding) = font.get(b"Encoding").ok().and_then(|e| doc.dereference(e).ok()).and_then(|(_, e)| e.as_dict().ok()) else {
+        return names;
+    };
+    let Ok(Object::Array(differences)) = encoding.get(b"Differences") else { return names };
+    let mut code = 0i64;
+    for item in differences {
+        match item {
+            Object::Integer(start) => code = *start,
+            Object::Name(name) => {
+                if let Ok(code) = u8::try_from(code) {
+                    names.insert(code, String::from_utf8_lossy(name).into_owned());
+                }
+                code += 1;
+            }
+            _ => {}
+        }
+    }
+    names
+}
+
+/// Unicode value of `uniXXXX`/`uXXXX` names and single ASCII letter or digit names
+fn glyph_name_unicode(name: &str) -> Option<u32> {
+    let hex = name.strip_prefix("uni").or_else(|| name.strip_prefix('u'));
+    if let Some(value) = hex.filter(|h| (4..=6).contains(&h.len())).and_then(|h| u32::from_str_radix(h, 16).ok()) {
+        return Some(value);
+    }
+    match name.as_bytes() {
+        [c] if c.is_ascii_alphanumeric() => Some(*c as u32),
+        _ => None,
+    }
+}
+
+fn has_subset_tag(name: &[u8]) -> bool {
+    name.len() > 7 && name[6] == b'+' && name[..6].iter().all(u8::is_ascii_uppercase)
+}
+
+/// WinAnsiEncoding differs from Latin-1 only in 0x80-0x9F
+static WIN_ANSI_UNICODE: [Option<u16>; 256] = {
+    let mut table = [None; 256];
+    let mut i = 0x20;
+    while i < 256 {
+        table[i] = Some(i as u16);
+        i += 1;
+    }
+    let high: [u16; 32] = [
+        0x20AC, 0, 0x201A, 0x0192, 0x201E, 0x2026, 0x2020, 0x2021, 0x02C6, 0x2030, 0x0160, 0x2039, 0x0152, 0, 0x017D, 0,
+        0, 0x2018, 0x2019, 0x201C, 0x201D, 0x2022, 0x2013, 0x2014, 0x02DC, 0x2122, 0x0161, 0x203A, 0x0153, 0, 0x017E, 0x0178,
+    ];
+    let mut i = 0;
+    while i < 32 {
+        table[0x80 + i] = if high[i] == 0 { None } else { Some(high[i]) };
+        i += 1;
+    }
+    table
+};
+
+/// MacRomanEncoding: ASCII plus its own upper half
+static MAC_ROMAN_UNICODE: [Option<u16>; 256] = {
+    let mut table = [None; 256];
+    let mut i = 0x20;
+    while i < 0x7F {
+        table[i] = Some(i as u16);
+        i += 1;
+    }
+    let high: [u16; 128] = [
+        0x00C4, 0x00C5, 0x00C7, 0x00C9, 0x00D1, 0x00D6, 0x00DC, 0x00E1, 0x00E0, 0x00E2, 0x00E4, 0x00E3, 0x00E5, 0x00E7, 0x00E9, 0x00E8,
+        0x00EA, 0x00EB, 0x00ED, 0x00EC, 0x00EE, 0x00EF, 0x00F1, 0x00F3, 0x00F2, 0x00F4, 0x00F6, 0x00F5, 0x00FA, 0x00F9, 0x00FB, 0x00FC,
+        0x2020, 0x00B0, 0x00A2, 0x00A3, 0x00A7, 0x2022, 0x00B6, 0x00DF, 0x00AE, 0x00A9, 0x2122, 0x00B4, 0x00A8, 0x2260, 0x00C6, 0x00D8,
+        0x221E, 0x00B1, 0x2264, 0x2265, 0x00A5, 0x00B5, 0x2202, 0x2211, 0x220F, 0x03C0, 0x222B, 0x00AA, 0x00BA, 0x03A9, 0x00E6, 0x00F8,
+        0x00BF, 0x00A1, 0x00AC, 0x221A, 0x0192, 0x2248, 0x2206, 0x00AB, 0x00BB, 0x2026, 0x00A0, 0x00C0, 0x00C3, 0x00D5, 0x0152, 0x0153,
+        0x2013, 0x2014, 0x201C, 0x201D, 0x2018, 0x2019, 0x00F7, 0x25CA, 0x00FF, 0x0178, 0x2044, 0x20AC, 0x2039, 0x203A, 0xFB01, 0xFB02,
+        0x2021, 0x00B7, 0x201A, 0x201E, 0x2030, 0x00C2, 0x00CA, 0x00C1, 0x00CB, 0x00C8, 0x00CD, 0x00CE, 0x00CF, 0x00CC, 0x00D3, 0x00D4,
+        0xF8FF, 0x00D2, 0x00DA, 0x00DB, 0x00D9, 0x0131, 0x02C6, 0x02DC, 0x00AF, 0x02D8, 0x02D9, 0x02DA, 0x00B8, 0x02DD, 0x02DB, 0x02C7,
+    ];
+    let mut i = 0;
+    while i < 128 {
+        table[0x80 + i] = Some(high[i]);
+        i += 1;
+    }
+    table
+};
+
+/// Glyph names of printable ASCII codes in WinAnsiEncoding. StandardEncoding differs
+/// only at 0x27 and 0x60
+static ASCII_NAMES: [&str; 95] = [
+    "space", "exclam", "quotedbl", "numbersign", "dollar", "percent", "ampersand", "quotesingle", "parenleft", "parenright",
+    "asterisk", "plus", "comma", "hyphen", "period", "slash", "zero", "one", "two", "three", "four", "five", "six", "seven",
+    "eight", "nine", "colon", "semicolon", "less", "equal", "greater", "question", "at", "A", "B", "C", "D", "E", "F", "G",
+    "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S", "T", "U", "V", "W", "X", "Y", "Z", "bracketleft",
+    "backslash", "bracketright", "asciicircum", "underscore", "grave", "a", "b", "c", "d", "e", "f", "g", "h", "i", "j",
+    "k", "l", "m", "n", "o", "p", "q", "r", "s", "t", "u", "v", "w", "x", "y", "z", "braceleft", "bar", "braceright",
+    "asciitilde",
+];
+
+/// WinAnsiEncoding glyph names for 0x80-0xFF; empty where no glyph is assigned
+static WIN_ANSI_HIGH_NAMES: [&str; 128] = [
+    "Euro", "", "quotesinglbase", "florin", "quotedblbase", "ellipsis", "dagger", "daggerdbl", "circumflex", "perthousand",
+    "Scaron", "guilsinglleft", "OE", "", "Zcaron", "", "", "quoteleft", "quoteright", "quotedblleft", "quotedblright",
+    "bullet", "endash", "emdash", "tilde", "trademark", "scaron", "guilsinglright", "oe", "", "zcaron", "Ydieresis",
+    "space", "exclamdown", "cent", "sterling", "currency", "yen", "brokenbar", "section", "dieresis", "copyright",
+    "ordfeminine", "guillemotleft", "logicalnot", "hyphen", "registered", "macron", "degree", "plusminus", "twosuperior",
+    "threesuperior", "acute", "mu", "paragraph", "periodcentered", "cedilla", "onesuperior", "ordmasculine",
+    "guillemotright", "onequarter", "onehalf", "threequarters", "questiondown", "Agrave", "Aacute", "Acircumflex",
+    "Atilde", "Adieresis", "Aring", "AE", "Ccedilla", "Egrave", "Eacute", "Ecircumflex", "Edieresis", "Igrave", "Iacute",
+    "Icircumflex", "Idieresis", "Eth", "Ntilde", "Ograve", "Oacute", "Ocircumflex", "Otilde", "Odieresis", "multiply",
+    "Oslash", "Ugrave", "Uacute", "Ucircumflex", "Udieresis", "Yacute", "Thorn", "germandbls", "agrave", "aacute",
+    "acircumflex", "atilde", "adieresis", "aring", "ae", "ccedilla", "egrave", "eacute", "ecircumflex", "edieresis",
+    "igrave", "iacute", "icircumflex", "idieresis", "eth", "ntilde", "ograve", "oacute", "ocircumflex", "otilde",
+    "odieresis", "divide", "oslash", "ugrave", "uacute", "ucircumflex", "udieresis", "yacute", "thorn", "ydieresis",
+];
+
+/// StandardEncoding glyph names above 0x7F
+static STANDARD_HIGH_NAMES: [(u8, &str); 54] = [
+    (0xA1, "exclamdown"), (0xA2, "cent"), (0xA3, "sterling"), (0xA4, "fraction"), (0xA5, "yen"), (0xA6, "florin"),
+    (0xA7, "section"), (0xA8, "currency"), (0xA9, "quotesingle"), (0xAA, "quotedblleft"), (0xAB, "guillemotleft"),
+    (0xAC, "guilsinglleft"), (0xAD, "guilsinglright"), (0xAE, "fi"), (0xAF, "fl"), (0xB1, "endash"), (0xB2, "dagger"),
+    (0xB3, "daggerdbl"), (0xB4, "periodcentered"), (0xB6, "paragraph"), (0xB7, "bullet"), (0xB8, "quotesinglbase"),
+    (0xB9, "quotedblbase"), (0xBA, "quotedblright"), (0xBB, "guillemotright"), (0xBC, "ellipsis"), (0xBD, "perthousand"),
+    (0xBF, "questiondown"), (0xC1, "grave"), (0xC2, "acute"), (0xC3, "circumflex"), (0xC4, "tilde"), (0xC5, "macron"),
+    (0xC6, "breve"), (0xC7, "dotaccent"), (0xC8, "dieresis"), (0xCA, "ring"), (0xCB, "cedilla"), (0xCD, "hungarumlaut"),
+    (0xCE, "ogonek"), (0xCF, "caron"), (0xD0, "emdash"), (0xE1, "AE"), (0xE3, "ordfeminine"), (0xE8, "Lslash"),
+    (0xE9, "Oslash"), (0xEA, "OE"), (0xEB, "ordmasculine"), (0xF1, "ae"), (0xF5, "dotlessi"), (0xF8, "lslash"),
+    (0xF9, "oslash"), (0xFA, "oe"), (0xFB, "germandbls"),
+];
+
+fn win_ansi_name(code: u8) -> Option<&'static str> {
+    match code {
+        0x20..=0x7E => Some(ASCII_NAMES[code as usize - 0x20]),
+        0x80..=0xFF => Some(WIN_ANSI_HIGH_NAMES[code as usize - 0x80]).filter(|name| !name.is_empty()),
+        _ => None,
+    }
+}
+
+pub(crate) fn standard_encoding_name(code: u8) -> Option<&'static str> {
+    match code {
+        0x27 => Some("quoteright"),
+        0x60 => Some("quoteleft"),
+        0x20..=0x7E => Some(ASCII_NAMES[code as usize - 0x20]),
+        _ => STANDARD_HIGH_NAMES.iter().find(|(c, _)| *c == code).map(|(_, name)| *name),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Rewriting
+
+/// Replace a font program with its subset. Returns the bytes saved in the
+/// (compressed) stream, 0 when the subset is not smaller.
+fn subset_program(doc: &mut Document, program_id: ObjectId, gids: &BTreeSet<u16>) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let stream = doc.get_object(program_id)?.as_stream()?;
+    let data = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+
+    let format = program_format(&stream.dict);
+    let (subset, type1_lengths) = match format {
+        ProgramFormat::Type1 => {
+            let (length1, length2) = type1_lengths(doc, &stream.dict).ok_or("Type 1 program without lengths")?;
+            let (subset, lengths) = Type1Program::parse(&data, length1, length2)?.subset(gids);
+            (subset, Some(lengths))
+        }
+        _ => {
+            // Glyph 0 (.notdef) is always kept
+            let glyphs: Vec<u16> = std::iter::once(0).chain(gids.iter().copied()).collect();
+            (subset_glyphs(&data, format, &glyphs)?, None)
+        }
+    };
+    let compressed = filters::deflate(&subset);
+    let saved = stream.content.len().saturating_sub(compressed.len());
+    if saved == 0 {
+        return Ok(0);
+    }
+
+    let mut dict = stream.dict.clone();
+    dict.set(b"Filter", Object::Name(b"FlateDecode".to_vec()));
+    dict.remove(b"DecodeParms");
+    if let Some(lengths) = type1_lengths {
+        for (key, length) in [&b"Length1"[..], b"Length2", b"Length3"].into_iter().zip(lengths) {
+            dict.set(key, Object::Integer(length as i64));
+        }
+    } else if dict.has(b"Length1") || !dict.has(b"Subtype") {
+        // FontFile2 streams record the uncompressed program length
+        dict.set(b"Length1", Object::Integer(subset.len() as i64));
+    }
+    doc.objects.insert(program_id, Object::Stream(Stream::new(dict, compressed)));
+    Ok(saved)
+}
+
+/// Subset a font program to `glyphs`. Bare CFF programs are wrapped in a minimal
+/// OpenType font for the subsetter and taken out of its result again.
+///
+/// CFF subsets are compared glyph by glyph with the original: accented glyphs built
+/// with `seac` draw two other glyphs the subsetter does not know about. When an
+/// outline changed, the StandardEncoding glyphs `seac` may refer to are kept too.
+fn subset_glyphs(data: &[u8], format: ProgramFormat, glyphs: &[u16]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let bare_cff = format == ProgramFormat::Cff;
+    let original = cff_table(data, format);
+    let font = match (bare_cff, original) {
+        (true, Some(cff)) => Cow::Owned(wrap_cff(data, cff.number_of_glyphs())),
+        (true, None) => return Err("unreadable CFF program".into()),
+        (false, _) => Cow::Borrowed(data),
+    };
+    let subset = |glyphs: &[u16]| -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let subset = subsetter::subset(&font, 0, subsetter::Profile::pdf(glyphs)).map_err(|e| format!("{:?}", e))?;
+        if !bare_cff {
+            return Ok(subset);
+        }
+        let raw = ttf_parser::RawFace::parse(&subset, 0).map_err(|e| e.to_string())?;
+        Ok(raw.table(ttf_parser::Tag::from_bytes(b"CFF ")).ok_or("subset has no CFF table")?.to_vec())
+    };
+
+    let result = subset(glyphs)?;
+    let Some(original) = original else { return Ok(result) };
+    if same_outlines(&original, cff_table(&result, format), glyphs) {
+        return Ok(result);
+    }
+    let mut extended = glyphs.to_vec();
+    extended.extend((0..=255).filter_map(standard_encoding_name).filter_map(|name| original.glyph_index_by_name(name)).map(|gid| gid.0));
+    extended.sort_unstable();
+    extended.dedup();
+    let result = subset(&extended)?;
+    if same_outlines(&original, cff_table(&result, format), glyphs) {
+        Ok(result)
+    } else {
+        Err("subset changes glyph outlines".into())
+    }
+}
+
+/// An OpenType font holding just `cff` and the glyph count the subsetter requires
+fn wrap_cff(cff: &[u8], glyph_count: u16) -> Vec<u8> {
+    let maxp_offset = (44 + cff.len()).next_multiple_of(4);
+    let mut font = Vec::with_capacity(maxp_offset + 6);
+    // Header: OTTO, two tables, searchRange 32, entrySelector 1, rangeShift 0
+    font.extend_from_slice(b"OTTO\x00\x02\x00\x20\x00\x01\x00\x00");
+    // Table records are sorted by tag; checksums are left at 0
+    for (tag, offset, length) in [(b"CFF ", 44, cff.len()), (b"maxp", maxp_offset, 6)] {
+        font.extend_from_slice(tag);
+        font.extend_from_slice(&[0; 4]);
+        font.extend_from_slice(&(offset as u32).to_be_bytes());
+        font.extend_from_slice(&(length as u32).to_be_bytes());
+    }
+    font.extend_from_slice(cff);
+    font.resize(maxp_offset, 0);
+    // maxp version 0.5, for CFF outlines
+    font.extend_from_slice(&[0x00, 0x00, 0x50, 0x00]);
+    font.extend_from_slice(&glyph_count.to_be_bytes());
+    font
+}
+
+/// Whether `subset` draws every glyph in `glyphs` exactly as `original` does
+fn same_outlines(original: &ttf_parser::cff::Table, subset: Option<ttf_parser::cff::Table>, glyphs: &[u16]) -> bool {
+    let Some(subset) = subset else { return false };
+    glyphs.iter().all(|&gid| {
+        let outline = |table: &ttf_parser::cff::Table| {
+            let mut recorder = OutlineRecorder::default();
+            table.outline(ttf_parser::GlyphId(gid), &mut recorder).ok().map(|_| recorder.0)
+        };
+        outline(original) == outline(&subset)
+    })
+}
+
+/// The segments of a glyph outline, as an operator tag followed by its coordinates
+#[derive(Default)]
+struct OutlineRecorder(Vec<f32>);
+
+impl ttf_parser::OutlineBuilder for OutlineRecorder {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.0.extend([0.0, x, y]);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        self.0.extend([1.0, x, y]);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        self.0.extend([2.0, x1, y1, x, y]);
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        self.0.extend([3.0, x1, y1, x2, y2, x, y]);
+    }
+
+    fn close(&mut self) {
+        self.0.push(4.0);
+    }
+}
+
+/// Delete entries for `fonts` from every /Font resource dictionary. Returns the
+/// number of fonts that were referenced from at least one of them.
+fn remove_font_resources(doc: &mut Document, fonts: &BTreeSet<ObjectId>) -> usize {
+    if fonts.is_empty() {
+        return 0;
+    }
+    // Resource and font dictionaries may be indirect objects of their own
+    let mut resource_ids = BTreeSet::new();
+    let mut font_dict_ids = BTreeSet::new();
+    for object in doc.objects.values() {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => &stream.dict,
+            _ => continue,
+        };
+        let resources = match dict.get(b"Resources") {
+            Ok(Object::Reference(id)) => {
+                resource_ids.insert(*id);
+                doc.get_dictionary(*id).ok()
+            }
+            Ok(Object::Dictionary(resources)) => Some(resources),
+            _ => None,
+        };
+        if let Some(Ok(id)) = resources.map(|r| r.get(b"Font").and_then(Object::as_reference)) {
+            font_dict_ids.insert(id);
+        }
+    }
+
+    let mut removed = BTreeSet::new();
+    let mut remove_entries = |fonts_dict: &mut Dictionary| {
+        let names: Vec<Vec<u8>> = fonts_dict
+            .iter()
+            .filter(|(_, f)| f.as_reference().is_ok_and(|id| fonts.contains(&id)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in names {
+            if let Some(Object::Reference(id)) = fonts_dict.remove(&name) {
+                removed.insert(id);
+            }
+        }
+    };
+    for (id, object) in doc.objects.iter_mut() {
+        if font_dict_ids.contains(id) {
+            if let Object::Dictionary(fonts_dict) = object {
+                remove_entries(fonts_dict);
+            }
+            continue;
+        }
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => &mut stream.dict,
+            _ => continue,
+        };
+        let resources = if resource_ids.contains(id) { Some(dict) } else { dict.get_mut(b"Resources").ok().and_then(|r| r.as_dict_mut().ok()) };
+        if let Some(Ok(Object::Dictionary(fonts_dict))) = resources.map(|r| r.get_mut(b"Font")) {
+            remove_entries(fonts_dict);
+        }
+    }
+    removed.len()
+}
+
+/// The descendant CIDFont of a Type0 font
+fn descendant_id(doc: &Document, font: &Dictionary) -> Option<ObjectId> {
+    let (_, descendants) = doc.dereference(font.get(b"DescendantFonts").ok()?).ok()?;
+    descendants.as_array().ok()?.first()?.as_reference().ok()
+}
+
+/// Drop /W entries of a CIDFont for CIDs no Type0 font using it shows
+fn trim_cid_widths(doc: &mut Document, cid_font_id: ObjectId, codes: &BTreeSet<u16>) {
+    let Ok(cid_font) = doc.get_dictionary(cid_font_id) else { return };
+    let Ok(Object::Array(w)) = cid_font.get(b"W") else { return };
+    let widths = parse_cid_widths(w);
+    let kept: BTreeMap<u16, Object> = widths.into_iter().filter(|(cid, _)| codes.contains(cid)).collect();
+    let new_w = build_cid_widths(kept);
+    if let Ok(cid_font) = doc.get_dictionary_mut(cid_font_id) {
+        cid_font.set(b"W", Object::Array(new_w));
+    }
+}
+
+/// Drop width entries of a simple font for character codes that are never shown
+fn trim_widths(doc: &mut Document, font_id: ObjectId, codes: &BTreeSet<u16>) {
+    let (Some(&first_used), Some(&last_used)) = (codes.first(), codes.last()) else { return };
+    let Ok(font) = doc.get_dictionary_mut(font_id) else { return };
+    let first_char = font.get(b"FirstChar").and_then(Object::as_i64).unwrap_or(0);
+    let Ok(Object::Array(widths)) = font.get(b"Widths") else { return };
+    let (first_used, last_used) = (first_used as i64, last_used as i64);
+    let last_char = first_char + widths.len() as i64 - 1;
+    if first_used < first_char || last_used > last_char {
+        return;
+    }
+    let kept = widths[(first_used - first_char) as usize..=(last_used - first_char) as usize].to_vec();
+    font.set(b"FirstChar", Object::Integer(first_used));
+    font.set(b"LastChar", Object::Integer(last_used));
+    font.set(b"Widths", Object::Array(kept));
+}
+
+/// Expand a CIDFont /W array into one width per CID
+fn parse_cid_widths(w: &[Object]) -> BTreeMap<u16, Object> {
+    let mut widths = BTreeMap::new();
+    let mut i = 0;
+    while i < w.len() {
+        let Ok(first) = w[i].as_i64() else { break };
+        match (w.get(i + 1), w.get(i + 2)) {
+            (Some(Object::Array(list)), _) => {
+                for (offset, width) in list.iter().enumerate() {
+                    if let Ok(cid) = u16::try_from(first + offset as i64) {
+                        widths.insert(cid, width.clone());
+                    }
+                }
+                i += 2;
+            }
+            (Some(last), Some(width)) => {
+                let last = last.as_i64().unwrap_or(first);
+                for cid in first..=last {
+                    if let Ok(cid) = u16::try_from(cid) {
+                        widths.insert(cid, width.clone());
+                    }
+                }
+                i += 3;
+            }
+            _ => break,
+        }
+    }
+    widths
+}
+
+/// Build a /W array, grouping consecutive CIDs as `first [w1 w2 ...]`
+fn build_cid_widths(widths: BTreeMap<u16, Object>) -> Vec<Object> {
+    let mut out = Vec::new();
+    let mut run: Vec<Object> = Vec::new();
+    let mut run_start = 0u16;
+    let mut previous: Option<u16> = None;
+    for (cid, width) in widths {
+        if previous.is_some_and(|p| p as u32 + 1 != cid as u32) {
+            out.push(Object::Integer(run_start as i64));
+            out.push(Object::Array(std::mem::take(&mut run)));
+        }
+        if run.is_empty() {
+            run_start = cid;
+        }
+        run.push(width);
+        previous = Some(cid);
+    }
+    if !run.is_empty() {
+        out.push(Object::Integer(run_start as i64));
+        out.push(Object::Array(run));
+    }
+    out
+}
+
+/// Prefix BaseFont and FontName with the six letter tag that marks a subset
+fn tag_font_names(doc: &mut Document, font_id: ObjectId, gids: &Option<BTreeSet<u16>>) {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    gids.hash(&mut hasher);
+    let mut hash = hasher.finish();
+    let tag: Vec<u8> = (0..6)
+        .map(|_| {
+            let letter = b'A' + (hash % 26) as u8;
+            hash /= 26;
+            letter
+        })
+        .collect();
+
+    let mut dict_ids = vec![font_id];
+    dict_ids.extend(doc.get_dictionary(font_id).ok().and_then(|font| descendant_id(doc, font)));
+    let descriptors: Vec<ObjectId> = dict_ids
+        .iter()
+        .filter_map(|id| doc.get_dictionary(*id).ok()?.get(b"FontDescriptor").ok()?.as_reference().ok())
+        .collect();
+
+    let tagged = |name: &[u8]| -> Vec<u8> {
+        if has_subset_tag(name) {
+            name.to_vec()
+        } else {
+            [&tag[..], b"+", name].concat()
+        }
+    };
+    for id in dict_ids {
+        if let Ok(dict) = doc.get_dictionary_mut(id) {
+            if let Ok(name) = dict.get(b"BaseFont").and_then(Object::as_name) {
+                let name = tagged(name);
+                dict.set(b"BaseFont", Object::Name(name));
+            }
+        }
+    }
+    for id in descriptors {
+        if let Ok(dict) = doc.get_dictionary_mut(id) {
+            if let Ok(name) = dict.get(b"FontName").and_then(Object::as_name) {
+                let name = tagged(name);
+                dict.set(b"FontName", Object::Name(name));
+            }
+        }
+    }
+}
+
+/// Rewrite a ToUnicode CMap with only the codes in use
+fn trim_to_unicode(doc: &mut Document, id: ObjectId, code_bytes: usize, codes: &BTreeSet<u16>) {
+    let Ok(stream) = doc.get_object(id).and_then(Object::as_stream) else { return };
+    let data = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+    let Some(mappings) = parse_to_unicode(&data) else { return };
+    let kept: Vec<(u16, Vec<u8>)> = mappings.into_iter().filter(|(code, _)| codes.contains(code)).collect();
+    if kept.is_empty() {
+        return;
+    }
+
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02X}", b)).collect::<String>();
+    let code_hex = |code: u16| if code_bytes == 1 { format!("{:02X}", code) } else { format!("{:04X}", code) };
+    let mut cmap = String::from(
+        "/CIDInit /ProcSet findresource begin\n12 dict begin\nbegincmap\n\
+         /CIDSystemInfo << /Registry (Adobe) /Ordering (UCS) /Supplement 0 >> def\n\
+         /CMapName /Adobe-Identity-UCS def\n/CMapType 2 def\n1 begincodespacerange\n",
+    );
+    cmap.push_str(if code_bytes == 1 { "<00> <FF>\n" } else { "<0000> <FFFF>\n" });
+    cmap.push_str("endcodespacerange\n");
+    // CMap blocks hold at most 100 entries
+    for block in kept.chunks(100) {
+        cmap.push_str(&format!("{} beginbfchar\n", block.len()));
+        for (code, unicode) in block {
+            cmap.push_str(&format!("<{}> <{}>\n", code_hex(*code), hex(unicode)));
+        }
+        cmap.push_str("endbfchar\n");
+    }
+    cmap.push_str("endcmap\nCMapName currentdict /CMap defineresource pop\nend\nend\n");
+
+    let mut dict = stream.dict.clone();
+    let content = filters::deflate(cmap.as_bytes());
+    dict.set(b"Filter", Object::Name(b"FlateDecode".to_vec()));
+    dict.remove(b"DecodeParms");
+    doc.objects.insert(id, Object::Stream(Stream::new(dict, content)));
+}
+
+/// Read bfchar and bfrange entries of a ToUnicode CMap as code to UTF-16BE bytes
+fn parse_to_unicode(data: &[u8]) -> Option<BTreeMap<u16, Vec<u8>>> {
+    let tokens = cmap_tokens(data);
+    let mut mappings = BTreeMap::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        match &tokens[i] {
+            CMapToken::Keyword(k) if k == "beginbfchar" => {
+                i += 1;
+                while let (Some(CMapToken::Hex(src)), Some(CMapToken::Hex(dst))) = (tokens.get(i), tokens.get(i + 1)) {
+                    mappings.insert(hex_code(src)?, dst.clone());
+                    i += 2;
+                }
+            }
+            CMapToken::Keyword(k) if k == "beginbfrange" => {
+                i += 1;
+                while let (Some(CMapToken::Hex(lo)), Some(CMapToken::Hex(hi))) = (tokens.get(i), tokens.get(i + 1)) {
+                    let (lo, hi) = (hex_code(lo)?, hex_code(hi)?);
+                    match tokens.get(i + 2) {
+                        Some(CMapToken::Hex(dst)) => {
+                            for (offset, code) in (lo..=hi).enumerate() {
+                                mappings.insert(code, offset_unicode(dst, offset as u16));
+                            }
+                            i += 3;
+                        }
+                        Some(CMapToken::ArrayStart) => {
+                            i += 3;
+                            let mut code = lo;
+                            while let Some(CMapToken::Hex(dst)) = tokens.get(i) {
+                                mappings.insert(code, dst.clone());
+                                code = code.wrapping_add(1);
+                                i += 1;
+                            }
+                            if !matches!(tokens.get(i), Some(CMapToken::ArrayEnd)) {
+                                return None;
+                            }
+                            i += 1;
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    (!mappings.is_empty()).then_some(mappings)
+}
+
+#[derive(Debug)]
+enum CMapToken {
+    Hex(Vec<u8>),
+    Keyword(String),
+    ArrayStart,
+    ArrayEnd,
+}
+
+fn cmap_tokens(data: &[u8]) -> Vec<CMapToken> {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        match data[i] {
+            b'<' if data.get(i + 1) != Some(&b'<') => {
+                let end = data[i..].iter().position(|&b| b == b'>').map_or(data.len(), |p| i + p);
+                let digits: Vec<u8> = data[i + 1..end].iter().copied().filter(u8::is_ascii_hexdigit).collect();
+                let bytes = digits
+                    .chunks(2)
+                    .map(|pair| {
+                        let text = std::str::from_utf8(pair).unwrap_or("0");
+                        u8::from_str_radix(text, 16).unwrap_or(0) << if pair.len() == 1 { 4 } else { 0 }
+                    })
+                    .collect();
+                tokens.push(CMapToken::Hex(bytes));
+                i = end + 1;
+            }
+            b'<' | b'>' => i += 2,
+            b'[' => {
+                tokens.push(CMapToken::ArrayStart);
+                i += 1;
+            }
+            b']' => {
+                tokens.push(CMapToken::ArrayEnd);
+                i += 1;
+            }
+            b'%' => {
+                while i < data.len() && data[i] != b'\n' && data[i] != b'\r' {
+                    i += 1;
+                }
+            }
+            b'(' => {
+                // Literal strings (e.g. in CIDSystemInfo) are skipped
+                let mut depth = 0;
+                while i < data.len() {
+                    match data[i] {
+                        b'\\' => i += 1,
+                        b'(' => depth += 1,
+                        b')' => depth -= 1,
+                        _ => {}
+                    }
+                    i += 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            }
+            b if b.is_ascii_alphabetic() => {
+                let start = i;
+                while i < data.len() && data[i].is_ascii_alphanumeric() {
+                    i += 1;
+                }
+                tokens.push(CMapToken::Keyword(String::from_utf8_lossy(&data[start..i]).into_owned()));
+            }
+            _ => i += 1,
+        }
+    }
+    tokens
+}
+
+fn hex_code(bytes: &[u8]) -> Option<u16> {
+    match bytes {
+        [b] => Some(*b as u16),
+        [hi, lo] => Some(u16::from_be_bytes([*hi, *lo])),
+        _ => None,
+    }
+}
+
+/// `dst` with its last UTF-16 code unit advanced by `offset`
+fn offset_unicode(dst: &[u8], offset: u16) -> Vec<u8> {
+    let mut out = dst.to_vec();
+    if out.len() >= 2 {
+        let n = out.len();
+        let unit = u16::from_be_bytes([out[n - 2], out[n - 1]]).wrapping_add(offset);
+        out[n - 2..].copy_from_slice(&unit.to_be_bytes());
+    } else if let Some(last) = out.last_mut() {
+        *last = last.wrapping_add(offset as u8);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    /// A one page document whose page selects /F1 and paints /X1, a form with
+    /// `form_content` and optionally its own resources naming /F2
+    fn document(page_content: &str, form_content: &str, form_has_resources: bool) -> (Document, ObjectId, ObjectId) {
+        let mut doc = Document::with_version("1.5");
+        let f1 = doc.add_object(dictionary! { "Type" => "Font", "Subtype" => "TrueType", "BaseFont" => "Page" });
+        let f2 = doc.add_object(dictionary! { "Type" => "Font", "Subtype" => "TrueType", "BaseFont" => "Form" });
+        let mut form_dict = dictionary! { "Type" => "XObject", "Subtype" => "Form", "BBox" => vec![0.into(), 0.into(), 10.into(), 10.into()] };
+        if form_has_resources {
+            form_dict.set("Resources", dictionary! { "Font" => dictionary! { "F2" => f2 } });
+        }
+        let form = doc.add_object(Stream::new(form_dict, form_content.as_bytes().to_vec()));
+        let content = doc.add_object(Stream::new(Dictionary::new(), page_content.as_bytes().to_vec()));
+        let pages_id = doc.new_object_id();
+        let page = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content,
+            "Resources" => dictionary! {
+                "Font" => dictionary! { "F1" => f1 },
+                "XObject" => dictionary! { "X1" => form },
+            },
+        });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+        (doc, f1, f2)
+    }
+
+    fn codes(usage: &FontUsage, font: ObjectId) -> Vec<u16> {
+        usage.codes.get(&font).map(|codes| codes.iter().copied().collect()).unwrap_or_default()
+    }
+
+    /// A CFF program with glyphs .notdef, A, B, Aacute (A with B as accent, through
+    /// seac) and D. Without an /Encoding it uses StandardEncoding
+    fn cff_program() -> Vec<u8> {
+        fn index(items: &[&[u8]]) -> Vec<u8> {
+            let mut out = (items.len() as u16).to_be_bytes().to_vec();
+            if items.is_empty() {
+                return out;
+            }
+            out.push(1);
+            let mut offset = 1;
+            out.push(offset);
+            for item in items {
+                offset += item.len() as u8;
+                out.push(offset);
+            }
+            items.iter().for_each(|item| out.extend_from_slice(item));
+            out
+        }
+        let offset = |value: usize| [&[29][..], &(value as i32).to_be_bytes()].concat();
+
+        // Numbers are stored as value + 139; 21 is rmoveto, 5 rlineto, 14 endchar
+        let char_strings: [&[u8]; 5] = [
+            &[14],
+            &[139, 139, 21, 239, 139, 5, 139, 239, 5, 14],
+            &[139, 139, 21, 189, 139, 5, 14],
+            &[139, 239, 204, 205, 14],
+            &[139, 139, 21, 239, 239, 5, 14],
+        ];
+        // SIDs of A, B and D, then Aacute from the String INDEX
+        let charset = [0, 0, 34, 0, 35, 1, 135, 0, 37];
+        let private = [139, 20];
+
+        let header = [1, 0, 4, 1];
+        let name = index(&[b"Test"]);
+        let strings = index(&[b"Aacute"]);
+        let global_subrs = index(&[]);
+        // Four 5 byte integers and three operators
+        let top_len = 4 * 5 + 3;
+        let top_index_len = index(&[&vec![0; top_len]]).len();
+        let charset_offset = header.len() + name.len() + top_index_len + strings.len() + global_subrs.len();
+        let char_strings_offset = charset_offset + charset.len();
+        let char_strings = index(&char_strings);
+        let private_offset = char_strings_offset + char_strings.len();
+        let top = [
+            offset(charset_offset),
+            vec![15],
+            offset(char_strings_offset),
+            vec![17],
+            offset(private.len()),
+            offset(private_offset),
+            vec![18],
+        ]
+        .concat();
+        [&header[..], &name, &index(&[&top]), &strings, &global_subrs, &charset, &char_strings, &private].concat()
+    }
+
+    fn outline(cff: &ttf_parser::cff::Table, gid: u16) -> Option<Vec<f32>> {
+        let mut recorder = OutlineRecorder::default();
+        cff.outline(ttf_parser::GlyphId(gid), &mut recorder).ok().map(|_| recorder.0)
+    }
+
+    #[test]
+    fn cff_programs_are_subset() {
+        let program = cff_program();
+        let original = cff_table(&program, ProgramFormat::Cff).unwrap();
+        let subset = subset_glyphs(&program, ProgramFormat::Cff, &[0, 1]).unwrap();
+        let subset = cff_table(&subset, ProgramFormat::Cff).unwrap();
+        assert_eq!(outline(&subset, 1), outline(&original, 1));
+        assert!(outline(&original, 4).is_some_and(|o| !o.is_empty()));
+        assert_ne!(outline(&subset, 4), outline(&original, 4));
+    }
+
+    #[test]
+    fn cff_subsets_keep_seac_components() {
+        let program = cff_program();
+        let original = cff_table(&program, ProgramFormat::Cff).unwrap();
+        let subset = subset_glyphs(&program, ProgramFormat::Cff, &[0, 3]).unwrap();
+        let subset = cff_table(&subset, ProgramFormat::Cff).unwrap();
+        assert_eq!(outline(&subset, 3), outline(&original, 3));
+        assert_eq!(outline(&subset, 1), outline(&original, 1));
+    }
+
+    #[test]
+    fn cff_simple_fonts_map_codes_by_glyph_name() {
+        let mut doc = Document::with_version("1.5");
+        let program = doc.add_object(Stream::new(dictionary! { "Subtype" => "Type1C" }, cff_program()));
+        let descriptor = doc.add_object(dictionary! { "Type" => "FontDescriptor", "FontFile3" => program });
+        let encoding = dictionary! { "BaseEncoding" => "WinAnsiEncoding", "Differences" => vec![0x80.into(), "D".into()] };
+        let font = dictionary! { "Type" => "Font", "Subtype" => "Type1", "FontDescriptor" => descriptor, "Encoding" => encoding };
+        let plan = plan_font(&doc, &font, &BTreeSet::from([b'A' as u16, 0x80])).unwrap();
+        assert_eq!(plan.program_id, program);
+        // 0x80 is unassigned in the built-in StandardEncoding, which shows .notdef
+        assert_eq!(plan.gids, BTreeSet::from([0, 1, 4]));
+    }
+
+    #[test]
+    fn cid_font_type0_fonts_are_planned() {
+        let mut doc = Document::with_version("1.5");
+        let program = doc.add_object(Stream::new(dictionary! { "Subtype" => "CIDFontType0C" }, cff_program()));
+        let descriptor = doc.add_object(dictionary! { "Type" => "FontDescriptor", "FontFile3" => program });
+        let cid_font = doc.add_object(dictionary! { "Type" => "Font", "Subtype" => "CIDFontType0", "FontDescriptor" => descriptor });
+        let font = dictionary! { "Type" => "Font", "Subtype" => "Type0", "Encoding" => "Identity-H", "DescendantFonts" => vec![cid_font.into()] };
+        // The program is not CID-keyed, so CIDs are glyph IDs
+        let plan = plan_font(&doc, &font, &BTreeSet::from([2, 4])).unwrap();
+        assert_eq!(plan.gids, BTreeSet::from([2, 4]));
+    }
+
+    #[test]
+    fn type1_programs_are_subset() {
+        let (data, length1, length2) = super::super::type1::tests::program();
+        let mut doc = Document::with_version("1.5");
+        let lengths = dictionary! { "Length1" => length1 as i64, "Length2" => length2 as i64, "Length3" => (data.len() - length1 - length2) as i64 };
+        let program = doc.add_object(Stream::new(lengths, data));
+        let descriptor = doc.add_object(dictionary! { "Type" => "FontDescriptor", "FontName" => "Test", "FontFile" => program });
+        let font = dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Test", "FontDescriptor" => descriptor };
+
+        // Without an /Encoding the program's StandardEncoding applies
+        let plan = plan_font(&doc, &font, &BTreeSet::from([b'B' as u16])).unwrap();
+        assert_eq!(plan.program_id, program);
+        assert_eq!(plan.gids, BTreeSet::from([2]));
+
+        assert!(subset_program(&mut doc, program, &plan.gids).unwrap() > 0);
+        let stream = doc.get_object(program).unwrap().as_stream().unwrap();
+        let subset = stream.decompressed_content().unwrap();
+        let length = |key: &[u8]| stream.dict.get(key).unwrap().as_i64().unwrap() as usize;
+        assert_eq!(length(b"Length1") + length(b"Length2") + length(b"Length3"), subset.len());
+        let subset = Type1Program::parse(&subset, length(b"Length1"), length(b"Length2")).unwrap();
+        assert_eq!(subset.glyph_index_by_name("B"), Some(1));
+        assert_eq!(subset.glyph_index_by_name("D"), None);
+    }
+
+    #[test]
+    fn shared_descendant_keeps_widths_of_every_font() {
+        let mut doc = Document::with_version("1.5");
+        let program = doc.add_object(Stream::new(dictionary! { "Subtype" => "CIDFontType0C" }, cff_program()));
+        let descriptor = doc.add_object(dictionary! { "Type" => "FontDescriptor", "FontName" => "Test", "FontFile3" => program });
+        let widths: Vec<Object> = vec![500.into(), 600.into(), 700.into(), 800.into(), 900.into()];
+        let cid_font = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "CIDFontType0",
+            "BaseFont" => "Test",
+            "FontDescriptor" => descriptor,
+            "W" => vec![0.into(), widths.into()],
+        });
+        let type0 = || dictionary! { "Type" => "Font", "Subtype" => "Type0", "BaseFont" => "Test", "Encoding" => "Identity-H", "DescendantFonts" => vec![cid_font.into()] };
+        let f1 = doc.add_object(type0());
+        let f2 = doc.add_object(type0());
+        let content = doc.add_object(Stream::new(Dictionary::new(), b"BT /F1 12 Tf <0001> Tj /F2 12 Tf <0004> Tj ET".to_vec()));
+        let pages_id = doc.new_object_id();
+        let page = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content,
+            "Resources" => dictionary! { "Font" => dictionary! { "F1" => f1, "F2" => f2 } },
+        });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+
+        let stats = subset_fonts(&mut doc);
+        assert_eq!(stats.fonts_subset, 1);
+        let w = doc.get_dictionary(cid_font).unwrap().get(b"W").unwrap().as_array().unwrap();
+        let widths = parse_cid_widths(w);
+        assert_eq!(widths.keys().copied().collect::<Vec<_>>(), [1, 4]);
+        assert_eq!(widths[&1].as_i64().unwrap(), 600);
+        assert_eq!(widths[&4].as_i64().unwrap(), 900);
+    }
+
+    #[test]
+    fn page_text_is_recorded() {
+        let (doc, f1, _) = document("BT /F1 12 Tf (AB) Tj [(C) 50 (A)] TJ ET", "", false);
+        let usage = collect_usage(&doc);
+        assert_eq!(codes(&usage, f1), [b'A' as u16, b'B' as u16, b'C' as u16]);
+        assert!(usage.unknown.is_empty());
+    }
+
+    #[test]
+    fn forms_without_resources_inherit_the_font() {
+        let (doc, f1, _) = document("BT /F1 12 Tf ET /X1 Do", "BT (Z) Tj ET", false);
+        let usage = collect_usage(&doc);
+        assert_eq!(codes(&usage, f1), [b'Z' as u16]);
+        assert!(usage.unknown.is_empty());
+    }
+
+    #[test]
+    fn forms_with_resources_inherit_the_font_until_they_select_one() {
+        let (doc, f1, f2) = document("BT /F1 12 Tf ET /X1 Do", "BT (Y) Tj /F2 10 Tf (X) Tj ET", true);
+        let usage = collect_usage(&doc);
+        assert_eq!(codes(&usage, f1), [b'Y' as u16]);
+        assert_eq!(codes(&usage, f2), [b'X' as u16]);
+    }
+
+    #[test]
+    fn text_without_a_font_marks_the_resources_unknown() {
+        let (doc, f1, _) = document("/X1 Do", "BT (Z) Tj ET", false);
+        let usage = collect_usage(&doc);
+        assert!(usage.unknown.contains(&f1));
+    }
+}
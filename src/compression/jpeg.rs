@@ -0,0 +1,431 @@
+//! JPEG helpers for the cases the `image` crate does not cover: reading
+//! four-component (CMYK/YCCK) JPEGs without a forced RGB conversion, and
+//! writing baseline JPEGs with an arbitrary number of components.
+
+use zune_core::bytestream::ZCursor;
+use zune_core::colorspace::ColorSpace;
+use zune_core::options::DecoderOptions;
+
+// Natural-order index of each zig-zag position (ITU T.81 figure A.6)
+#[rustfmt::skip]
+const ZIGZAG: [usize; 64] = [
+     0,  1,  8, 16,  9,  2,  3, 10,
+    17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// Table K.1. Only the luminance table is used: the encoder applies no colour
+// transform, so every component carries full-detail samples (gray, R, G, B or C, M,
+// Y, K) rather than the chroma the Table K.2 quantisation is tuned for.
+#[rustfmt::skip]
+const STD_LUMA_QTABLE: [u16; 64] = [
+    16, 11, 10, 16,  24,  40,  51,  61,
+    12, 12, 14, 19,  26,  58,  60,  55,
+    14, 13, 16, 24,  40,  57,  69,  56,
+    14, 17, 22, 29,  51,  87,  80,  62,
+    18, 22, 37, 56,  68, 109, 103,  77,
+    24, 35, 55, 64,  81, 104, 113,  92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103,  99,
+];
+
+// Table K.3
+const STD_DC_CODE_LENGTHS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const STD_DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+// Table K.5
+const STD_AC_CODE_LENGTHS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D];
+const STD_AC_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+    0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5,
+    0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2,
+    0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+/// Iterate over the marker segments of a JPEG header, stopping at the first scan.
+fn segments(data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut pos = 2; // skip SOI
+    std::iter::from_fn(move || {
+        while pos + 4 <= data.len() {
+            if data[pos] != 0xFF {
+                return None;
+            }
+            let marker = data[pos + 1];
+            if marker == 0xFF {
+                pos += 1;
+                continue;
+            }
+            if marker == 0xDA || marker == 0xD9 {
+                return None;
+            }
+            let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+            let body = data.get(pos + 4..pos + 2 + len)?;
+            pos += 2 + len;
+            return Some((marker, body));
+        }
+        None
+    })
+}
+
+/// Number of color components declared in the frame header.
+pub(crate) fn component_count(data: &[u8]) -> Option<u8> {
+    segments(data)
+        .find(|(marker, _)| matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC))
+        .and_then(|(_, body)| body.get(5).copied())
+}
+
+/// Whether the JPEG carries an Adobe APP14 segment. Adobe applications
+/// write four-component JPEGs with inverted samples when this marker is present.
+pub(crate) fn has_adobe_marker(data: &[u8]) -> bool {
+    segments(data).any(|(marker, body)| marker == 0xEE && body.starts_with(b"Adobe"))
+}
+
+/// Decode a four-component JPEG to interleaved samples exactly as stored
+/// (YCCK is converted back to CMYK, but no inversion is applied).
+pub(crate) fn decode_four_component(data: &[u8]) -> Result<(u32, u32, Vec<u8>), Box<dyn std::error::Error + Send + Sync>> {
+    let options = DecoderOptions::default()
+        .set_strict_mode(false)
+        .set_max_width(usize::MAX)
+        .set_max_height(usize::MAX);
+    let mut decoder = zune_jpeg::JpegDecoder::new_with_options(ZCursor::new(data), options);
+    decoder.decode_headers().map_err(|e| format!("Failed to read JPEG header: {:?}", e))?;
+    let input = decoder.input_colorspace().ok_or("JPEG has no colorspace")?;
+    if input.num_components() != 4 {
+        return Err(format!("Expected a four component JPEG, found {:?}", input).into());
+    }
+    let (width, height) = decoder.dimensions().ok_or("JPEG has no dimensions")?;
+
+    // Asking for the input colorspace makes zune copy the raw component samples.
+    decoder.set_options(decoder.options().jpeg_set_out_colorspace(input));
+    let mut pixels = decoder.decode().map_err(|e| format!("Failed to decode JPEG: {:?}", e))?;
+
+    if input == ColorSpace::YCCK {
+        for px in pixels.chunks_exact_mut(4) {
+            let (y, cb, cr) = (px[0] as f32, px[1] as f32 - 128.0, px[2] as f32 - 128.0);
+            px[0] = (y + 1.402 * cr).round().clamp(0.0, 255.0) as u8;
+            px[1] = (y - 0.344_136 * cb - 0.714_136 * cr).round().clamp(0.0, 255.0) as u8;
+            px[2] = (y + 1.772 * cb).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+
+    Ok((width as u32, height as u32, pixels))
+}
+
+fn scaled_qtable(quality: u8) -> [u16; 64] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 { 5000 / quality } else { 200 - quality * 2 };
+    let mut table = [0u16; 64];
+    for (dst, &base) in table.iter_mut().zip(STD_LUMA_QTABLE.iter()) {
+        *dst = ((base as u32 * scale + 50) / 100).clamp(1, 255) as u16;
+    }
+    table
+}
+
+/// Code and length for every symbol, built as described in ITU T.81 annex C.
+fn huffman_codes(lengths: &[u8; 16], values: &[u8]) -> [(u16, u8); 256] {
+    let mut table = [(0u16, 0u8); 256];
+    let mut code = 0u16;
+    let mut k = 0;
+    for (i, &count) in lengths.iter().enumerate() {
+        for _ in 0..count {
+            table[values[k] as usize] = (code, i as u8 + 1);
+            code += 1;
+            k += 1;
+        }
+        code <<= 1;
+    }
+    table
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, len: u8) {
+        if len == 0 {
+            return;
+        }
+        let len = len as u32;
+        self.acc = (self.acc << len) | (code as u32 & ((1 << len) - 1));
+        self.bits += len;
+        while self.bits >= 8 {
+            let byte = (self.acc >> (self.bits - 8)) as u8;
+            self.out.push(byte);
+            if byte == 0xFF {
+                self.out.push(0x00);
+            }
+            self.bits -= 8;
+        }
+        self.acc &= (1 << self.bits) - 1;
+    }
+
+    fn flush(&mut self) {
+        if self.bits > 0 {
+            let pad = 8 - self.bits as u8;
+            self.write((1 << pad) - 1, pad);
+        }
+    }
+}
+
+/// Magnitude category and the additional bits for a coefficient.
+fn magnitude(value: i32) -> (u8, u16) {
+    let size = 32 - value.unsigned_abs().leading_zeros();
+    let bits = if value < 0 { value - 1 } else { value };
+    (size as u8, (bits as u32 & ((1u32 << size) - 1)) as u16)
+}
+
+fn fdct(block: &[f32; 64], basis: &[[f32; 8]; 8]) -> [f32; 64] {
+    let mut tmp = [0f32; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            tmp[y * 8 + u] = (0..8).map(|x| basis[u][x] * block[y * 8 + x]).sum();
+        }
+    }
+    let mut out = [0f32; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            out[v * 8 + u] = (0..8).map(|y| basis[v][y] * tmp[y * 8 + u]).sum();
+        }
+    }
+    out
+}
+
+fn write_segment(out: &mut Vec<u8>, marker: u8, body: &[u8]) {
+    out.extend_from_slice(&[0xFF, marker]);
+    out.extend_from_slice(&((body.len() + 2) as u16).to_be_bytes());
+    out.extend_from_slice(body);
+}
+
+/// Encode interleaved 8-bit samples as a baseline JPEG.
+///
+/// All components share the standard luminance tables, use 1x1 sampling and
+/// are written without any color transform or Adobe marker, so a PDF reader
+/// sees the samples exactly as given (plain, non-inverted CMYK for 4 components).
+/// Readers assume YCbCr for 3 components, so RGB output needs `/ColorTransform 0`
+/// in its DecodeParms.
+pub(crate) fn encode(pixels: &[u8], width: u32, height: u32, components: usize, quality: u8) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    if !(1..=4).contains(&components) {
+        return Err(format!("Cannot encode {} components as JPEG", components).into());
+    }
+    if width == 0 || height == 0 || width > 65535 || height > 65535 {
+        return Err(format!("Invalid JPEG dimensions {}x{}", width, height).into());
+    }
+    let (w, h) = (width as usize, height as usize);
+    if pixels.len() != w * h * components {
+        return Err("Pixel buffer does not match image dimensions".into());
+    }
+
+    let qtable = scaled_qtable(quality);
+    let dc_codes = huffman_codes(&STD_DC_CODE_LENGTHS, &STD_DC_VALUES);
+    let ac_codes = huffman_codes(&STD_AC_CODE_LENGTHS, &STD_AC_VALUES);
+
+    let mut basis = [[0f32; 8]; 8];
+    for (u, row) in basis.iter_mut().enumerate() {
+        let alpha = if u == 0 { (1.0f32 / 8.0).sqrt() } else { (2.0f32 / 8.0).sqrt() };
+        for (x, value) in row.iter_mut().enumerate() {
+            *value = alpha * (((2 * x + 1) * u) as f32 * std::f32::consts::PI / 16.0).cos();
+        }
+    }
+
+    let mut out = Vec::with_capacity(pixels.len() / 8);
+    out.extend_from_slice(&[0xFF, 0xD8]);
+
+    let mut dqt = vec![0u8];
+    dqt.extend(ZIGZAG.iter().map(|&i| qtable[i] as u8));
+    write_segment(&mut out, 0xDB, &dqt);
+
+    let mut sof = vec![8];
+    sof.extend_from_slice(&(height as u16).to_be_bytes());
+    sof.extend_from_slice(&(width as u16).to_be_bytes());
+    sof.push(components as u8);
+    for c in 0..components {
+        sof.extend_from_slice(&[c as u8 + 1, 0x11, 0]);
+    }
+    write_segment(&mut out, 0xC0, &sof);
+
+    let mut dht = vec![0x00];
+    dht.extend_from_slice(&STD_DC_CODE_LENGTHS);
+    dht.extend_from_slice(&STD_DC_VALUES);
+    dht.push(0x10);
+    dht.extend_from_slice(&STD_AC_CODE_LENGTHS);
+    dht.extend_from_slice(&STD_AC_VALUES);
+    write_segment(&mut out, 0xC4, &dht);
+
+    let mut sos = vec![components as u8];
+    for c in 0..components {
+        sos.extend_from_slice(&[c as u8 + 1, 0x00]);
+    }
+    sos.extend_from_slice(&[0, 63, 0]);
+    write_segment(&mut out, 0xDA, &sos);
+
+    let mut writer = BitWriter { out, acc: 0, bits: 0 };
+    let mut dc_pred = vec![0i32; components];
+    let mut block = [0f32; 64];
+    for by in (0..h).step_by(8) {
+        for bx in (0..w).step_by(8) {
+            for (c, pred) in dc_pred.iter_mut().enumerate() {
+                // Edge blocks repeat the last row/column.
+                for y in 0..8 {
+                    let row = (by + y).min(h - 1) * w;
+                    for x in 0..8 {
+                        let col = (bx + x).min(w - 1);
+                        block[y * 8 + x] = pixels[(row + col) * components + c] as f32 - 128.0;
+                    }
+                }
+                let coeffs = fdct(&block, &basis);
+                let mut quant = [0i32; 64];
+                for (k, &natural) in ZIGZAG.iter().enumerate() {
+                    quant[k] = (coeffs[natural] / qtable[natural] as f32).round() as i32;
+                }
+
+                let (size, bits) = magnitude(quant[0] - *pred);
+                *pred = quant[0];
+                let (code, len) = dc_codes[size as usize];
+                writer.write(code, len);
+                writer.write(bits, size);
+
+                let mut run = 0;
+                for &coef in &quant[1..] {
+                    if coef == 0 {
+                        run += 1;
+                        continue;
+                    }
+                    while run > 15 {
+                        let (code, len) = ac_codes[0xF0];
+                        writer.write(code, len);
+                        run -= 16;
+                    }
+                    let (size, bits) = magnitude(coef);
+                    let (code, len) = ac_codes[(run << 4) | size as usize];
+                    writer.write(code, len);
+                    writer.write(bits, size);
+                    run = 0;
+                }
+                if run > 0 {
+                    let (code, len) = ac_codes[0x00];
+                    writer.write(code, len);
+                }
+            }
+        }
+    }
+    writer.flush();
+
+    let mut out = writer.out;
+    out.extend_from_slice(&[0xFF, 0xD9]);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A gradient with a few sharp edges, sized so the last blocks are partial
+    fn test_image(width: usize, height: usize, components: usize) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(width * height * components);
+        for y in 0..height {
+            for x in 0..width {
+                for c in 0..components {
+                    let value = (x * 255 / width + y * 64 / height + c * 40) % 256;
+                    pixels.push(if (x / 6 + c) % 3 == 0 { value as u8 / 2 } else { value as u8 });
+                }
+            }
+        }
+        pixels
+    }
+
+    /// Decode to the samples as stored, in the JPEG's own colorspace
+    fn decode(data: &[u8]) -> (ColorSpace, usize, usize, Vec<u8>) {
+        let mut decoder = zune_jpeg::JpegDecoder::new(ZCursor::new(data));
+        decoder.decode_headers().unwrap();
+        let input = decoder.input_colorspace().unwrap();
+        decoder.set_options(decoder.options().jpeg_set_out_colorspace(input));
+        let (width, height) = decoder.dimensions().unwrap();
+        (input, width, height, decoder.decode().unwrap())
+    }
+
+    fn assert_close(decoded: &[u8], original: &[u8]) {
+        assert_eq!(decoded.len(), original.len());
+        let errors: Vec<u32> = decoded.iter().zip(original).map(|(&a, &b)| a.abs_diff(b) as u32).collect();
+        let mean = errors.iter().sum::<u32>() as f32 / errors.len() as f32;
+        let max = errors.iter().max().copied().unwrap_or(0);
+        assert!(mean < 3.0 && max < 40, "mean error {}, max error {}", mean, max);
+    }
+
+    #[test]
+    fn gray_round_trip() {
+        let pixels = test_image(21, 13, 1);
+        let data = encode(&pixels, 21, 13, 1, 90).unwrap();
+        assert_eq!(component_count(&data), Some(1));
+        let (colorspace, width, height, decoded) = decode(&data);
+        assert_eq!((colorspace, width, height), (ColorSpace::Luma, 21, 13));
+        assert_close(&decoded, &pixels);
+    }
+
+    #[test]
+    fn rgb_round_trip() {
+        let pixels = test_image(19, 17, 3);
+        let data = encode(&pixels, 19, 17, 3, 90).unwrap();
+        assert!(!has_adobe_marker(&data));
+        // Without a marker decoders take the samples for YCbCr, which is how they
+        // are read back untransformed
+        let (colorspace, width, height, decoded) = decode(&data);
+        assert_eq!((colorspace, width, height), (ColorSpace::YCbCr, 19, 17));
+        assert_close(&decoded, &pixels);
+    }
+
+    #[test]
+    fn cmyk_round_trip() {
+        let pixels = test_image(20, 9, 4);
+        let data = encode(&pixels, 20, 9, 4, 90).unwrap();
+        assert_eq!(component_count(&data), Some(4));
+        assert!(!has_adobe_marker(&data));
+        let (width, height, decoded) = decode_four_component(&data).unwrap();
+        assert_eq!((width, height), (20, 9));
+        assert_close(&decoded, &pixels);
+    }
+
+    #[test]
+    fn adobe_cmyk_is_read_as_stored() {
+        let pixels = test_image(16, 16, 4);
+        let data = encode(&pixels, 16, 16, 4, 90).unwrap();
+        // APP14 with transform 0 right after SOI, as Adobe applications write it
+        let mut adobe = data[..2].to_vec();
+        write_segment(&mut adobe, 0xEE, &[b'A', b'd', b'o', b'b', b'e', 0, 100, 0, 0, 0, 0, 0]);
+        adobe.extend_from_slice(&data[2..]);
+
+        assert!(has_adobe_marker(&adobe));
+        assert_eq!(component_count(&adobe), Some(4));
+        let (_, _, decoded) = decode_four_component(&adobe).unwrap();
+        assert_close(&decoded, &pixels);
+    }
+
+    #[test]
+    fn lower_quality_is_smaller() {
+        let pixels = test_image(64, 64, 1);
+        let high = encode(&pixels, 64, 64, 1, 90).unwrap();
+        let low = encode(&pixels, 64, 64, 1, 20).unwrap();
+        assert!(low.len() < high.len());
+    }
+
+    #[test]
+    fn invalid_input_is_rejected() {
+        assert!(encode(&[0; 10], 2, 2, 1, 50).is_err());
+        assert!(encode(&[0; 20], 2, 2, 5, 50).is_err());
+        assert!(encode(&[], 0, 2, 1, 50).is_err());
+    }
+}
@@ -0,0 +1,363 @@
+//! Document metadata: the trailer's Info dictionary and XMP packets are stripped,
+//! kept as they are, or rebuilt as a small Info dictionary with a matching XMP
+//! packet.
+//!
+//! XMP is removed wherever it is attached (pages, images, fonts), and every
+//! `/Metadata` entry pointing at a removed packet goes with it, so no reference
+//! is left dangling.
+
+use super::{CompressionConfig, MetadataMode};
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream, StringFormat};
+use std::collections::BTreeSet;
+
+/// Info keys kept when rewriting: the ones readers show, and their XMP equivalents
+const DESCRIPTIVE_KEYS: [&str; 8] = ["Title", "Author", "Subject", "Keywords", "Creator", "Producer", "CreationDate", "ModDate"];
+
+/// Characters PDFDocEncoding puts in 0x80-0xA0, where it differs from Latin-1
+/// (0x9F is undefined)
+const PDF_DOC_HIGH: [char; 33] = [
+    '\u{2022}', '\u{2020}', '\u{2021}', '\u{2026}', '\u{2014}', '\u{2013}', '\u{0192}', '\u{2044}', '\u{2039}', '\u{203A}',
+    '\u{2212}', '\u{2030}', '\u{201E}', '\u{201C}', '\u{201D}', '\u{2018}', '\u{2019}', '\u{201A}', '\u{2122}', '\u{FB01}',
+    '\u{FB02}', '\u{0141}', '\u{0152}', '\u{0160}', '\u{0178}', '\u{017D}', '\u{0131}', '\u{0142}', '\u{0153}', '\u{0161}',
+    '\u{017E}', '\u{FFFD}', '\u{20AC}',
+];
+
+/// Apply the configured metadata mode. Returns whether the document changed.
+pub(crate) fn process_metadata(doc: &mut Document, config: &CompressionConfig) -> bool {
+    match config.metadata {
+        MetadataMode::Preserve => false,
+        MetadataMode::Strip => {
+            let removed = remove_xmp(doc);
+            let keep: Vec<&str> = config.keep_info_keys.iter().map(String::as_str).collect();
+            let info = kept_info(doc, &keep);
+            set_info(doc, info) || removed
+        }
+        MetadataMode::Rewrite => {
+            let removed = remove_xmp(doc);
+            let keep: Vec<&str> = DESCRIPTIVE_KEYS.iter().copied().chain(config.keep_info_keys.iter().map(String::as_str)).collect();
+            let mut info = kept_info(doc, &keep);
+            for (key, value) in [("Title", &config.title), ("Author", &config.author), ("Producer", &config.producer)] {
+                if let Some(value) = value {
+                    info.set(key, text_string(value));
+                }
+            }
+            let added = !info.is_empty();
+            if added {
+                let packet = xmp_packet(&info);
+                let mut dict = Dictionary::new();
+                dict.set("Type", Object::Name(b"Metadata".to_vec()));
+                dict.set("Subtype", Object::Name(b"XML".to_vec()));
+                // Left uncompressed so tools that scan for XMP packets can find it
+                let metadata_id = doc.add_object(Stream::new(dict, packet.into_bytes()).with_compression(false));
+                if let Ok(catalog) = doc.catalog_mut() {
+                    catalog.set("Metadata", Object::Reference(metadata_id));
+                }
+            }
+            set_info(doc, info) || removed || added
+        }
+    }
+}
+
+/// Remove every XMP packet and the `/Metadata` entries that point to them.
+/// Returns whether there was anything to remove.
+fn remove_xmp(doc: &mut Document) -> bool {
+    let is_packet = |object: &Object| {
+        object.as_stream().is_ok_and(|s| matches!(s.dict.get(b"Type").and_then(Object::as_name), Ok(b"Metadata")))
+    };
+    let mut packets: BTreeSet<ObjectId> = doc.objects.iter().filter(|(_, o)| is_packet(o)).map(|(id, _)| *id).collect();
+    let mut removed = !packets.is_empty();
+    // Packets without a /Type are only known by where they are referenced from
+    for object in doc.objects.values_mut() {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => &mut stream.dict,
+            _ => continue,
+        };
+        if let Some(metadata) = dict.remove(b"Metadata") {
+            removed = true;
+            if let Object::Reference(id) = metadata {
+                packets.insert(id);
+            }
+        }
+    }
+    for id in &packets {
+        doc.objects.remove(id);
+    }
+    removed
+}
+
+/// The entries of the Info dictionary listed in `keep`
+fn kept_info(doc: &Document, keep: &[&str]) -> Dictionary {
+    let mut kept = Dictionary::new();
+    let Some(info) = doc.trailer.get(b"Info").ok().and_then(|i| doc.dereference(i).ok()).and_then(|(_, i)| i.as_dict().ok()) else {
+        return kept;
+    };
+    for (key, value) in info.iter() {
+        if keep.iter().any(|k| k.as_bytes() == key.as_slice()) {
+            // Values may be indirect strings
+            let value = doc.dereference(value).map_or_else(|_| value.clone(), |(_, v)| v.clone());
+            kept.set(key.clone(), value);
+        }
+    }
+    kept
+}
+
+/// Replace the Info dictionary, or drop it when nothing is left. Returns whether
+/// it changed.
+fn set_info(doc: &mut Document, info: Dictionary) -> bool {
+    let old = doc.trailer.get(b"Info").ok().and_then(|i| doc.dereference(i).ok()).and_then(|(_, i)| i.as_dict().ok());
+    let changed = old != Some(&info) && !(old.is_none() && info.is_empty());
+    let info_id = doc.trailer.get(b"Info").and_then(Object::as_reference).ok();
+    if info.is_empty() {
+        doc.trailer.remove(b"Info");
+        return changed;
+    }
+    match info_id {
+        Some(id) if doc.objects.contains_key(&id) => {
+            doc.objects.insert(id, Object::Dictionary(info));
+        }
+        _ => {
+            let id = doc.add_object(info);
+            doc.trailer.set("Info", Object::Reference(id));
+        }
+    }
+    changed
+}
+
+/// A PDF text string: PDFDocEncoding for plain ASCII, UTF-16BE otherwise
+fn text_string(text: &str) -> Object {
+    if text.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        return Object::String(text.as_bytes().to_vec(), StringFormat::Literal);
+    }
+    let mut bytes = vec![0xFE, 0xFF];
+    bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+    Object::String(bytes, StringFormat::Hexadecimal)
+}
+
+fn decode_text_string(bytes: &[u8]) -> String {
+    if let Some(utf16) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        let units: Vec<u16> = utf16.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+        return String::from_utf16_lossy(&units);
+    }
+    if let Some(utf8) = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        return String::from_utf8_lossy(utf8).into_owned();
+    }
+    bytes
+        .iter()
+        .map(|&b| match b {
+            0x80..=0xA0 => PDF_DOC_HIGH[(b - 0x80) as usize],
+            _ => b as char,
+        })
+        .collect()
+}
+
+/// XMP packet carrying the same values as `info`
+fn xmp_packet(info: &Dictionary) -> String {
+    let text = |key: &[u8]| match info.get(key) {
+        Ok(Object::String(bytes, _)) => Some(xml_escape(&decode_text_string(bytes))),
+        _ => None,
+    };
+    let mut properties = String::from("<dc:format>application/pdf</dc:format>\n");
+    if let Some(title) = text(b"Title") {
+        properties.push_str(&format!("<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>\n", title));
+    }
+    if let Some(author) = text(b"Author") {
+        properties.push_str(&format!("<dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>\n", author));
+    }
+    if let Some(subject) = text(b"Subject") {
+        properties.push_str(&format!(
+            "<dc:description><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:description>\n",
+            subject
+        ));
+    }
+    if let Some(keywords) = text(b"Keywords") {
+        properties.push_str(&format!("<pdf:Keywords>{}</pdf:Keywords>\n", keywords));
+    }
+    if let Some(producer) = text(b"Producer") {
+        properties.push_str(&format!("<pdf:Producer>{}</pdf:Producer>\n", producer));
+    }
+    if let Some(creator) = text(b"Creator") {
+        properties.push_str(&format!("<xmp:CreatorTool>{}</xmp:CreatorTool>\n", creator));
+    }
+    for (key, property) in [(b"CreationDate".as_slice(), "xmp:CreateDate"), (b"ModDate", "xmp:ModifyDate")] {
+        if let Some(date) = text(key).as_deref().and_then(xmp_date) {
+            properties.push_str(&format!("<{0}>{1}</{0}>\n", property, date));
+        }
+    }
+
+    format!(
+        "<?xpacket begin=\"\u{FEFF}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+         <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n\
+         <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n\
+         <rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+         xmlns:pdf=\"http://ns.adobe.com/pdf/1.3/\" xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\">\n\
+         {}</rdf:Description>\n\
+         </rdf:RDF>\n\
+         </x:xmpmeta>\n\
+         <?xpacket end=\"w\"?>",
+        properties
+    )
+}
+
+/// Escape markup characters and drop control characters, which XML does not allow
+fn xml_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if c.is_control() => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Convert a PDF date (`D:YYYYMMDDHHmmSSOHH'mm'`, every part after the year
+/// optional) to the ISO 8601 form XMP uses
+fn xmp_date(date: &str) -> Option<String> {
+    let date = date.strip_prefix("D:").unwrap_or(date);
+    let digits: String = date.chars().take_while(char::is_ascii_digit).collect();
+    if digits.len() < 4 {
+        return None;
+    }
+    let part = |start: usize| digits.get(start..start + 2);
+    let mut out = digits[..4].to_string();
+    for (start, separator) in [(4, "-"), (6, "-")] {
+        match part(start) {
+            Some(value) => out.push_str(&format!("{}{}", separator, value)),
+            None => return Some(out),
+        }
+    }
+    let Some(hour) = part(8) else { return Some(out) };
+    let minute = part(10).unwrap_or("00");
+    out.push_str(&format!("T{}:{}", hour, minute));
+    if let Some(second) = part(12) {
+        out.push_str(&format!(":{}", second));
+    }
+
+    // Time zone: Z, or +/- with hours and optional minutes
+    let zone: Vec<char> = date[digits.len()..].chars().filter(|c| *c != '\'').collect();
+    match zone.first() {
+        Some('Z') => out.push('Z'),
+        Some(sign @ ('+' | '-')) => {
+            let offset: String = zone[1..].iter().take_while(|c| c.is_ascii_digit()).collect();
+            let hours = offset.get(..2)?;
+            let minutes = offset.get(2..4).unwrap_or("00");
+            out.push_str(&format!("{}{}:{}", sign, hours, minutes));
+        }
+        _ => {}
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    /// A one page document with `info` and XMP packets on the catalog (typed) and
+    /// the page (untyped)
+    fn document(info: Dictionary) -> Document {
+        let mut doc = Document::with_version("1.5");
+        let catalog_xmp = doc.add_object(Stream::new(dictionary! { "Type" => "Metadata", "Subtype" => "XML" }, b"<x:xmpmeta/>".to_vec()));
+        let page_xmp = doc.add_object(Stream::new(Dictionary::new(), b"<x:xmpmeta/>".to_vec()));
+        let pages_id = doc.new_object_id();
+        let page = doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id, "Metadata" => page_xmp });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id, "Metadata" => catalog_xmp });
+        doc.trailer.set("Root", catalog);
+        let info = doc.add_object(info);
+        doc.trailer.set("Info", info);
+        doc
+    }
+
+    fn info(doc: &Document) -> &Dictionary {
+        let (_, info) = doc.dereference(doc.trailer.get(b"Info").unwrap()).unwrap();
+        info.as_dict().unwrap()
+    }
+
+    fn has_metadata_entry(object: &Object) -> bool {
+        match object {
+            Object::Dictionary(dict) => dict.has(b"Metadata"),
+            Object::Stream(stream) => stream.dict.has(b"Metadata"),
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn strip_leaves_no_dangling_metadata() {
+        let mut doc = document(dictionary! { "Title" => Object::string_literal("Report"), "Author" => Object::string_literal("Ann") });
+        let config = CompressionConfig { metadata: MetadataMode::Strip, keep_info_keys: vec!["Title".to_string()], ..CompressionConfig::default() };
+
+        assert!(process_metadata(&mut doc, &config));
+
+        assert!(!doc.objects.values().any(has_metadata_entry));
+        assert!(!doc.catalog().unwrap().has(b"Metadata"));
+        assert!(!doc.objects.values().any(|o| o.as_stream().is_ok_and(|s| s.content.starts_with(b"<x:xmpmeta"))));
+        let info = info(&doc);
+        assert_eq!(info.get(b"Title").unwrap().as_str().unwrap(), b"Report");
+        assert!(!info.has(b"Author"));
+
+        // A second run has nothing left to do
+        assert!(!process_metadata(&mut doc, &config));
+    }
+
+    #[test]
+    fn rewrite_writes_info_and_xmp_that_agree() {
+        let mut doc = document(dictionary! {
+            "Title" => Object::string_literal("Old title"),
+            "Author" => Object::string_literal("Ann & Bob"),
+            "CreationDate" => Object::string_literal("D:20240131235959+01'00'"),
+            "ModDate" => Object::string_literal("D:202402"),
+            "Trapped" => "False",
+        });
+        let config = CompressionConfig {
+            metadata: MetadataMode::Rewrite,
+            title: Some("Annual report \u{2013} 2024".to_string()),
+            producer: Some("rustpdf".to_string()),
+            ..CompressionConfig::default()
+        };
+
+        assert!(process_metadata(&mut doc, &config));
+
+        let info = info(&doc);
+        assert_eq!(decode_text_string(info.get(b"Title").unwrap().as_str().unwrap()), "Annual report \u{2013} 2024");
+        assert_eq!(info.get(b"Author").unwrap().as_str().unwrap(), b"Ann & Bob");
+        assert_eq!(info.get(b"Producer").unwrap().as_str().unwrap(), b"rustpdf");
+        assert!(!info.has(b"Trapped"));
+
+        // Only the new packet is left, on the catalog
+        assert!(!doc.get_dictionary(doc.page_iter().next().unwrap()).unwrap().has(b"Metadata"));
+        let packet_id = doc.catalog().unwrap().get(b"Metadata").and_then(Object::as_reference).unwrap();
+        let packet = String::from_utf8(doc.get_object(packet_id).and_then(Object::as_stream).unwrap().content.clone()).unwrap();
+        assert!(packet.contains("<rdf:li xml:lang=\"x-default\">Annual report \u{2013} 2024</rdf:li>"));
+        assert!(packet.contains("<rdf:li>Ann &amp; Bob</rdf:li>"));
+        assert!(packet.contains("<pdf:Producer>rustpdf</pdf:Producer>"));
+        assert!(packet.contains("<xmp:CreateDate>2024-01-31T23:59:59+01:00</xmp:CreateDate>"));
+        assert!(packet.contains("<xmp:ModifyDate>2024-02</xmp:ModifyDate>"));
+        assert!(!packet.contains("Trapped"));
+    }
+
+    #[test]
+    fn pdf_dates_convert_to_xmp_dates() {
+        let cases = [
+            ("D:2024", Some("2024")),
+            ("D:20240131", Some("2024-01-31")),
+            ("D:2024013115", Some("2024-01-31T15:00")),
+            ("D:202401311530Z", Some("2024-01-31T15:30Z")),
+            ("D:20240131153045-05'30'", Some("2024-01-31T15:30:45-05:30")),
+            ("D:20240131153045+01", Some("2024-01-31T15:30:45+01:00")),
+            ("20240131153045Z00'00'", Some("2024-01-31T15:30:45Z")),
+            ("D:20240131153045+1", None),
+            ("D:24", None),
+            ("yesterday", None),
+        ];
+        for (date, expected) in cases {
+            assert_eq!(xmp_date(date).as_deref(), expected, "{}", date);
+        }
+    }
+}
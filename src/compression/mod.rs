@@ -1,7 +1,153 @@
-use lopdf::{Document, Object, Stream};
+use lopdf::{Document, Object, ObjectId, Stream};
 use image::ImageReader;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::Cursor;
 use image::DynamicImage;
+use rayon::prelude::*;
+use tracing::field;
+
+mod ccitt;
+mod cleanup;
+mod color;
+mod content;
+mod dedup;
+mod error;
+mod filters;
+mod fonts;
+mod jpeg;
+mod metadata;
+mod placement;
+mod progress;
+mod recompress;
+mod report;
+mod samples;
+mod target;
+mod type1;
+mod writer;
+
+use color::ColorSpace;
+pub use error::CompressionError;
+pub use progress::Progress;
+pub use report::{CompressionReport, ImageAction, ImageReport};
+use report::SkipReason;
+
+/// How CMYK images are written back after recompression
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CmykHandling {
+    /// Convert to RGB before JPEG encoding. Smallest output, suited to screen viewing
+    #[default]
+    ConvertToRgb,
+    /// Keep the four CMYK channels and write a CMYK JPEG, for print workflows
+    Preserve,
+}
+
+/// When images are re-encoded losslessly (Flate with PNG predictors) instead of as JPEG
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LosslessMode {
+    /// Lossless for images with few colors (screenshots, diagrams, line art), JPEG otherwise
+    #[default]
+    Auto,
+    /// Always lossless, for documents where no artifacts are acceptable
+    Always,
+    /// Always JPEG
+    Never,
+}
+
+/// How 1-bit images are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BilevelEncoding {
+    /// CCITT Group 4 fax coding, typically several times smaller than Flate for scans
+    #[default]
+    CcittG4,
+    /// Flate at the best compression level
+    Flate,
+}
+
+/// How hard non-image streams are recompressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FlateEffort {
+    /// Only compress streams that have no filter
+    Off,
+    /// Re-deflate every Flate stream at the highest zlib level
+    #[default]
+    Best,
+    /// Re-deflate with Zopfli's exhaustive search; much slower, a few percent smaller
+    Exhaustive,
+}
+
+/// What happens to the document's Info dictionary and XMP metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataMode {
+    /// Remove all XMP and every Info entry not listed in `keep_info_keys`
+    #[default]
+    Strip,
+    /// Leave metadata as it is
+    Preserve,
+    /// Keep the descriptive Info entries (title, author, subject, keywords, creator,
+    /// producer, dates) and `keep_info_keys`, apply the title/author/producer
+    /// overrides and replace all XMP with a minimal packet matching the Info entries
+    Rewrite,
+}
+
+/// Which kinds of unneeded objects the cleanup pass removes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CleanupOptions {
+    /// Page thumbnail images (/Thumb), which viewers render themselves
+    pub thumbnails: bool,
+    /// Resource dictionary entries (fonts, images, graphics states, ...) that no
+    /// content stream selects
+    pub unused_resources: bool,
+    /// Application private data (/PieceInfo), such as Illustrator's editing state
+    pub piece_info: bool,
+    /// Document scripts and JavaScript actions
+    pub javascript: bool,
+    /// Form XObjects listed in resources but never painted
+    pub orphan_forms: bool,
+}
+
+impl Default for CleanupOptions {
+    fn default() -> Self {
+        Self { thumbnails: true, unused_resources: true, piece_info: true, javascript: true, orphan_forms: true }
+    }
+}
+
+/// Number of items the cleanup pass removed, by kind
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CleanupStats {
+    pub thumbnails: usize,
+    pub unused_resources: usize,
+    pub piece_info: usize,
+    pub javascript: usize,
+    pub orphan_forms: usize,
+}
+
+impl CleanupStats {
+    pub fn total(&self) -> usize {
+        self.thumbnails + self.unused_resources + self.piece_info + self.javascript + self.orphan_forms
+    }
+}
+
+/// Form XObjects nested deeper than this are not followed by the passes that walk
+/// content streams
+const MAX_FORM_DEPTH: usize = 16;
+
+/// Images are only downsampled for `target_dpi` when their resolution exceeds the
+/// target by this factor; smaller reductions cost quality for little size gain
+const DPI_DOWNSAMPLE_THRESHOLD: f32 = 1.5;
+
+/// Map an optional on/off request flag to a mode: unset means `Auto`
+pub fn lossless_mode(lossless: Option<bool>) -> LosslessMode {
+    match lossless {
+        None => LosslessMode::Auto,
+        Some(true) => LosslessMode::Always,
+        Some(false) => LosslessMode::Never,
+    }
+}
+
+/// Images with at most this many distinct colors count as line art in `LosslessMode::Auto`
+const LINE_ART_MAX_COLORS: usize = 256;
 
 /// Configuration for PDF compression
 #[derive(Debug, Clone)]
@@ -10,8 +156,94 @@ pub struct CompressionConfig {
     pub jpeg_quality: u8,
     /// Maximum dimension in pixels. Images larger than this will be downscaled. Default: 600
     pub max_dimension: u32,
-    /// Whether to remove metadata from the PDF. Default: true
-    pub remove_metadata: bool,
+    /// How Info and XMP metadata are handled. Default: strip
+    pub metadata: MetadataMode,
+    /// Info dictionary keys (e.g. "Title") kept when stripping, or kept in addition
+    /// to the descriptive ones when rewriting. Default: none
+    pub keep_info_keys: Vec<String>,
+    /// Document title set when rewriting metadata. Default: None (keep the existing one)
+    pub title: Option<String>,
+    /// Document author set when rewriting metadata. Default: None
+    pub author: Option<String>,
+    /// Producer set when rewriting metadata. Default: None
+    pub producer: Option<String>,
+    /// Inputs larger than this many bytes are rejected before parsing. Default: None
+    pub max_input_size: Option<u64>,
+    /// How CMYK images are re-encoded. Default: convert to RGB
+    pub cmyk_handling: CmykHandling,
+    /// Minimum fraction (0.0-1.0) a recompressed image must save over its original
+    /// stream to replace it. Default: 0.05
+    pub min_image_savings: f32,
+    /// When to keep images lossless instead of JPEG encoding them. Default: auto
+    pub lossless: LosslessMode,
+    /// Codec for 1-bit images. Default: CCITT Group 4
+    pub bilevel_encoding: BilevelEncoding,
+    /// Threshold grayscale images to 1-bit, for black-and-white scans stored as gray. Default: false
+    pub threshold_grayscale: bool,
+    /// Downsample images to this resolution at the size they are drawn on the page.
+    /// Replaces `max_dimension` for images found in page content. Default: None
+    pub target_dpi: Option<u32>,
+    /// Output size in bytes to aim for by lowering JPEG quality, then resolution. Default: None
+    pub target_size: Option<u64>,
+    /// Size reduction in percent (0-100) to aim for, searched like `target_size`. Default: None
+    pub target_ratio: Option<f32>,
+    /// Lowest JPEG quality the target size search may use. Default: 10
+    pub min_jpeg_quality: u8,
+    /// Drop unused fonts and reduce embedded TrueType, OpenType, CFF and Type 1 fonts
+    /// to the glyphs the document uses. Default: true
+    pub subset_fonts: bool,
+    /// Merge streams and dictionaries that are identical once decoded, such as a
+    /// logo or font embedded once per page in merged PDFs. Default: true
+    pub deduplicate: bool,
+    /// Pack objects into compressed object streams and write a cross-reference stream
+    /// (PDF 1.5). Default: true
+    pub object_streams: bool,
+    /// Clean up page and form content streams: redundant `q`/`Q` pairs, consecutive
+    /// text moves and no-op operators are removed. Default: true
+    pub optimize_content: bool,
+    /// Decimal places coordinates in content streams are rounded to when optimising
+    /// them. Colours and other operands are never rounded. Default: 3
+    pub content_decimals: u8,
+    /// Effort spent recompressing Flate streams other than images, keeping whichever
+    /// encoding is smaller. Default: best
+    pub flate_effort: FlateEffort,
+    /// Unneeded objects to remove: thumbnails, unused resources, application data,
+    /// scripts and unpainted forms. Default: all of them
+    pub cleanup: CleanupOptions,
+    /// Worker threads used to recompress images. Default: None (rayon's global
+    /// pool, one thread per CPU core)
+    pub threads: Option<usize>,
+}
+
+/// A compressed document and the settings that produced it
+#[derive(Debug, Clone)]
+pub struct CompressionOutput {
+    pub data: Vec<u8>,
+    /// Settings of the pass whose output was returned; in target size mode these
+    /// are the quality and resolution the search settled on
+    pub final_config: CompressionConfig,
+    /// Whether the output meets `target_size`/`target_ratio`, or None when no target
+    /// was set. False means the target needs a quality below `min_jpeg_quality`
+    /// even at the lowest resolution tried
+    pub target_reached: Option<bool>,
+    /// Size reduction achieved, in percent of the input size
+    pub achieved_ratio: f32,
+    /// Items removed by the cleanup pass
+    pub cleanup: CleanupStats,
+    /// Encoded size of the duplicate objects merged by deduplication
+    pub bytes_reclaimed: usize,
+    /// What happened to each image
+    pub report: CompressionReport,
+}
+
+/// Output of a single compression pass
+#[derive(Debug, Clone)]
+pub(crate) struct PassOutput {
+    pub data: Vec<u8>,
+    /// All zero when the input is returned unchanged
+    pub cleanup: CleanupStats,
+    pub bytes_reclaimed: usize,
+    pub report: CompressionReport,
 }
 
 impl Default for CompressionConfig {
@@ -19,23 +251,208 @@ impl Default for CompressionConfig {
         Self {
             jpeg_quality: 30,  // Very aggressive for 90% reduction
             max_dimension: 600, // Smaller dimensions for 90% reduction
-            remove_metadata: true,
+            metadata: MetadataMode::Strip,
+            keep_info_keys: Vec::new(),
+            title: None,
+            author: None,
+            producer: None,
+            max_input_size: None,
+            cmyk_handling: CmykHandling::ConvertToRgb,
+            min_image_savings: 0.05,
+            lossless: LosslessMode::Auto,
+            bilevel_encoding: BilevelEncoding::CcittG4,
+            threshold_grayscale: false,
+            target_dpi: None,
+            target_size: None,
+            target_ratio: None,
+            min_jpeg_quality: 10,
+            subset_fonts: true,
+            deduplicate: true,
+            object_streams: true,
+            optimize_content: true,
+            content_decimals: 3,
+            flate_effort: FlateEffort::Best,
+            cleanup: CleanupOptions::default(),
+            threads: None,
+        }
+    }
+}
+
+/// Named bundles of settings for common uses, similar to Ghostscript's `-dPDFSETTINGS`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionPreset {
+    /// On-screen viewing: 72 dpi, low JPEG quality, RGB, no metadata
+    Screen,
+    /// E-readers and tablets: 150 dpi, medium JPEG quality, RGB, no metadata
+    Ebook,
+    /// Office printing: 300 dpi, high JPEG quality, CMYK and metadata kept
+    Printer,
+    /// Commercial printing: 300 dpi, very high JPEG quality, CMYK and metadata kept
+    Prepress,
+    /// Long-term storage: no downsampling, lossless images only, metadata kept
+    Archive,
+}
+
+impl CompressionPreset {
+    /// The settings this preset stands for
+    pub fn config(self) -> CompressionConfig {
+        let base = CompressionConfig::default();
+        match self {
+            CompressionPreset::Screen => CompressionConfig {
+                jpeg_quality: 30,
+                max_dimension: 600,
+                target_dpi: Some(72),
+                ..base
+            },
+            CompressionPreset::Ebook => CompressionConfig {
+                jpeg_quality: 50,
+                max_dimension: 1200,
+                target_dpi: Some(150),
+                ..base
+            },
+            CompressionPreset::Printer => CompressionConfig {
+                jpeg_quality: 75,
+                max_dimension: 2400,
+                metadata: MetadataMode::Preserve,
+                cmyk_handling: CmykHandling::Preserve,
+                target_dpi: Some(300),
+                ..base
+            },
+            CompressionPreset::Prepress => CompressionConfig {
+                jpeg_quality: 90,
+                max_dimension: 4800,
+                metadata: MetadataMode::Preserve,
+                cmyk_handling: CmykHandling::Preserve,
+                target_dpi: Some(300),
+                ..base
+            },
+            CompressionPreset::Archive => CompressionConfig {
+                jpeg_quality: 95,
+                max_dimension: u32::MAX,
+                metadata: MetadataMode::Preserve,
+                cmyk_handling: CmykHandling::Preserve,
+                lossless: LosslessMode::Always,
+                target_dpi: None,
+                ..base
+            },
         }
     }
 }
 
-pub fn compress_pdf(input: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+pub fn compress_pdf(input: &[u8]) -> Result<Vec<u8>, CompressionError> {
     compress_pdf_with_config(input, CompressionConfig::default())
 }
 
-pub fn compress_pdf_with_config(input: &[u8], config: CompressionConfig) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+pub fn compress_pdf_with_config(input: &[u8], config: CompressionConfig) -> Result<Vec<u8>, CompressionError> {
+    compress_pdf_with_output(input, config).map(|output| output.data)
+}
+
+/// Compress and report how the result was reached. With `target_size` or
+/// `target_ratio` set, the document is compressed repeatedly with lower quality and
+/// resolution until it fits (the smaller target wins when both are given).
+pub fn compress_pdf_with_output(input: &[u8], config: CompressionConfig) -> Result<CompressionOutput, CompressionError> {
+    compress_pdf_with_progress(input, config, &Progress::default())
+}
+
+/// `compress_pdf_with_output`, reporting progress to `progress` and stopping with
+/// `CompressionError::Cancelled` when it is cancelled
+pub fn compress_pdf_with_progress(
+    input: &[u8],
+    config: CompressionConfig,
+    progress: &Progress,
+) -> Result<CompressionOutput, CompressionError> {
+    let span = tracing::info_span!("compress_document", input_bytes = input.len(), output_bytes = field::Empty, images = field::Empty);
+    let _entered = span.enter();
+    if let Some(max_input_size) = config.max_input_size.filter(|max| input.len() as u64 > *max) {
+        return Err(CompressionError::ResourceLimit(format!(
+            "input is {} bytes, the limit is {} bytes",
+            input.len(),
+            max_input_size
+        )));
+    }
+    let ratio_size = config
+        .target_ratio
+        .map(|ratio| (input.len() as f64 * (1.0 - ratio.clamp(0.0, 100.0) as f64 / 100.0)) as u64);
+    // Built once for all passes, since target size mode runs several
+    let pool = config.threads.and_then(|threads| match rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+        Ok(pool) => Some(pool),
+        Err(e) => {
+            tracing::warn!(threads, error = %e, "Could not start image worker threads, using the shared pool");
+            None
+        }
+    });
+    let (pass, final_config, target_reached) = match config.target_size.into_iter().chain(ratio_size).min() {
+        Some(target_size) => {
+            let (pass, final_config, reached) = target::compress_to_size(input, config, target_size, pool.as_ref(), progress)?;
+            (pass, final_config, Some(reached))
+        }
+        None => (compress_once(input, &config, pool.as_ref(), progress)?, config, None),
+    };
+    let PassOutput { data, cleanup, bytes_reclaimed, report } = pass;
+    span.record("output_bytes", data.len());
+    span.record("images", report.images.len());
+
+    let achieved_ratio = if input.is_empty() {
+        0.0
+    } else {
+        (1.0 - data.len() as f64 / input.len() as f64) as f32 * 100.0
+    };
+    Ok(CompressionOutput { data, final_config, target_reached, achieved_ratio, cleanup, bytes_reclaimed, report })
+}
+
+/// One pass of the compression pipeline with fixed settings. Images are recompressed
+/// on `pool`, or on rayon's global pool without one.
+fn compress_once(
+    input: &[u8],
+    config: &CompressionConfig,
+    pool: Option<&rayon::ThreadPool>,
+    progress: &Progress,
+) -> Result<PassOutput, CompressionError> {
+    let pass_span = tracing::debug_span!(
+        "compression_pass",
+        jpeg_quality = config.jpeg_quality,
+        max_dimension = config.max_dimension,
+        output_bytes = field::Empty
+    );
+    let _entered = pass_span.enter();
+    progress.start_pass();
     let mut doc = Document::load_mem(input)?;
+    // lopdf loads encrypted documents without decrypting them, and compressing
+    // their streams would corrupt them
+    if let Ok(encrypt) = doc.get_encrypted() {
+        return Err(match encrypt.get(b"Filter").and_then(Object::as_name) {
+            Ok(b"Standard") | Err(_) => CompressionError::Encrypted,
+            Ok(handler) => CompressionError::Unsupported(format!("{} security handler", String::from_utf8_lossy(handler))),
+        });
+    }
 
-    println!("Starting compression with config: quality={}, max_dim={}", config.jpeg_quality, config.max_dimension);
+    let metadata_changed = metadata::process_metadata(&mut doc, config);
+    tracing::debug!(mode = ?config.metadata, changed = metadata_changed, "Processed PDF metadata");
 
-    // Remove metadata if configured
-    if config.remove_metadata {
-        remove_metadata(&mut doc);
+    // Merge copies first so each shared image or font is only processed once
+    let mut bytes_reclaimed = 0;
+    if config.deduplicate {
+        let stats = dedup::deduplicate(&mut doc);
+        if stats.objects_merged > 0 {
+            tracing::info!(objects_merged = stats.objects_merged, bytes_reclaimed = stats.bytes_reclaimed, "Merged duplicate objects");
+        }
+        bytes_reclaimed = stats.bytes_reclaimed;
+    }
+
+    progress.check()?;
+    let cleanup = cleanup::clean_up(&mut doc, &config.cleanup);
+    if cleanup.total() > 0 {
+        tracing::info!(
+            thumbnails = cleanup.thumbnails,
+            unused_resources = cleanup.unused_resources,
+            piece_info = cleanup.piece_info,
+            javascript = cleanup.javascript,
+            orphan_forms = cleanup.orphan_forms,
+            "Removed unneeded objects"
+        );
+        // Drop what is no longer referenced, so removed thumbnails and images are not recompressed
+        doc.prune_objects();
     }
 
     // Collect all stream objects that are images
@@ -43,10 +460,7 @@ pub fn compress_pdf_with_config(input: &[u8], config: CompressionConfig) -> Resu
     let image_ids: Vec<_> = doc.objects.iter()
         .filter(|(_, obj)| {
             if let Ok(stream) = obj.as_stream() {
-                 match stream.dict.get(b"Subtype") {
-                     Ok(Object::Name(name)) if name == b"Image" => true,
-                     _ => false,
-                 }
+                 matches!(stream.dict.get(b"Subtype"), Ok(Object::Name(name)) if name == b"Image")
             } else {
                 false
             }
@@ -54,36 +468,138 @@ pub fn compress_pdf_with_config(input: &[u8], config: CompressionConfig) -> Resu
         .map(|(id, _)| *id)
         .collect();
 
-    println!("Found {} images to process", image_ids.len());
+    // Soft masks are recompressed together with the image that uses them
+    let smask_ids: BTreeSet<ObjectId> = image_ids.iter()
+        .filter_map(|id| doc.get_object(*id).ok()?.as_stream().ok()?.dict.get(b"SMask").ok()?.as_reference().ok())
+        .collect();
+    let image_ids: Vec<_> = image_ids.into_iter().filter(|id| !smask_ids.contains(id)).collect();
+    let mut processed_smasks = BTreeSet::new();
+
+    tracing::info!(images = image_ids.len(), "Found images to process");
+    progress.found_images(image_ids.len());
 
-    // Process images in parallel
-    // We can't modify the document in parallel easily, so we process the data then update.
-    // However, extracting content requires access to the doc.
-    // For simplicity in this iteration, we might process sequentially or careful extraction.
-    
-    // Let's iterate and process.
-    // Needed: ID, Original Data (decoded), Filter info to re-encode.
-    
-    // Strategy: 
-    // 1. Identify images.
-    // 2. Decode them using lopdf to raw bytes.
-    // 3. Process with `image` crate (resize, compress to JPEG).
-    // 4. Replace the Stream content in the Document.
-    
-    for object_id in image_ids {
-        // We have to handle errors gracefully to avoid failing the whole PDF if one image fails
-        println!("Processing image {:?}", object_id);
-        match process_image_object(&doc, object_id, &config) {
-            Ok(processed_stream) => {
-                println!("Successfully processed image {:?}", object_id);
-                if let Some(obj) = doc.objects.get_mut(&object_id) {
-                    *obj = processed_stream;
+    // Drawn sizes are only needed to downsample by resolution
+    let placements = match config.target_dpi {
+        Some(_) => placement::image_placements(&doc),
+        None => BTreeMap::new(),
+    };
+
+    // Images are decoded and encoded in parallel against the unchanged document,
+    // then written back in their original order so the output does not depend on
+    // scheduling. Errors are kept per image so one bad image does not fail the PDF.
+    let encode = || -> Vec<ImageResult> {
+        image_ids
+            .par_iter()
+            .map(|&object_id| {
+                // After a cancellation the remaining images are only passed over
+                if progress.is_cancelled() {
+                    let entry = ImageReport::describe(&doc, object_id, 0);
+                    return ImageResult { object_id, entry, span: tracing::Span::none(), result: Err("cancelled".into()) };
+                }
+                let smask_size = doc
+                    .get_object(object_id)
+                    .and_then(Object::as_stream)
+                    .and_then(|s| s.dict.get(b"SMask"))
+                    .and_then(Object::as_reference)
+                    .map_or(0, |smask_id| stream_len(doc.objects.get(&smask_id)));
+                let entry = ImageReport::describe(&doc, object_id, stream_len(doc.objects.get(&object_id)) + smask_size);
+                let span = tracing::debug_span!(
+                    parent: &pass_span,
+                    "image",
+                    object = object_id.0,
+                    generation = object_id.1,
+                    width = entry.original_width,
+                    height = entry.original_height,
+                    bytes_before = entry.bytes_before,
+                    bytes_after = field::Empty,
+                    action = field::Empty
+                );
+                let result = span.in_scope(|| {
+                    let image_config = CompressionConfig {
+                        max_dimension: image_max_dimension(&doc, object_id, &placements, config),
+                        ..config.clone()
+                    };
+                    process_image_object(&doc, object_id, &image_config)
+                });
+                progress.image_done();
+                ImageResult { object_id, entry, span, result }
+            })
+            .collect()
+    };
+    let encoded = match pool {
+        Some(pool) => pool.install(encode),
+        None => encode(),
+    };
+    progress.check()?;
+
+    let mut report = CompressionReport::default();
+    for ImageResult { object_id, mut entry, span, result } in encoded {
+        let _entered = span.enter();
+        match result {
+            Ok(processed) => {
+                // Keep the original (and its mask) unless the new encoding is clearly smaller
+                let original_size = stream_len(doc.objects.get(&object_id))
+                    + processed.smask.as_ref().map_or(0, |(smask_id, _)| stream_len(doc.objects.get(smask_id)));
+                let new_size = stream_len(Some(&processed.image))
+                    + processed.smask.as_ref().map_or(0, |(_, smask)| stream_len(Some(smask)))
+                    + stream_len(processed.new_smask.as_ref());
+                let max_size = original_size as f64 * (1.0 - config.min_image_savings.clamp(0.0, 1.0) as f64);
+                if new_size as f64 > max_size {
+                    tracing::debug!(recompressed_bytes = new_size, "Keeping original image");
+                    entry.kept(format!("recompressed to {} bytes, not enough smaller than {} bytes", new_size, original_size));
+                } else {
+                    entry.recompressed(&processed.image, new_size);
+                    let mut image = processed.image;
+                    // A mask shared by several images is only replaced once
+                    if let Some((smask_id, smask)) = processed.smask {
+                        if processed_smasks.insert(smask_id) {
+                            doc.objects.insert(smask_id, smask);
+                        }
+                    }
+                    if let Some(new_smask) = processed.new_smask {
+                        let smask_id = doc.add_object(new_smask);
+                        if let Ok(stream) = image.as_stream_mut() {
+                            stream.dict.set(b"SMask", Object::Reference(smask_id));
+                        }
+                    }
+                    if let Some(obj) = doc.objects.get_mut(&object_id) {
+                        *obj = image;
+                    }
                 }
             },
             Err(e) => {
-                println!("Failed to process image {:?}: {}", object_id, e);
+                entry.not_processed(e.as_ref());
+                if entry.action == ImageAction::Failed {
+                    tracing::warn!(error = %e, "Failed to process image");
+                } else {
+                    tracing::debug!(reason = %e, "Skipping image");
+                }
             }
         }
+        span.record("bytes_after", entry.bytes_after);
+        span.record("action", field::debug(entry.action));
+        report.images.push(entry);
+    }
+
+    if config.subset_fonts {
+        let stats = fonts::subset_fonts(&mut doc);
+        if stats.fonts_removed > 0 {
+            tracing::info!(fonts_removed = stats.fonts_removed, "Removed unused fonts");
+        }
+        if stats.fonts_subset > 0 {
+            tracing::info!(fonts_subset = stats.fonts_subset, bytes_saved = stats.bytes_saved, "Subset fonts");
+        }
+    }
+
+    if config.optimize_content {
+        let stats = content::optimize_content_streams(&mut doc, config.content_decimals);
+        if stats.streams_optimized > 0 {
+            tracing::info!(
+                streams_optimized = stats.streams_optimized,
+                bytes_saved = stats.bytes_saved,
+                "Optimized content streams (savings before compression)"
+            );
+        }
     }
 
     // Remove unused objects (simple garbage collection)
@@ -92,173 +608,649 @@ pub fn compress_pdf_with_config(input: &[u8], config: CompressionConfig) -> Resu
     // Compress streams (general PDF compression)
     doc.compress();
 
+    if config.flate_effort != FlateEffort::Off {
+        let stats = recompress::recompress_streams(&mut doc, config.flate_effort);
+        if stats.streams_recompressed > 0 {
+            tracing::info!(streams_recompressed = stats.streams_recompressed, bytes_saved = stats.bytes_saved, "Recompressed streams");
+        }
+    }
+
+    progress.check()?;
+
     // Save to memory
-    let mut out_buffer = Vec::new();
-    doc.save_to(&mut out_buffer)?;
-    
-    Ok(out_buffer)
-}
+    let out_buffer = if config.object_streams {
+        writer::save_with_object_streams(&doc, config.flate_effort)?
+    } else {
+        let mut out_buffer = Vec::new();
+        doc.save_to(&mut out_buffer)?;
+        out_buffer
+    };
 
-fn remove_metadata(doc: &mut Document) {
-    // Remove Info dictionary from trailer (contains metadata like Author, Title, etc.)
-    let _ = doc.trailer.remove(b"Info");
-    
-    // Try to remove Metadata stream from catalog if present
-    // We iterate through objects to find and remove metadata streams
-    let metadata_ids: Vec<_> = doc.objects.iter()
-        .filter(|(_, obj)| {
-            if let Ok(stream) = obj.as_stream() {
-                if let Ok(Object::Name(name)) = stream.dict.get(b"Type") {
-                    return name == b"Metadata";
-                }
+    // Rewriting the document can still grow it (e.g. when nothing could be recompressed).
+    // The input is only a substitute when the pass changed nothing but encodings.
+    pass_span.record("output_bytes", out_buffer.len());
+    if out_buffer.len() >= input.len() {
+        if metadata_changed || cleanup.total() > 0 {
+            tracing::info!(output_bytes = out_buffer.len(), input_bytes = input.len(), "Compressed output is not smaller than the input, keeping it for the requested changes");
+            report.grew = true;
+        } else {
+            tracing::info!(output_bytes = out_buffer.len(), input_bytes = input.len(), "Compressed output is not smaller than the input, returning the original");
+            for entry in report.images.iter_mut().filter(|e| e.action == ImageAction::Recompressed) {
+                entry.kept("the compressed document was not smaller than the input".to_string());
             }
-            false
-        })
-        .map(|(id, _)| *id)
-        .collect();
-    
-    for id in metadata_ids {
-        doc.objects.remove(&id);
+            return Ok(PassOutput { data: input.to_vec(), cleanup: CleanupStats::default(), bytes_reclaimed: 0, report });
+        }
     }
     
-    println!("Removed PDF metadata");
+    Ok(PassOutput { data: out_buffer, cleanup, bytes_reclaimed, report })
+}
+
+/// Largest pixel dimension an image may keep. With a target DPI this follows from the
+/// biggest size the image is drawn at; unplaced images fall back to `max_dimension`.
+fn image_max_dimension(
+    doc: &Document,
+    object_id: ObjectId,
+    placements: &BTreeMap<ObjectId, (f32, f32)>,
+    config: &CompressionConfig,
+) -> u32 {
+    let (Some(target_dpi), Some(&(placed_width, placed_height))) = (config.target_dpi, placements.get(&object_id)) else {
+        return config.max_dimension;
+    };
+    let Ok(dict) = doc.get_object(object_id).and_then(Object::as_stream).map(|s| &s.dict) else {
+        return config.max_dimension;
+    };
+    let width = dict.get(b"Width").and_then(|v| v.as_i64()).unwrap_or(0) as f32;
+    let height = dict.get(b"Height").and_then(|v| v.as_i64()).unwrap_or(0) as f32;
+    if width <= 0.0 || height <= 0.0 || placed_width <= 0.0 || placed_height <= 0.0 {
+        return config.max_dimension;
+    }
+
+    // Placed sizes are in points (1/72 inch). Keep enough pixels for the target
+    // resolution along both axes, so non-uniformly stretched images stay sharp.
+    let effective_dpi = (width / placed_width * 72.0).min(height / placed_height * 72.0);
+    let scale = target_dpi as f32 / effective_dpi;
+    tracing::debug!(object = object_id.0, effective_dpi = effective_dpi.round(), target_dpi, "Image resolution");
+    if scale * DPI_DOWNSAMPLE_THRESHOLD >= 1.0 {
+        return u32::MAX;
+    }
+    (width.max(height) * scale).round().max(1.0) as u32
+}
+
+/// Size of a stream object's encoded content, 0 for anything else
+fn stream_len(object: Option<&Object>) -> usize {
+    object.and_then(|o| o.as_stream().ok()).map_or(0, |s| s.content.len())
+}
+
+/// Pixels decoded from an image XObject
+enum DecodedPixels {
+    Image(DynamicImage),
+    /// Plain (non-inverted) CMYK samples, kept in an RGBA buffer so the `image` crate can resample them
+    Cmyk(image::RgbaImage),
+    /// 1-bit samples expanded to 0/255, still in the image's original color space
+    Bilevel(image::GrayImage),
+}
+
+/// Result of recompressing one image, waiting to be written back
+struct ImageResult {
+    object_id: ObjectId,
+    entry: ImageReport,
+    span: tracing::Span,
+    result: Result<ProcessedImage, Box<dyn std::error::Error + Send + Sync>>,
 }
 
-fn process_image_object(doc: &Document, object_id: (u32, u16), config: &CompressionConfig) -> Result<Object, Box<dyn std::error::Error + Send + Sync>> {
+/// Replacement objects produced for one image XObject
+struct ProcessedImage {
+    image: Object,
+    /// Recompressed copy of the image's existing /SMask stream
+    smask: Option<(ObjectId, Object)>,
+    /// Soft mask split off from an alpha channel, to be added as a new object
+    new_smask: Option<Object>,
+}
+
+fn process_image_object(doc: &Document, object_id: ObjectId, config: &CompressionConfig) -> Result<ProcessedImage, Box<dyn std::error::Error + Send + Sync>> {
     let object = doc.get_object(object_id)?;
     let stream = object.as_stream()?;
-    
-    // Try to decode the stream
-    // lopdf's decode_content usually handles filters like FlateDecode
-    // But for Images, we might need to be careful with color spaces.
-    
-    // Note: lopdf decode might just give raw pixels or formatted data depending on filters.
-    // If it's a JPEG (DCTDecode), we can try to re-compress it if it's too large.
-    // If it's a raw bitmap or PNG (FlateDecode), we can convert to JPEG.
-    
-    let _content_data = stream.content.clone(); 
-    // We interpret the stream. If it has filters, we should try to decode it.
-    // However, lopdf `get_content` or `decode` doesn't fully handle all image conversion logic (like CMYK -> RGB).
-    
-    // Let's attempt to use the raw bytes if it is already an image format, or decode if it is a stream of pixels.
-    // A robust PDF image extractor works by checking filters.
-    
-    let filters = stream.dict.get(b"Filter");
-    println!("Image {:?} filters: {:?}", object_id, filters);
-    
-    let is_jpeg = match filters {
-        Ok(Object::Name(name)) => name == b"DCTDecode",
-        Ok(Object::Array(arr)) => arr.contains(&Object::Name(b"DCTDecode".to_vec())),
-        _ => false,
+
+    // We need to know the dimensions and color space to form an image.
+    let width = stream.dict.get(b"Width").and_then(|v| v.as_i64()).unwrap_or(0) as u32;
+    let height = stream.dict.get(b"Height").and_then(|v| v.as_i64()).unwrap_or(0) as u32;
+    let bits = stream.dict.get(b"BitsPerComponent").and_then(|v| v.as_i64()).unwrap_or(8) as u8;
+    let original_color_space = stream.dict.get(b"ColorSpace").ok();
+    tracing::trace!(bits, filter = ?stream.dict.get(b"Filter").ok(), color_space = ?original_color_space, "Decoding image");
+
+    // Color key masks select exact sample values, which lossy recompression would not preserve
+    if matches!(stream.dict.get(b"Mask"), Ok(Object::Array(_))) {
+        return Err(SkipReason("Color key masked images are not recompressed".to_string()).into());
+    }
+
+    let pixels = decode_image(doc, stream)?;
+
+    // Transparency travels in a separate soft mask image; the base image only keeps color
+    let (pixels, alpha) = split_alpha(pixels);
+
+    // CMYK pixels are only kept as CMYK when the caller asked for it
+    let pixels = match pixels {
+        DecodedPixels::Cmyk(buf) if config.cmyk_handling == CmykHandling::ConvertToRgb => {
+            let (w, h) = buf.dimensions();
+            match image::RgbImage::from_raw(w, h, color::cmyk_to_rgb(buf.as_raw())) {
+                Some(rgb) => DecodedPixels::Image(DynamicImage::ImageRgb8(rgb)),
+                None => return Err("Failed to convert CMYK to RGB".into()),
+            }
+        }
+        other => other,
     };
 
-    let decoded_bytes = match stream.decompressed_content() {
-        Ok(bytes) => bytes,
-        Err(e) => {
-             // If it fails, maybe we can use raw content if it is just DCTDecode
-             if is_jpeg {
-                  println!("Decompression failed but identified as JPEG. Using raw content.");
-                  stream.content.clone()
-             } else {
-                  return Err(format!("Failed to decompress: {:?}", e).into());
-             }
-        },
+    let encoded = encode_pixels(doc, pixels, original_color_space, config)?;
+    
+    // Create new stream dictionary
+    let mut new_dict = stream.dict.clone();
+    new_dict.set(b"Filter", Object::Name(encoded.filter.to_vec()));
+    new_dict.set(b"Width", Object::Integer(encoded.width as i64));
+    new_dict.set(b"Height", Object::Integer(encoded.height as i64));
+    new_dict.set(b"BitsPerComponent", Object::Integer(encoded.bits_per_component as i64));
+    new_dict.set(b"Length", Object::Integer(encoded.data.len() as i64));
+    // Remove other filters/params that might conflict
+    new_dict.remove(b"DecodeParms");
+    if let Some(decode_parms) = encoded.decode_parms {
+        new_dict.set(b"DecodeParms", decode_parms);
+    }
+    if let Some(color_space) = encoded.color_space {
+        // Decode arrays and palette lookups have been applied to the samples, so the
+        // new data uses the default mapping in its new color space
+        new_dict.remove(b"Decode");
+        new_dict.set(b"ColorSpace", color_space);
+    }
+
+    // Existing soft masks are resampled in lockstep with the base image
+    let smask = match stream.dict.get(b"SMask") {
+        Ok(Object::Reference(smask_id)) => {
+            let mask = process_soft_mask(doc, *smask_id, (width, height), (encoded.width, encoded.height))?;
+            Some((*smask_id, mask))
+        }
+        _ => None,
+    };
+    let new_smask = match alpha {
+        Some(alpha) if smask.is_none() => Some(Object::Stream(encode_soft_mask(alpha, encoded.width, encoded.height, None))),
+        _ => None,
     };
 
-    // Now we have raw bytes. But we need to know the dimensions and color space to form an image.
+    Ok(ProcessedImage {
+        image: Object::Stream(Stream::new(new_dict, encoded.data)),
+        smask,
+        new_smask,
+    })
+}
+
+/// Decode an image XObject's samples, whatever its filters and color space
+fn decode_image(doc: &Document, stream: &Stream) -> Result<DecodedPixels, Box<dyn std::error::Error + Send + Sync>> {
+    // Undo the general purpose filters (Flate, LZW, predictors). Image codecs are left
+    // encoded; we can re-read JPEG and CCITT Group 4 data, anything else is skipped.
+    let decoded = filters::decode_image_stream(stream)?;
+
     let width = stream.dict.get(b"Width").and_then(|v| v.as_i64()).unwrap_or(0) as u32;
     let height = stream.dict.get(b"Height").and_then(|v| v.as_i64()).unwrap_or(0) as u32;
     let bits = stream.dict.get(b"BitsPerComponent").and_then(|v| v.as_i64()).unwrap_or(8) as u8;
-    let color_space = stream.dict.get(b"ColorSpace").ok();
-    
-    println!("Image {:?} Metadata: W={} H={} Bits={} CS={:?}", object_id, width, height, bits, color_space);
-
     if width == 0 || height == 0 {
          return Err("Invalid dimensions".into());
     }
 
-    let img: DynamicImage = if is_jpeg {
-         // Try loading as JPEG
-         let data = if decoded_bytes.len() > 0 { &decoded_bytes } else { &stream.content };
-         match ImageReader::new(Cursor::new(data)).with_guessed_format() {
-            Ok(reader) => reader.decode()?,
-            Err(e) => return Err(format!("Failed to read JPEG: {}", e).into()),
-         }
-    } else {
-        // Raw pixel data. 
-        // We only support standard RGB/Gray 8-bit for now to avoid complexity.
-        if bits != 8 {
-             return Err(format!("Unsupported bits per component: {}", bits).into());
+    let is_jpeg = match decoded.codec.as_deref() {
+        None => false,
+        Some(b"DCTDecode") => true,
+        Some(b"CCITTFaxDecode") => {
+            // Fax data decodes to plain 1-bit samples
+            let packed = ccitt::decode(&decoded.data, decoded.codec_parms.as_ref(), width, height)?;
+            return decode_raw_pixels(doc, stream, packed, width, height, 1);
         }
-        
-        let is_rgb = match color_space {
-            Some(Object::Name(name)) => name == b"DeviceRGB",
-            Some(Object::Array(arr)) if arr.len() > 0 => {
-                 // Sometime it's [/ICCBased ref] or [/DeviceRGB]
-                 if let Some(Object::Name(n)) = arr.get(0) { n == b"DeviceRGB" } else { false }
-            },
-            _ => false // Default to false, check for grayscale
+        Some(other) => return Err(SkipReason(format!("Unsupported image codec: {}", String::from_utf8_lossy(other))).into()),
+    };
+    let decoded_bytes = decoded.data;
+
+    if !is_jpeg {
+        return decode_raw_pixels(doc, stream, decoded_bytes, width, height, bits);
+    }
+
+    if jpeg::component_count(&decoded_bytes) == Some(4) {
+        // CMYK or YCCK JPEG. The `image` crate would convert these to RGB assuming
+        // Adobe-style inverted samples, so we read the raw channels ourselves.
+        let (jpeg_width, jpeg_height, mut samples) = jpeg::decode_four_component(&decoded_bytes)?;
+        // Adobe applications store CMYK JPEGs inverted. Files written by Acrobat
+        // describe that same inversion with a [1 0 1 0 ...] Decode array, so
+        // either hint means "invert once" rather than twice.
+        if jpeg::has_adobe_marker(&decoded_bytes) || color::decode_is_inverted(&stream.dict) {
+            color::invert(&mut samples);
+        }
+        return match image::RgbaImage::from_raw(jpeg_width, jpeg_height, samples) {
+            Some(buf) => Ok(DecodedPixels::Cmyk(buf)),
+            None => Err("Failed to create CMYK buffer from JPEG".into()),
+        };
+    }
+
+    let mut pixels = match ImageReader::new(Cursor::new(&decoded_bytes)).with_guessed_format() {
+        Ok(reader) => DecodedPixels::Image(reader.decode()?),
+        Err(e) => return Err(format!("Failed to read JPEG: {}", e).into()),
+    };
+    apply_decode_array(&mut pixels, &stream.dict);
+    Ok(pixels)
+}
+
+/// Separate the alpha channel from color pixels. Fully opaque alpha is dropped.
+fn split_alpha(pixels: DecodedPixels) -> (DecodedPixels, Option<image::GrayImage>) {
+    match pixels {
+        DecodedPixels::Image(img) if img.color().has_alpha() => {
+            let rgba = img.to_rgba8();
+            let alpha = image::GrayImage::from_fn(rgba.width(), rgba.height(), |x, y| image::Luma([rgba.get_pixel(x, y)[3]]));
+            let base = if img.color().has_color() {
+                DynamicImage::ImageRgb8(img.to_rgb8())
+            } else {
+                DynamicImage::ImageLuma8(img.to_luma8())
+            };
+            let alpha = (!alpha.iter().all(|&a| a == 255)).then_some(alpha);
+            (DecodedPixels::Image(base), alpha)
+        }
+        other => (other, None),
+    }
+}
+
+/// Resize and re-encode decoded pixels. Color images become JPEG with the configured
+/// quality (lower = smaller file); bilevel images stay 1-bit and use Flate.
+fn encode_pixels(
+    doc: &Document,
+    pixels: DecodedPixels,
+    original_color_space: Option<&Object>,
+    config: &CompressionConfig,
+) -> Result<EncodedImage, Box<dyn std::error::Error + Send + Sync>> {
+    let encoded = match pixels {
+        DecodedPixels::Image(img) => {
+            // Output is 8-bit gray or RGB; anything else (16-bit, alpha) is converted first
+            let img = match img {
+                img @ (DynamicImage::ImageLuma8(_) | DynamicImage::ImageRgb8(_)) => img,
+                img @ (DynamicImage::ImageLumaA8(_) | DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_)) => {
+                    DynamicImage::ImageLuma8(img.to_luma8())
+                }
+                img => DynamicImage::ImageRgb8(img.to_rgb8()),
+            };
+            let components = img.color().channel_count() as usize;
+            if config.threshold_grayscale && components == 1 {
+                // The gray levels (and any Decode array) are gone after thresholding,
+                // so the result is described as plain 1-bit gray
+                let color_space = color::output_color_space(doc, original_color_space, 1);
+                return encode_bilevel(img.into_luma8(), config, Some(color_space));
+            }
+            let lossless = use_lossless(config.lossless, img.as_bytes(), components);
+            let new_img = downscale(img, config.max_dimension);
+            let color_space = Some(color::output_color_space(doc, original_color_space, components));
+            if lossless {
+                encode_lossless(new_img.as_bytes(), new_img.width(), new_img.height(), components, color_space)
+            } else {
+                let mut comp_bytes: Vec<u8> = Vec::new();
+                let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut comp_bytes, config.jpeg_quality);
+                encoder.encode(new_img.as_bytes(), new_img.width(), new_img.height(), new_img.color().into())?;
+                EncodedImage {
+                    data: comp_bytes,
+                    width: new_img.width(),
+                    height: new_img.height(),
+                    filter: b"DCTDecode",
+                    bits_per_component: 8,
+                    color_space,
+                    decode_parms: None,
+                }
+            }
+        }
+        DecodedPixels::Cmyk(buf) => {
+            let lossless = use_lossless(config.lossless, buf.as_raw(), 4);
+            // Resampling treats the four channels independently, so RGBA storage is safe here
+            let new_buf = downscale(DynamicImage::ImageRgba8(buf), config.max_dimension).into_rgba8();
+            let color_space = Some(color::output_color_space(doc, original_color_space, 4));
+            if lossless {
+                encode_lossless(new_buf.as_raw(), new_buf.width(), new_buf.height(), 4, color_space)
+            } else {
+                EncodedImage {
+                    data: jpeg::encode(new_buf.as_raw(), new_buf.width(), new_buf.height(), 4, config.jpeg_quality)?,
+                    width: new_buf.width(),
+                    height: new_buf.height(),
+                    filter: b"DCTDecode",
+                    bits_per_component: 8,
+                    color_space,
+                    decode_parms: None,
+                }
+            }
+        }
+        DecodedPixels::Bilevel(gray) => encode_bilevel(gray, config, None)?,
+    };
+    Ok(encoded)
+}
+
+/// Resample a bilevel image as gray, threshold it back to two levels and encode it
+/// with the configured bilevel codec
+fn encode_bilevel(
+    gray: image::GrayImage,
+    config: &CompressionConfig,
+    color_space: Option<Object>,
+) -> Result<EncodedImage, Box<dyn std::error::Error + Send + Sync>> {
+    let new_gray = downscale(DynamicImage::ImageLuma8(gray), config.max_dimension).into_luma8();
+    let (width, height) = new_gray.dimensions();
+    let packed = samples::pack_bilevel(new_gray.as_raw(), width as usize);
+
+    // Group 4 line widths are limited to 16 bits; wider images fall back to Flate
+    if config.bilevel_encoding == BilevelEncoding::CcittG4 && width <= u16::MAX as u32 {
+        let decode_parms = lopdf::Dictionary::from_iter(vec![
+            ("K", Object::Integer(-1)),
+            ("Columns", Object::Integer(width as i64)),
+            ("Rows", Object::Integer(height as i64)),
+        ]);
+        return Ok(EncodedImage {
+            data: ccitt::encode_g4(&packed, width, height)?,
+            width,
+            height,
+            filter: b"CCITTFaxDecode",
+            bits_per_component: 1,
+            color_space,
+            decode_parms: Some(Object::Dictionary(decode_parms)),
+        });
+    }
+
+    Ok(EncodedImage {
+        data: filters::deflate(&packed),
+        width,
+        height,
+        filter: b"FlateDecode",
+        bits_per_component: 1,
+        color_space,
+        decode_parms: None,
+    })
+}
+
+/// Whether 8-bit samples should be kept lossless under `mode`
+fn use_lossless(mode: LosslessMode, samples: &[u8], components: usize) -> bool {
+    match mode {
+        LosslessMode::Always => true,
+        LosslessMode::Never => false,
+        // Flat colors and sharp edges compress well with Flate but show JPEG artifacts
+        LosslessMode::Auto => samples::has_few_colors(samples, components, LINE_ART_MAX_COLORS),
+    }
+}
+
+/// Encode 8-bit samples with PNG predictors and Flate at the best level
+fn encode_lossless(pixels: &[u8], width: u32, height: u32, components: usize, color_space: Option<Object>) -> EncodedImage {
+    let predicted = filters::png_predict(pixels, width as usize * components, components);
+    let decode_parms = lopdf::Dictionary::from_iter(vec![
+        ("Predictor", Object::Integer(15)),
+        ("Colors", Object::Integer(components as i64)),
+        ("BitsPerComponent", Object::Integer(8)),
+        ("Columns", Object::Integer(width as i64)),
+    ]);
+    EncodedImage {
+        data: filters::deflate(&predicted),
+        width,
+        height,
+        filter: b"FlateDecode",
+        bits_per_component: 8,
+        color_space,
+        decode_parms: Some(Object::Dictionary(decode_parms)),
+    }
+}
+
+/// Recompress an image's /SMask so it follows the base image's new size.
+///
+/// A mask with the same dimensions as its image (always the case with /Matte)
+/// gets exactly the new image dimensions; other masks are scaled by the same factor.
+fn process_soft_mask(
+    doc: &Document,
+    smask_id: ObjectId,
+    (width, height): (u32, u32),
+    (new_width, new_height): (u32, u32),
+) -> Result<Object, Box<dyn std::error::Error + Send + Sync>> {
+    let stream = doc.get_object(smask_id)?.as_stream()?;
+    let mask = match decode_image(doc, stream)? {
+        DecodedPixels::Image(img) => img.to_luma8(),
+        DecodedPixels::Bilevel(gray) => gray,
+        DecodedPixels::Cmyk(_) => return Err("Soft mask must be a gray image".into()),
+    };
+
+    let (mask_width, mask_height) = mask.dimensions();
+    let (target_width, target_height) = if (mask_width, mask_height) == (width, height) {
+        (new_width, new_height)
+    } else {
+        (
+            ((mask_width as u64 * new_width as u64) / width as u64).max(1) as u32,
+            ((mask_height as u64 * new_height as u64) / height as u64).max(1) as u32,
+        )
+    };
+
+    Ok(Object::Stream(encode_soft_mask(mask, target_width, target_height, Some(&stream.dict))))
+}
+
+/// Encode a soft mask as 8-bit DeviceGray with Flate. Masks stay lossless since JPEG
+/// artifacts show up as halos around transparent edges.
+fn encode_soft_mask(mask: image::GrayImage, width: u32, height: u32, original: Option<&lopdf::Dictionary>) -> Stream {
+    let mask = if mask.dimensions() == (width, height) {
+        mask
+    } else {
+        image::imageops::resize(&mask, width, height, image::imageops::FilterType::Triangle)
+    };
+
+    let mut dict = original.cloned().unwrap_or_else(|| {
+        lopdf::Dictionary::from_iter(vec![
+            ("Type", Object::Name(b"XObject".to_vec())),
+            ("Subtype", Object::Name(b"Image".to_vec())),
+        ])
+    });
+    let encoded = encode_lossless(mask.as_raw(), width, height, 1, Some(Object::Name(b"DeviceGray".to_vec())));
+    dict.set(b"Width", Object::Integer(width as i64));
+    dict.set(b"Height", Object::Integer(height as i64));
+    dict.set(b"BitsPerComponent", Object::Integer(8));
+    dict.set(b"Filter", Object::Name(encoded.filter.to_vec()));
+    if let Some(color_space) = encoded.color_space {
+        dict.set(b"ColorSpace", color_space);
+    }
+    if let Some(decode_parms) = encoded.decode_parms {
+        dict.set(b"DecodeParms", decode_parms);
+    }
+    // Any Decode array has been applied while decoding the mask
+    dict.remove(b"Decode");
+
+    Stream::new(dict, encoded.data)
+}
+
+/// Decode uncompressed samples (after Flate/LZW) into pixels using the image's color space
+fn decode_raw_pixels(
+    doc: &Document,
+    stream: &Stream,
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+    bits: u8,
+) -> Result<DecodedPixels, Box<dyn std::error::Error + Send + Sync>> {
+    if !matches!(bits, 1 | 2 | 4 | 8 | 16) {
+         return Err(format!("Unsupported bits per component: {}", bits).into());
+    }
+    let (w, h) = (width as usize, height as usize);
+    let pixel_count = w * h;
+    // Rows of packed samples always start on a byte boundary
+    let data_len_for = |components: usize| (w * components * bits as usize).div_ceil(8) * h;
+
+    // Stencil masks are 1-bit images without a color space
+    let image_mask = matches!(stream.dict.get(b"ImageMask"), Ok(Object::Boolean(true)));
+
+    // Resolve the color space through references, ICC profiles and palettes.
+    // Without one, guess from the number of bytes per pixel.
+    let resolved = match stream.dict.get(b"ColorSpace") {
+        _ if image_mask => ColorSpace::Gray,
+        Ok(cs) => color::resolve_color_space(doc, cs)?,
+        Err(_) if data.len() == data_len_for(4) => ColorSpace::Cmyk,
+        Err(_) if data.len() == data_len_for(3) => ColorSpace::Rgb,
+        Err(_) if data.len() == data_len_for(1) => ColorSpace::Gray,
+        Err(_) => return Err("Unknown ColorSpace or pixel format".into()),
+    };
+    let components = resolved.components();
+    if data.len() < data_len_for(components) {
+        return Err(format!("Image data too short: {} bytes, expected {}", data.len(), data_len_for(components)).into());
+    }
+
+    // Bilevel images keep their original samples, color space and Decode array
+    if bits == 1 && components == 1 {
+        let gray = samples::unpack_low_bits(&data, w, h, 1, 1, true);
+        return match image::GrayImage::from_raw(width, height, gray) {
+            Some(buf) => Ok(DecodedPixels::Bilevel(buf)),
+            None => Err("Failed to create bilevel buffer from raw bytes".into()),
         };
-        
-        let is_gray = match color_space {
-            Some(Object::Name(name)) => name == b"DeviceGray",
-             _ => false
+    }
+
+    // Palette indices have their own Decode semantics; the expanded colors are used as-is
+    let is_indexed = matches!(resolved, ColorSpace::Indexed { .. });
+    if bits == 16 {
+        let wide = samples::unpack_16_bits(&data[..data_len_for(components)]);
+        let wide_image = match resolved {
+            ColorSpace::Gray => image::ImageBuffer::from_raw(width, height, wide).map(DynamicImage::ImageLuma16),
+            ColorSpace::Rgb => image::ImageBuffer::from_raw(width, height, wide).map(DynamicImage::ImageRgb16),
+            ColorSpace::Indexed { .. } => return Err("Indexed images cannot use 16 bits per component".into()),
+            // CMYK is handled with 8 bits per sample below
+            ColorSpace::Cmyk => None,
         };
+        if let Some(img) = wide_image {
+            let mut pixels = DecodedPixels::Image(img);
+            apply_decode_array(&mut pixels, &stream.dict);
+            return Ok(pixels);
+        }
+    }
 
-        if is_rgb {
-             if let Some(buf) = image::RgbImage::from_raw(width, height, decoded_bytes.into()) {
-                 DynamicImage::ImageRgb8(buf)
-             } else {
-                 return Err("Failed to create RGB buffer from raw bytes".into());
-             }
-        } else if is_gray {
-             if let Some(buf) = image::GrayImage::from_raw(width, height, decoded_bytes.into()) {
-                 DynamicImage::ImageLuma8(buf)
-             } else {
-                 return Err("Failed to create Gray buffer from raw bytes".into());
-             }
-        } else {
-             // Try CMYK or assume RGB if 3 bytes per pixel
-             if decoded_bytes.len() as u32 == width * height * 3 {
-                  if let Some(buf) = image::RgbImage::from_raw(width, height, decoded_bytes.into()) {
-                     DynamicImage::ImageRgb8(buf)
-                  } else {
-                      return Err("Failed to force create RGB buffer".into());
-                  }
-             } else if decoded_bytes.len() as u32 == width * height * 4 {
-                  // CMYK likely, but image crate doesn't natively support CMYK -> RGB easily without conversion.
-                  // We can convert CMYK to RGB naively.
-                   return Err("CMYK not fully supported in this MVP".into());
-             } else {
-                 return Err("Unknown ColorSpace or pixel format".into());
-             }
+    let samples = match bits {
+        8 => data,
+        16 => samples::reduce_16_to_8_bits(&data),
+        _ => samples::unpack_low_bits(&data, w, h, components, bits, !is_indexed),
+    };
+
+    let (space, mut samples) = match resolved {
+        ColorSpace::Indexed { base, hival, lookup } => {
+            let indices = &samples[..pixel_count.min(samples.len())];
+            let expanded = color::expand_indexed(indices, base.components(), hival, &lookup);
+            (*base, expanded)
         }
+        other => (other, samples),
     };
+    samples.truncate(pixel_count * space.components());
 
-    // Resize (Downscale) - use config settings
-    let (w, h) = (img.width(), img.height());
-    let max_dim = config.max_dimension;
-    let new_img = if w > max_dim || h > max_dim {
+    let mut pixels = match space {
+        ColorSpace::Rgb => match image::RgbImage::from_raw(width, height, samples) {
+            Some(buf) => DecodedPixels::Image(DynamicImage::ImageRgb8(buf)),
+            None => return Err("Failed to create RGB buffer from raw bytes".into()),
+        },
+        ColorSpace::Gray => match image::GrayImage::from_raw(width, height, samples) {
+            Some(buf) => DecodedPixels::Image(DynamicImage::ImageLuma8(buf)),
+            None => return Err("Failed to create Gray buffer from raw bytes".into()),
+        },
+        ColorSpace::Cmyk => match image::RgbaImage::from_raw(width, height, samples) {
+            Some(buf) => DecodedPixels::Cmyk(buf),
+            None => return Err("Failed to create CMYK buffer from raw bytes".into()),
+        },
+        ColorSpace::Indexed { .. } => return Err("Nested Indexed color space".into()),
+    };
+    if !is_indexed {
+        apply_decode_array(&mut pixels, &stream.dict);
+    }
+    Ok(pixels)
+}
+
+/// Bake a non-default /Decode array into the samples
+fn apply_decode_array(pixels: &mut DecodedPixels, dict: &lopdf::Dictionary) {
+    match pixels {
+        DecodedPixels::Image(DynamicImage::ImageLuma8(buf)) => {
+            if let Some(decode) = color::decode_array(dict, 1) {
+                color::apply_decode(buf.as_mut(), &decode, 255.0);
+            }
+        }
+        DecodedPixels::Image(DynamicImage::ImageRgb8(buf)) => {
+            if let Some(decode) = color::decode_array(dict, 3) {
+                color::apply_decode(buf.as_mut(), &decode, 255.0);
+            }
+        }
+        DecodedPixels::Image(DynamicImage::ImageLuma16(buf)) => {
+            if let Some(decode) = color::decode_array(dict, 1) {
+                color::apply_decode(buf.as_mut(), &decode, 65535.0);
+            }
+        }
+        DecodedPixels::Image(DynamicImage::ImageRgb16(buf)) => {
+            if let Some(decode) = color::decode_array(dict, 3) {
+                color::apply_decode(buf.as_mut(), &decode, 65535.0);
+            }
+        }
+        DecodedPixels::Cmyk(buf) => {
+            if let Some(decode) = color::decode_array(dict, 4) {
+                color::apply_decode(buf.as_mut(), &decode, 255.0);
+            }
+        }
+        // Other layouts never come with a meaningful Decode array; bilevel images keep theirs
+        _ => {}
+    }
+}
+
+/// Re-encoded image data and the dictionary entries describing it
+struct EncodedImage {
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+    filter: &'static [u8],
+    bits_per_component: u8,
+    /// Replacement /ColorSpace, or None to keep the original entry
+    color_space: Option<Object>,
+    /// /DecodeParms for the new filter, if it needs any
+    decode_parms: Option<Object>,
+}
+
+/// Downscale so that neither side exceeds `max_dim`, keeping the aspect ratio
+fn downscale(img: DynamicImage, max_dim: u32) -> DynamicImage {
+    if img.width() > max_dim || img.height() > max_dim {
         // Use Triangle filter for faster processing with acceptable quality
         img.resize(max_dim, max_dim, image::imageops::FilterType::Triangle)
     } else {
         img
-    };
+    }
+}
 
-    // Re-encode to JPEG with configured quality (lower = smaller file)
-    let mut comp_bytes: Vec<u8> = Vec::new();
-    let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut comp_bytes, config.jpeg_quality);
-    encoder.encode(new_img.as_bytes(), new_img.width(), new_img.height(), new_img.color().into())?;
-    
-    // Create new stream dictionary
-    let mut new_dict = stream.dict.clone();
-    new_dict.set(b"Filter", Object::Name(b"DCTDecode".to_vec()));
-    new_dict.set(b"Width", Object::Integer(new_img.width() as i64));
-    new_dict.set(b"Height", Object::Integer(new_img.height() as i64));
-    new_dict.set(b"Length", Object::Integer(comp_bytes.len() as i64));
-    // Remove other filters/params that might conflict
-    new_dict.remove(b"DecodeParms");
-    new_dict.set(b"ColorSpace", Object::Name(b"DeviceRGB".to_vec())); // JPEG is usually DeviceRGB or DeviceGray
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    /// A one page document without images, saved as lopdf writes it
+    fn blank_document() -> Vec<u8> {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let page = doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id, "MediaBox" => vec![0.into(), 0.into(), 10.into(), 10.into()] });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+        let mut data = Vec::new();
+        doc.save_to(&mut data).unwrap();
+        data
+    }
 
-    Ok(Object::Stream(Stream::new(new_dict, comp_bytes)))
+    #[test]
+    fn unchanged_documents_that_grow_fall_back_to_the_input() {
+        let input = blank_document();
+        let config = CompressionConfig { metadata: MetadataMode::Preserve, ..CompressionConfig::default() };
+
+        let output = compress_pdf_with_output(&input, config).unwrap();
+
+        assert!(output.data.len() <= input.len());
+        assert!(!output.report.grew);
+    }
+
+    #[test]
+    fn requested_changes_are_kept_when_the_output_grows() {
+        let input = blank_document();
+        let config = CompressionConfig {
+            metadata: MetadataMode::Rewrite,
+            title: Some("A title long enough to outweigh what rewriting saves".to_string()),
+            ..CompressionConfig::default()
+        };
+
+        let output = compress_pdf_with_output(&input, config).unwrap();
+
+        assert!(output.data.len() >= input.len());
+        assert!(output.report.grew);
+        let doc = Document::load_mem(&output.data).unwrap();
+        assert!(doc.catalog().unwrap().has(b"Metadata"));
+    }
 }
@@ -0,0 +1,230 @@
+//! Where images are drawn: walks page content streams, tracking the current
+//! transformation matrix, to find the size each image XObject is painted at.
+
+use super::content::decode_complete;
+use super::MAX_FORM_DEPTH;
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Affine transform [a b c d e f] in PDF's row-vector convention
+#[derive(Debug, Clone, Copy)]
+struct Matrix([f32; 6]);
+
+impl Matrix {
+    const IDENTITY: Matrix = Matrix([1.0, 0.0, 0.0, 1.0, 0.0, 0.0]);
+
+    fn from_operands(operands: &[Object]) -> Option<Matrix> {
+        let values: Vec<f32> = operands.iter().filter_map(|o| o.as_float().ok()).collect();
+        <[f32; 6]>::try_from(values).ok().map(Matrix)
+    }
+
+    /// `self` applied first, then `other` (the effect of `self cm` on a CTM of `other`)
+    fn then(self, other: Matrix) -> Matrix {
+        let [a, b, c, d, e, f] = self.0;
+        let [a2, b2, c2, d2, e2, f2] = other.0;
+        Matrix([
+            a * a2 + b * c2,
+            a * b2 + b * d2,
+            c * a2 + d * c2,
+            c * b2 + d * d2,
+            e * a2 + f * c2 + e2,
+            e * b2 + f * d2 + f2,
+        ])
+    }
+
+    /// Lengths of the unit square's edges after the transform, in points
+    fn unit_square_size(self) -> (f32, f32) {
+        let [a, b, c, d, _, _] = self.0;
+        (a.hypot(b), c.hypot(d))
+    }
+}
+
+/// Largest size, in points, at which each image XObject is drawn on any page.
+/// Images that are never painted by a page or form content stream are absent.
+pub(crate) fn image_placements(doc: &Document) -> BTreeMap<ObjectId, (f32, f32)> {
+    let mut placements = BTreeMap::new();
+    for page_id in doc.get_pages().into_values() {
+        let Ok(content) = doc.get_page_content(page_id) else { continue };
+        let resources = page_resources(doc, page_id);
+        let mut active_forms = BTreeSet::new();
+        walk_content(doc, &content, &resources, Matrix::IDENTITY, &mut active_forms, &mut placements);
+    }
+    placements
+}
+
+fn walk_content(
+    doc: &Document,
+    content: &[u8],
+    resources: &[&Dictionary],
+    base_ctm: Matrix,
+    active_forms: &mut BTreeSet<ObjectId>,
+    placements: &mut BTreeMap<ObjectId, (f32, f32)>,
+) {
+    let Some(content) = decode_complete(content) else { return };
+    let mut ctm = base_ctm;
+    let mut stack = Vec::new();
+
+    for operation in &content.operations {
+        match operation.operator.as_str() {
+            "q" => stack.push(ctm),
+            "Q" => ctm = stack.pop().unwrap_or(base_ctm),
+            "cm" => {
+                if let Some(matrix) = Matrix::from_operands(&operation.operands) {
+                    ctm = matrix.then(ctm);
+                }
+            }
+            "Do" => {
+                let Some(name) = operation.operands.first().and_then(|o| o.as_name().ok()) else { continue };
+                let Some(xobject_id) = find_resource(doc, resources, b"XObject", name) else { continue };
+                let Ok(stream) = doc.get_object(xobject_id).and_then(Object::as_stream) else { continue };
+                match stream.dict.get(b"Subtype").and_then(Object::as_name) {
+                    Ok(b"Image") => {
+                        // Images fill the unit square of the current user space
+                        let (width, height) = ctm.unit_square_size();
+                        let placed = placements.entry(xobject_id).or_insert((0.0, 0.0));
+                        placed.0 = placed.0.max(width);
+                        placed.1 = placed.1.max(height);
+                    }
+                    Ok(b"Form") if active_forms.len() < MAX_FORM_DEPTH && active_forms.insert(xobject_id) => {
+                        let matrix = stream
+                            .dict
+                            .get(b"Matrix")
+                            .ok()
+                            .and_then(|m| m.as_array().ok())
+                            .and_then(|m| Matrix::from_operands(m))
+                            .unwrap_or(Matrix::IDENTITY);
+                        // Forms without their own resources inherit the caller's
+                        let own_resources = stream
+                            .dict
+                            .get(b"Resources")
+                            .ok()
+                            .and_then(|r| doc.dereference(r).ok())
+                            .and_then(|(_, r)| r.as_dict().ok());
+                        let form_resources: Vec<&Dictionary> = match own_resources {
+                            Some(dict) => vec![dict],
+                            None => resources.to_vec(),
+                        };
+                        let data = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+                        walk_content(doc, &data, &form_resources, matrix.then(ctm), active_forms, placements);
+                        active_forms.remove(&xobject_id);
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Look up a named resource (e.g. an XObject or Font) in the first resource
+/// dictionary that defines it. Only indirect resources are returned.
+pub(crate) fn find_resource(doc: &Document, resources: &[&Dictionary], category: &[u8], name: &[u8]) -> Option<ObjectId> {
+    resources.iter().find_map(|dict| {
+        let (_, entries) = doc.dereference(dict.get(category).ok()?).ok()?;
+        entries.as_dict().ok()?.get(name).ok()?.as_reference().ok()
+    })
+}
+
+/// The resource dictionaries visible to a page: its own, then inherited ones
+pub(crate) fn page_resources(doc: &Document, page_id: ObjectId) -> Vec<&Dictionary> {
+    let (inline, resource_ids) = doc.get_page_resources(page_id);
+    let mut resources: Vec<&Dictionary> = inline.into_iter().collect();
+    resources.extend(resource_ids.iter().filter_map(|id| doc.get_dictionary(*id).ok()));
+    resources
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{image_max_dimension, CompressionConfig};
+    use lopdf::{dictionary, Stream};
+
+    /// A one page document with a 600x300 image /Im1 and a form /Fm1 scaled by 2
+    /// that paints it with `form_content`
+    fn document(page_content: &str, form_content: &str) -> (Document, ObjectId) {
+        let mut doc = Document::with_version("1.5");
+        let image = doc.add_object(Stream::new(
+            dictionary! { "Type" => "XObject", "Subtype" => "Image", "Width" => 600, "Height" => 300, "ColorSpace" => "DeviceGray", "BitsPerComponent" => 8 },
+            Vec::new(),
+        ));
+        let form = doc.add_object(Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Form",
+                "BBox" => vec![0.into(), 0.into(), 100.into(), 100.into()],
+                "Matrix" => vec![2.into(), 0.into(), 0.into(), 2.into(), 0.into(), 0.into()],
+            },
+            form_content.as_bytes().to_vec(),
+        ));
+        let content = doc.add_object(Stream::new(Dictionary::new(), page_content.as_bytes().to_vec()));
+        let pages_id = doc.new_object_id();
+        let page = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content,
+            "Resources" => dictionary! { "XObject" => dictionary! { "Im1" => image, "Fm1" => form } },
+        });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+        (doc, image)
+    }
+
+    /// Effective resolution of the 600x300 image along its width and height
+    fn effective_dpi(placements: &BTreeMap<ObjectId, (f32, f32)>, image: ObjectId) -> (f32, f32) {
+        let (width, height) = placements[&image];
+        (600.0 / width * 72.0, 300.0 / height * 72.0)
+    }
+
+    #[test]
+    fn nested_cm_operators_compose() {
+        // 2x, then 144x72 points: drawn at 288x144 points, 150 dpi
+        let (doc, image) = document("q 2 0 0 2 0 0 cm q 144 0 0 72 10 10 cm /Im1 Do Q Q", "");
+        let placements = image_placements(&doc);
+        assert_eq!(effective_dpi(&placements, image), (150.0, 150.0));
+    }
+
+    #[test]
+    fn restored_state_does_not_leak_into_later_placements() {
+        // The scale inside q/Q is undone, so the second placement is 72x36 points
+        let (doc, image) = document("q 10 0 0 10 0 0 cm Q q 72 0 0 36 0 0 cm /Im1 Do Q", "");
+        let placements = image_placements(&doc);
+        assert_eq!(effective_dpi(&placements, image), (600.0, 600.0));
+    }
+
+    #[test]
+    fn largest_placement_wins() {
+        let (doc, image) = document("q 72 0 0 36 0 0 cm /Im1 Do Q q 288 0 0 144 0 0 cm /Im1 Do Q", "");
+        let placements = image_placements(&doc);
+        assert_eq!(effective_dpi(&placements, image), (150.0, 150.0));
+    }
+
+    #[test]
+    fn rotated_placements_measure_the_rotated_edges() {
+        // Rotated by 90 degrees: the image's width runs up the page
+        let (doc, image) = document("q 0 288 -144 0 144 0 cm /Im1 Do Q", "");
+        let placements = image_placements(&doc);
+        assert_eq!(effective_dpi(&placements, image), (150.0, 150.0));
+    }
+
+    #[test]
+    fn form_matrix_and_caller_ctm_apply_to_images_in_forms() {
+        // 0.5 on the page, 2 from the form's /Matrix and 288x144 in the form
+        let (doc, image) = document("q 0.5 0 0 0.5 0 0 cm /Fm1 Do Q", "q 288 0 0 144 0 0 cm /Im1 Do Q");
+        let placements = image_placements(&doc);
+        assert_eq!(effective_dpi(&placements, image), (150.0, 150.0));
+    }
+
+    #[test]
+    fn images_are_downsampled_to_the_target_dpi() {
+        let (doc, image) = document("q 288 0 0 144 0 0 cm /Im1 Do Q", "");
+        let placements = image_placements(&doc);
+
+        // Drawn at 150 dpi, so 75 dpi halves it
+        let config = CompressionConfig { target_dpi: Some(75), ..CompressionConfig::default() };
+        assert_eq!(image_max_dimension(&doc, image, &placements, &config), 300);
+        // 150 dpi is within the downsampling threshold of 120 dpi, so the image is left alone
+        let config = CompressionConfig { target_dpi: Some(120), ..CompressionConfig::default() };
+        assert_eq!(image_max_dimension(&doc, image, &placements, &config), u32::MAX);
+    }
+}
@@ -0,0 +1,63 @@
+//! Progress of a running compression, for callers that watch it from another
+//! thread (the job store) and may cancel it.
+
+use super::CompressionError;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[derive(Debug, Default)]
+pub struct Progress {
+    passes: AtomicUsize,
+    images_total: AtomicUsize,
+    images_processed: AtomicUsize,
+    cancelled: AtomicBool,
+}
+
+impl Progress {
+    /// Compression passes started. More than one only in target size mode, where
+    /// every pass processes the images again
+    pub fn passes(&self) -> usize {
+        self.passes.load(Ordering::Relaxed)
+    }
+
+    /// Images to process in the current pass; 0 until the images are found
+    pub fn images_total(&self) -> usize {
+        self.images_total.load(Ordering::Relaxed)
+    }
+
+    /// Images of the current pass that are done
+    pub fn images_processed(&self) -> usize {
+        self.images_processed.load(Ordering::Relaxed)
+    }
+
+    /// Ask the compression to stop. It returns `CompressionError::Cancelled` at the
+    /// next image or stage boundary
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn start_pass(&self) {
+        self.passes.fetch_add(1, Ordering::Relaxed);
+        self.images_total.store(0, Ordering::Relaxed);
+        self.images_processed.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn found_images(&self, count: usize) {
+        self.images_total.store(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn image_done(&self) {
+        self.images_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Err once the compression has been cancelled
+    pub(crate) fn check(&self) -> Result<(), CompressionError> {
+        if self.is_cancelled() {
+            return Err(CompressionError::Cancelled);
+        }
+        Ok(())
+    }
+}
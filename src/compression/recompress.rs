@@ -0,0 +1,58 @@
+//! Flate recompression: streams that other tools deflated at a low level (or with
+//! a fast encoder) are inflated and deflated again at a higher effort.
+//!
+//! Images are handled by the image pass and left alone here; any PNG predictor on
+//! a stream is kept, since the predicted bytes are simply deflated again.
+
+use super::{filters, FlateEffort};
+use lopdf::{Document, Object};
+use std::io::Read;
+
+/// Streams this small cannot gain enough to be worth the effort
+const MIN_STREAM_LEN: usize = 64;
+
+/// Result of the recompression pass
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct RecompressStats {
+    pub streams_recompressed: usize,
+    pub bytes_saved: usize,
+}
+
+/// Re-deflate every Flate-only, non-image stream, keeping the smaller encoding
+pub(crate) fn recompress_streams(doc: &mut Document, effort: FlateEffort) -> RecompressStats {
+    let mut stats = RecompressStats::default();
+    for object in doc.objects.values_mut() {
+        let Object::Stream(stream) = object else { continue };
+        if stream.content.len() < MIN_STREAM_LEN || !is_candidate(&stream.dict) {
+            continue;
+        }
+        // Only streams that inflate cleanly; a truncated stream would lose its tail
+        let mut data = Vec::with_capacity(stream.content.len() * 3);
+        if flate2::read::ZlibDecoder::new(stream.content.as_slice()).read_to_end(&mut data).is_err() {
+            continue;
+        }
+        let recompressed = match effort {
+            FlateEffort::Off => continue,
+            FlateEffort::Best => filters::deflate(&data),
+            FlateEffort::Exhaustive => filters::deflate_exhaustive(&data),
+        };
+        if recompressed.len() < stream.content.len() {
+            stats.streams_recompressed += 1;
+            stats.bytes_saved += stream.content.len() - recompressed.len();
+            stream.set_content(recompressed);
+        }
+    }
+    stats
+}
+
+/// Flate-encoded streams other than images and XMP metadata (which PDF/A wants
+/// left readable)
+fn is_candidate(dict: &lopdf::Dictionary) -> bool {
+    let flate_only = match dict.get(b"Filter") {
+        Ok(Object::Name(name)) => name == b"FlateDecode" || name == b"Fl",
+        Ok(Object::Array(filters)) => matches!(filters.as_slice(), [Object::Name(name)] if name == b"FlateDecode" || name == b"Fl"),
+        _ => false,
+    };
+    let kind = |key: &[u8]| dict.get(key).and_then(Object::as_name).ok();
+    flate_only && kind(b"Subtype") != Some(b"Image") && kind(b"Type") != Some(b"Metadata")
+}
@@ -0,0 +1,139 @@
+//! Per-image report: what the image pass did with each image XObject, and why.
+
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::fmt;
+
+/// What happened to the images of a document
+#[derive(Debug, Clone, Default, serde::Serialize, utoipa::ToSchema)]
+pub struct CompressionReport {
+    pub images: Vec<ImageReport>,
+    /// The output is not smaller than the input but was returned anyway, since it
+    /// holds requested changes (removed metadata, scripts, thumbnails, ...) that
+    /// returning the input would undo
+    pub grew: bool,
+}
+
+/// Outcome for one image XObject. Soft masks are counted with the image they belong to.
+#[derive(Debug, Clone, serde::Serialize, utoipa::ToSchema)]
+pub struct ImageReport {
+    pub object_number: u32,
+    pub generation: u16,
+    pub original_width: u32,
+    pub original_height: u32,
+    /// Same as the original size unless the image was recompressed
+    pub new_width: u32,
+    pub new_height: u32,
+    /// Filters of the original stream, e.g. ["FlateDecode"] or ["DCTDecode"]
+    pub original_filters: Vec<String>,
+    pub new_filters: Vec<String>,
+    /// Color space family of the original image, e.g. "DeviceRGB" or "ICCBased"
+    pub color_space: String,
+    /// Encoded size of the image and its soft mask
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub action: ImageAction,
+    /// Why the image was kept, skipped or failed
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageAction {
+    /// Replaced by a smaller encoding
+    Recompressed,
+    /// Recompressed, but the original was kept because the new encoding was not
+    /// smaller by `min_image_savings`
+    Kept,
+    /// Deliberately left alone (unsupported codec, color key mask)
+    Skipped,
+    /// Could not be decoded or encoded; the original was kept
+    Failed,
+}
+
+/// An image that is left unchanged on purpose rather than because processing
+/// failed. Returned as an error so it travels the same way as failures.
+#[derive(Debug)]
+pub(crate) struct SkipReason(pub String);
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SkipReason {}
+
+impl ImageReport {
+    /// Entry describing the image as it is in `doc`, before any change
+    pub(crate) fn describe(doc: &Document, object_id: ObjectId, bytes: usize) -> ImageReport {
+        let dict = doc.get_object(object_id).and_then(Object::as_stream).map(|s| &s.dict).ok();
+        let dimension = |key: &[u8]| dict.and_then(|d| d.get(key).and_then(Object::as_i64).ok()).unwrap_or(0).max(0) as u32;
+        let (width, height) = (dimension(b"Width"), dimension(b"Height"));
+        let filters = dict.map(filter_names).unwrap_or_default();
+        ImageReport {
+            object_number: object_id.0,
+            generation: object_id.1,
+            original_width: width,
+            original_height: height,
+            new_width: width,
+            new_height: height,
+            new_filters: filters.clone(),
+            original_filters: filters,
+            color_space: dict.map(|d| color_space_family(doc, d)).unwrap_or_default(),
+            bytes_before: bytes as u64,
+            bytes_after: bytes as u64,
+            action: ImageAction::Skipped,
+            reason: None,
+        }
+    }
+
+    /// Record the replacement image written to the document
+    pub(crate) fn recompressed(&mut self, image: &Object, bytes: usize) {
+        if let Ok(stream) = image.as_stream() {
+            let dimension = |key: &[u8]| stream.dict.get(key).and_then(Object::as_i64).unwrap_or(0).max(0) as u32;
+            self.new_width = dimension(b"Width");
+            self.new_height = dimension(b"Height");
+            self.new_filters = filter_names(&stream.dict);
+        }
+        self.bytes_after = bytes as u64;
+        self.action = ImageAction::Recompressed;
+    }
+
+    /// Record that the original was kept, for a skip or a failure given by `error`
+    pub(crate) fn not_processed(&mut self, error: &(dyn std::error::Error + Send + Sync + 'static)) {
+        self.action = if error.downcast_ref::<SkipReason>().is_some() { ImageAction::Skipped } else { ImageAction::Failed };
+        self.reason = Some(error.to_string());
+    }
+
+    /// Record that the new encoding was discarded for `reason`
+    pub(crate) fn kept(&mut self, reason: String) {
+        self.new_width = self.original_width;
+        self.new_height = self.original_height;
+        self.new_filters = self.original_filters.clone();
+        self.bytes_after = self.bytes_before;
+        self.action = ImageAction::Kept;
+        self.reason = Some(reason);
+    }
+}
+
+fn filter_names(dict: &Dictionary) -> Vec<String> {
+    let name = |o: &Object| o.as_name().ok().map(|n| String::from_utf8_lossy(n).into_owned());
+    match dict.get(b"Filter") {
+        Ok(Object::Array(filters)) => filters.iter().filter_map(name).collect(),
+        Ok(filter) => name(filter).into_iter().collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Name of the color space, or the family of an array color space
+fn color_space_family(doc: &Document, dict: &Dictionary) -> String {
+    let Ok((_, color_space)) = dict.get(b"ColorSpace").and_then(|cs| doc.dereference(cs)) else {
+        // Masks and JPX images may have no color space
+        return String::new();
+    };
+    let family = match color_space {
+        Object::Array(items) => items.first().and_then(|f| f.as_name().ok()),
+        other => other.as_name().ok(),
+    };
+    family.map(|f| String::from_utf8_lossy(f).into_owned()).unwrap_or_default()
+}
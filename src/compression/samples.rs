@@ -0,0 +1,58 @@
+/// Unpack 1, 2 or 4 bit samples into one byte per sample.
+///
+/// Every row starts on a byte boundary. With `scale` set, values are stretched
+/// to the full 0-255 range; otherwise they are kept as is (palette indices).
+pub(crate) fn unpack_low_bits(data: &[u8], width: usize, height: usize, components: usize, bits: u8, scale: bool) -> Vec<u8> {
+    let bits = bits as usize;
+    let samples_per_row = width * components;
+    let row_bytes = (samples_per_row * bits).div_ceil(8);
+    let max = (1u16 << bits) - 1;
+    let mut out = Vec::with_capacity(samples_per_row * height);
+    for row in data.chunks(row_bytes).take(height) {
+        for i in 0..samples_per_row {
+            let bit = i * bits;
+            let byte = row.get(bit / 8).copied().unwrap_or(0);
+            let value = (byte >> (8 - bits - bit % 8)) as u16 & max;
+            out.push(if scale { (value * 255 / max) as u8 } else { value as u8 });
+        }
+    }
+    out
+}
+
+/// Read big-endian 16-bit samples
+pub(crate) fn unpack_16_bits(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect()
+}
+
+/// Keep the high byte of every big-endian 16-bit sample
+pub(crate) fn reduce_16_to_8_bits(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(2).map(|pair| pair[0]).collect()
+}
+
+/// Pack one-byte gray samples into 1 bit per pixel rows (MSB first, rows padded
+/// to a byte). Samples of 128 or more become 1.
+pub(crate) fn pack_bilevel(gray: &[u8], width: usize) -> Vec<u8> {
+    let row_bytes = width.div_ceil(8);
+    let mut out = Vec::with_capacity(row_bytes * gray.len() / width.max(1));
+    for row in gray.chunks(width) {
+        let mut packed = vec![0u8; row_bytes];
+        for (x, &value) in row.iter().enumerate() {
+            if value >= 128 {
+                packed[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        out.extend_from_slice(&packed);
+    }
+    out
+}
+
+/// Whether interleaved 8-bit samples use at most `limit` distinct colors
+pub(crate) fn has_few_colors(samples: &[u8], components: usize, limit: usize) -> bool {
+    let mut colors = std::collections::HashSet::new();
+    for px in samples.chunks_exact(components.max(1)) {
+        if colors.insert(px) && colors.len() > limit {
+            return false;
+        }
+    }
+    true
+}
@@ -0,0 +1,182 @@
+//! Target size mode: repeated compression passes with lower JPEG quality, and then
+//! lower resolution, until the output fits the requested size.
+
+use super::{compress_once, CompressionConfig, CompressionError, PassOutput, Progress};
+use rayon::ThreadPool;
+
+type TargetResult<T> = Result<T, CompressionError>;
+
+/// Resolutions tried, relative to the requested `max_dimension` / `target_dpi`,
+/// once the lowest allowed quality no longer fits
+const RESOLUTION_STEPS: [f32; 5] = [1.0, 0.75, 0.5, 0.35, 0.25];
+
+/// Starting point for scaling when `max_dimension` is effectively unlimited
+const UNLIMITED_DIMENSION: u32 = 4096;
+
+/// Search for the highest quality (at the highest resolution) whose output is at
+/// most `target_size` bytes. If nothing fits, the smallest output found is returned.
+///
+/// Returns the output, the settings that produced it and whether it fits.
+pub(crate) fn compress_to_size(
+    input: &[u8],
+    config: CompressionConfig,
+    target_size: u64,
+    pool: Option<&ThreadPool>,
+    progress: &Progress,
+) -> TargetResult<(PassOutput, CompressionConfig, bool)> {
+    let mut search = Search { input, target_size, pool, progress, smallest: None };
+
+    for scale in RESOLUTION_STEPS {
+        let level = scaled(&config, scale);
+        let floor = config.min_jpeg_quality.clamp(1, level.jpeg_quality.max(1));
+
+        let (pass, fits) = search.attempt(&level)?;
+        if fits {
+            return Ok((pass, level, true));
+        }
+        if floor >= level.jpeg_quality {
+            continue;
+        }
+        let lowest = CompressionConfig { jpeg_quality: floor, ..level.clone() };
+        let (pass, fits) = search.attempt(&lowest)?;
+        if !fits {
+            continue;
+        }
+
+        // Binary search between a quality that fits (lo) and one that does not (hi)
+        let (mut lo, mut hi) = (floor, level.jpeg_quality);
+        let mut best = (pass, lowest);
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            let candidate = CompressionConfig { jpeg_quality: mid, ..level.clone() };
+            let (pass, fits) = search.attempt(&candidate)?;
+            if fits {
+                lo = mid;
+                best = (pass, candidate);
+            } else {
+                hi = mid;
+            }
+        }
+        let (pass, final_config) = best;
+        return Ok((pass, final_config, true));
+    }
+
+    let (pass, final_config) = search.smallest.expect("every resolution step runs at least one pass");
+    tracing::info!(target_size, smallest_bytes = pass.data.len(), "Target size not reached");
+    Ok((pass, final_config, false))
+}
+
+struct Search<'a> {
+    input: &'a [u8],
+    target_size: u64,
+    pool: Option<&'a ThreadPool>,
+    progress: &'a Progress,
+    smallest: Option<(PassOutput, CompressionConfig)>,
+}
+
+impl Search<'_> {
+    /// Run one pass, remembering the smallest output seen so far
+    fn attempt(&mut self, config: &CompressionConfig) -> TargetResult<(PassOutput, bool)> {
+        let pass = compress_once(self.input, config, self.pool, self.progress)?;
+        let fits = pass.data.len() as u64 <= self.target_size;
+        tracing::debug!(
+            jpeg_quality = config.jpeg_quality,
+            max_dimension = config.max_dimension,
+            target_dpi = ?config.target_dpi,
+            output_bytes = pass.data.len(),
+            target_size = self.target_size,
+            fits,
+            "Target size pass"
+        );
+        if self.smallest.as_ref().is_none_or(|(smallest, _)| pass.data.len() < smallest.data.len()) {
+            self.smallest = Some((pass.clone(), config.clone()));
+        }
+        Ok((pass, fits))
+    }
+}
+
+/// Settings with the image resolution limits scaled by `scale`
+fn scaled(config: &CompressionConfig, scale: f32) -> CompressionConfig {
+    if scale >= 1.0 {
+        return config.clone();
+    }
+    let max_dimension = config.max_dimension.min(UNLIMITED_DIMENSION) as f32 * scale;
+    CompressionConfig {
+        max_dimension: (max_dimension.round() as u32).max(1),
+        target_dpi: config.target_dpi.map(|dpi| ((dpi as f32 * scale).round() as u32).max(1)),
+        ..config.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{dictionary, Document, Object, Stream};
+
+    /// A one page document showing a 200x200 RGB image of noise, stored raw
+    fn noise_document() -> Vec<u8> {
+        let mut state = 0x2545_F491_u32;
+        let pixels: Vec<u8> = (0..200 * 200 * 3)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let mut doc = Document::with_version("1.5");
+        let image = doc.add_object(Stream::new(
+            dictionary! { "Type" => "XObject", "Subtype" => "Image", "Width" => 200, "Height" => 200, "ColorSpace" => "DeviceRGB", "BitsPerComponent" => 8 },
+            pixels,
+        ));
+        let content = doc.add_object(Stream::new(lopdf::Dictionary::new(), b"q 200 0 0 200 0 0 cm /Im1 Do Q".to_vec()));
+        let pages_id = doc.new_object_id();
+        let page = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), 200.into(), 200.into()],
+            "Contents" => content,
+            "Resources" => dictionary! { "XObject" => dictionary! { "Im1" => image } },
+        });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+        let mut data = Vec::new();
+        doc.save_to(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn unreachable_target_stops_at_the_minimum_quality() {
+        let input = noise_document();
+        let config = CompressionConfig { jpeg_quality: 60, min_jpeg_quality: 20, ..CompressionConfig::default() };
+        let progress = Progress::default();
+
+        let (pass, final_config, reached) = compress_to_size(&input, config.clone(), 1, None, &progress).unwrap();
+
+        assert!(!reached);
+        assert_eq!(final_config.jpeg_quality, 20);
+        assert_eq!(final_config.max_dimension, 150);
+        // Each resolution step tries its quality and the minimum, never anything lower
+        assert_eq!(progress.passes(), RESOLUTION_STEPS.len() * 2);
+        assert!(pass.data.len() < input.len());
+
+        let output = super::super::compress_pdf_with_output(&input, CompressionConfig { target_size: Some(1), ..config }).unwrap();
+        assert_eq!(output.target_reached, Some(false));
+        assert_eq!(output.final_config.jpeg_quality, 20);
+    }
+
+    #[test]
+    fn reachable_target_keeps_the_requested_quality() {
+        let input = noise_document();
+        let config = CompressionConfig { jpeg_quality: 60, ..CompressionConfig::default() };
+        let progress = Progress::default();
+
+        let (pass, final_config, reached) = compress_to_size(&input, config, input.len() as u64, None, &progress).unwrap();
+
+        assert!(reached);
+        assert_eq!(final_config.jpeg_quality, 60);
+        assert_eq!(progress.passes(), 1);
+        assert!(pass.data.len() <= input.len());
+    }
+}
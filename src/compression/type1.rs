@@ -0,0 +1,398 @@
+//! Type 1 font programs (`FontFile`): just enough of the format to list the glyphs
+//! in /CharStrings and drop the ones a document never shows.
+//!
+//! A program is a cleartext part (Length1), an eexec encrypted binary part
+//! (Length2) and a trailer of zeros (Length3). Glyphs are selected by name, so
+//! unused /CharStrings entries are removed outright; /Subrs are kept whole. Glyph
+//! IDs here are indices into /CharStrings in program order.
+
+use super::fonts::standard_encoding_name;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+type Type1Result<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Key of the eexec encryption of the private part
+const EEXEC_KEY: u16 = 55665;
+/// Key of the encryption of each charstring
+const CHARSTRING_KEY: u16 = 4330;
+
+struct Glyph {
+    name: String,
+    /// The whole `/name len RD <bytes> ND` entry in the decrypted private part
+    entry: Range<usize>,
+    /// The encrypted charstring bytes
+    charstring: Range<usize>,
+}
+
+/// How the program maps codes to glyph names when the PDF does not override it
+enum BuiltinEncoding {
+    Standard,
+    Custom(BTreeMap<u8, String>),
+}
+
+pub(crate) struct Type1Program<'a> {
+    cleartext: &'a [u8],
+    /// Decrypted private part, including the four random lead bytes
+    private: Vec<u8>,
+    trailer: &'a [u8],
+    /// Random bytes before each charstring, or None when charstrings are not encrypted
+    len_iv: Option<usize>,
+    glyphs: Vec<Glyph>,
+    encoding: BuiltinEncoding,
+}
+
+impl<'a> Type1Program<'a> {
+    /// Split `data` with the lengths from the stream dictionary and read the glyph
+    /// list. Programs with a hexadecimal eexec part are not supported.
+    pub(crate) fn parse(data: &'a [u8], length1: usize, length2: usize) -> Type1Result<Self> {
+        if length1 + length2 > data.len() || length2 < 4 {
+            return Err("Type 1 program lengths do not match its data".into());
+        }
+        let (cleartext, rest) = data.split_at(length1);
+        let (encrypted, trailer) = rest.split_at(length2);
+        if encrypted[..4].iter().all(u8::is_ascii_hexdigit) {
+            return Err("hexadecimal eexec sections are not supported".into());
+        }
+        let private = decrypt(encrypted, EEXEC_KEY);
+
+        let mut len_iv = Some(4);
+        let mut glyphs = Vec::new();
+        let mut in_char_strings = false;
+        let mut pending: Option<(String, usize, Option<Range<usize>>)> = None;
+        let mut previous: Option<Token> = None;
+        for token in Tokens::new(&private[4..]) {
+            let token = token.offset(4);
+            let text = &private[token.range.clone()];
+            if previous.as_ref().is_some_and(|prev| &private[prev.range.clone()] == b"/lenIV") {
+                // -1 means the charstrings are not encrypted
+                len_iv = parse_number(text).and_then(|n| usize::try_from(n).ok());
+            }
+            if text == b"/CharStrings" {
+                in_char_strings = true;
+            } else if in_char_strings {
+                if let Some(binary) = &token.binary {
+                    if let Some((_, _, charstring)) = &mut pending {
+                        *charstring = Some(binary.clone());
+                    }
+                } else if text.starts_with(b"/") {
+                    pending = Some((String::from_utf8_lossy(&text[1..]).into_owned(), token.range.start, None));
+                } else if matches!(text, b"ND" | b"|-" | b"def") {
+                    if let Some((name, start, Some(charstring))) = pending.take() {
+                        glyphs.push(Glyph { name, entry: start..token.range.end, charstring });
+                    }
+                } else if text == b"end" && pending.is_none() {
+                    break;
+                }
+            }
+            previous = Some(token);
+        }
+        if glyphs.is_empty() {
+            return Err("Type 1 program has no /CharStrings".into());
+        }
+
+        Ok(Type1Program { cleartext, private, trailer, len_iv, glyphs, encoding: builtin_encoding(cleartext) })
+    }
+
+    pub(crate) fn glyph_index_by_name(&self, name: &str) -> Option<u16> {
+        self.glyphs.iter().position(|glyph| glyph.name == name).and_then(|i| u16::try_from(i).ok())
+    }
+
+    /// The glyph the program's own /Encoding gives `code`
+    pub(crate) fn glyph_index(&self, code: u8) -> Option<u16> {
+        match &self.encoding {
+            BuiltinEncoding::Standard => self.glyph_index_by_name(standard_encoding_name(code)?),
+            BuiltinEncoding::Custom(names) => self.glyph_index_by_name(names.get(&code)?),
+        }
+    }
+
+    /// The program with only `glyphs`, .notdef and the glyphs accented glyphs
+    /// (`seac`) are built from. Returns the data and its Length1, Length2 and
+    /// Length3.
+    pub(crate) fn subset(&self, glyphs: &BTreeSet<u16>) -> (Vec<u8>, [usize; 3]) {
+        let mut kept: BTreeSet<usize> = glyphs.iter().map(|&gid| gid as usize).collect();
+        kept.extend(self.glyph_index_by_name(".notdef").map(usize::from));
+        let components: Vec<usize> = kept
+            .iter()
+            .filter_map(|&i| self.glyphs.get(i))
+            .filter_map(|glyph| self.seac_components(glyph))
+            .flatten()
+            .filter_map(|code| standard_encoding_name(code).and_then(|name| self.glyph_index_by_name(name)))
+            .map(usize::from)
+            .collect();
+        kept.extend(components);
+
+        let mut private = Vec::with_capacity(self.private.len());
+        let mut pos = 0;
+        for (i, glyph) in self.glyphs.iter().enumerate() {
+            if !kept.contains(&i) {
+                private.extend_from_slice(&self.private[pos..glyph.entry.start]);
+                pos = glyph.entry.end;
+            }
+        }
+        private.extend_from_slice(&self.private[pos..]);
+
+        let encrypted = encrypt(&private, EEXEC_KEY);
+        let lengths = [self.cleartext.len(), encrypted.len(), self.trailer.len()];
+        ([self.cleartext, &encrypted, self.trailer].concat(), lengths)
+    }
+
+    /// Standard codes of the base and accent glyph, if the glyph is a `seac` composite
+    fn seac_components(&self, glyph: &Glyph) -> Option<[u8; 2]> {
+        let encrypted = &self.private[glyph.charstring.clone()];
+        let charstring = match self.len_iv {
+            Some(skip) => decrypt(encrypted, CHARSTRING_KEY).get(skip..)?.to_vec(),
+            None => encrypted.to_vec(),
+        };
+        let mut stack: Vec<i32> = Vec::new();
+        let mut i = 0;
+        while i < charstring.len() {
+            let v = charstring[i];
+            match v {
+                32..=246 => stack.push(v as i32 - 139),
+                247..=250 => {
+                    stack.push((v as i32 - 247) * 256 + *charstring.get(i + 1)? as i32 + 108);
+                    i += 1;
+                }
+                251..=254 => {
+                    stack.push(-(v as i32 - 251) * 256 - *charstring.get(i + 1)? as i32 - 108);
+                    i += 1;
+                }
+                255 => {
+                    stack.push(i32::from_be_bytes(charstring.get(i + 1..i + 5)?.try_into().ok()?));
+                    i += 4;
+                }
+                12 => {
+                    if charstring.get(i + 1) == Some(&6) {
+                        let achar = u8::try_from(stack.pop()?).ok()?;
+                        let bchar = u8::try_from(stack.pop()?).ok()?;
+                        return Some([bchar, achar]);
+                    }
+                    stack.clear();
+                    i += 1;
+                }
+                _ => stack.clear(),
+            }
+            i += 1;
+        }
+        None
+    }
+}
+
+/// The /Encoding of the cleartext part: StandardEncoding or `dup code /name put` entries
+fn builtin_encoding(cleartext: &[u8]) -> BuiltinEncoding {
+    let tokens: Vec<&[u8]> = Tokens::new(cleartext).map(|token| &cleartext[token.range]).collect();
+    if tokens.windows(2).any(|pair| pair == [&b"/Encoding"[..], b"StandardEncoding"]) {
+        return BuiltinEncoding::Standard;
+    }
+    let mut names = BTreeMap::new();
+    for window in tokens.windows(4) {
+        if let [b"dup", code, name, b"put"] = window {
+            if let (Some(code), Some(name)) = (parse_number(code).and_then(|c| u8::try_from(c).ok()), name.strip_prefix(b"/")) {
+                names.insert(code, String::from_utf8_lossy(name).into_owned());
+            }
+        }
+    }
+    BuiltinEncoding::Custom(names)
+}
+
+fn parse_number(token: &[u8]) -> Option<i64> {
+    std::str::from_utf8(token).ok()?.parse().ok()
+}
+
+fn decrypt(data: &[u8], key: u16) -> Vec<u8> {
+    let mut r = key;
+    data.iter()
+        .map(|&cipher| {
+            let plain = cipher ^ (r >> 8) as u8;
+            r = (cipher as u16).wrapping_add(r).wrapping_mul(52845).wrapping_add(22719);
+            plain
+        })
+        .collect()
+}
+
+fn encrypt(data: &[u8], key: u16) -> Vec<u8> {
+    let mut r = key;
+    data.iter()
+        .map(|&plain| {
+            let cipher = plain ^ (r >> 8) as u8;
+            r = (cipher as u16).wrapping_add(r).wrapping_mul(52845).wrapping_add(22719);
+            cipher
+        })
+        .collect()
+}
+
+/// A PostScript token, with the binary data that follows `RD`/`-|`
+struct Token {
+    range: Range<usize>,
+    binary: Option<Range<usize>>,
+}
+
+impl Token {
+    fn offset(self, by: usize) -> Token {
+        Token {
+            range: self.range.start + by..self.range.end + by,
+            binary: self.binary.map(|b| b.start + by..b.end + by),
+        }
+    }
+}
+
+/// Tokens of PostScript source with binary `len RD <bytes>` sections skipped.
+/// Strings and comments are skipped; a binary section is reported on its RD token.
+struct Tokens<'a> {
+    data: &'a [u8],
+    pos: usize,
+    last_number: Option<usize>,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Tokens { data, pos: 0, last_number: None }
+    }
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        let data = self.data;
+        let is_delimiter = |b: u8| b.is_ascii_whitespace() || b"()<>[]{}/%".contains(&b);
+        loop {
+            while data.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+                self.pos += 1;
+            }
+            match *data.get(self.pos)? {
+                b'%' => {
+                    while data.get(self.pos).is_some_and(|&b| b != b'\n' && b != b'\r') {
+                        self.pos += 1;
+                    }
+                }
+                b'(' => {
+                    let mut depth = 0;
+                    while let Some(&b) = data.get(self.pos) {
+                        self.pos += 1;
+                        match b {
+                            b'\\' => self.pos += 1,
+                            b'(' => depth += 1,
+                            b')' => {
+                                depth -= 1;
+                                if depth == 0 {
+                                    break;
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                    self.last_number = None;
+                }
+                _ => break,
+            }
+        }
+
+        let start = self.pos;
+        if b"[]{}<>".contains(&data[start]) {
+            self.pos += 1;
+        } else {
+            self.pos += 1;
+            while data.get(self.pos).is_some_and(|&b| !is_delimiter(b)) {
+                self.pos += 1;
+            }
+        }
+        let end = self.pos;
+        let text = &data[start..end];
+
+        let mut binary = None;
+        if matches!(text, b"RD" | b"-|") {
+            if let Some(length) = self.last_number {
+                // Exactly one space separates the token from the binary data
+                let begin = (self.pos + 1).min(data.len());
+                let end = (begin + length).min(data.len());
+                binary = Some(begin..end);
+                self.pos = end;
+            }
+        }
+        self.last_number = std::str::from_utf8(text).ok().and_then(|t| t.parse().ok());
+        Some(Token { range: start..end, binary })
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    fn charstring(plain: &[u8]) -> Vec<u8> {
+        encrypt(&[&[0, 0, 0, 0][..], plain].concat(), CHARSTRING_KEY)
+    }
+
+    /// A program with glyphs .notdef, A, B, Aacute (A with B as accent, through
+    /// seac) and D, and a subroutine that must survive subsetting
+    pub(in crate::compression) fn program() -> (Vec<u8>, usize, usize) {
+        let cleartext = b"%!FontType1-1.0: Test\n/FontName /Test def\n/Encoding StandardEncoding def\ncurrentfile eexec\n".to_vec();
+        // 13 is hsbw, 14 endchar, 12 6 seac; numbers are stored as value + 139
+        let glyphs: [(&str, Vec<u8>); 5] = [
+            (".notdef", charstring(&[139, 139, 13, 14])),
+            ("A", charstring(&[139, 239, 13, 14])),
+            ("B", charstring(&[139, 189, 13, 14])),
+            ("Aacute", charstring(&[139, 139, 139, 204, 205, 12, 6])),
+            ("D", charstring(&[139, 229, 13, 14])),
+        ];
+        let subr = charstring(&[11]);
+        let mut private = b"\0\0\0\0dup /Private 8 dict dup begin\n/RD{string currentfile exch readstring pop}executeonly def\n/ND{noaccess def}executeonly def\n/NP{noaccess put}executeonly def\n/lenIV 4 def\n/Subrs 1 array\n".to_vec();
+        private.extend_from_slice(format!("dup 0 {} RD ", subr.len()).as_bytes());
+        private.extend_from_slice(&subr);
+        private.extend_from_slice(b" NP\nND\n2 index /CharStrings 5 dict dup begin\n");
+        for (name, data) in &glyphs {
+            private.extend_from_slice(format!("/{} {} RD ", name, data.len()).as_bytes());
+            private.extend_from_slice(data);
+            private.extend_from_slice(b" ND\n");
+        }
+        private.extend_from_slice(b"end\nend\nreadonly put\nnoaccess put\ndup/FontName get exch definefont pop\nmark currentfile closefile\n");
+        let encrypted = encrypt(&private, EEXEC_KEY);
+        let trailer = [&[b'0'; 64][..], b"\ncleartomark\n"].concat();
+        let (length1, length2) = (cleartext.len(), encrypted.len());
+        ([cleartext, encrypted, trailer].concat(), length1, length2)
+    }
+
+    fn names<'a>(program: &'a Type1Program) -> Vec<&'a str> {
+        program.glyphs.iter().map(|glyph| glyph.name.as_str()).collect()
+    }
+
+    #[test]
+    fn glyphs_and_encoding_are_read() {
+        let (data, length1, length2) = program();
+        let program = Type1Program::parse(&data, length1, length2).unwrap();
+        assert_eq!(names(&program), [".notdef", "A", "B", "Aacute", "D"]);
+        assert_eq!(program.len_iv, Some(4));
+        assert_eq!(program.glyph_index(b'B'), Some(2));
+        assert_eq!(program.glyph_index_by_name("D"), Some(4));
+    }
+
+    #[test]
+    fn unused_glyphs_are_removed() {
+        let (data, length1, length2) = program();
+        let original = Type1Program::parse(&data, length1, length2).unwrap();
+        let (subset, [l1, l2, l3]) = original.subset(&BTreeSet::from([2]));
+        assert_eq!(l1 + l2 + l3, subset.len());
+        assert!(subset.len() < data.len());
+        let subset = Type1Program::parse(&subset, l1, l2).unwrap();
+        assert_eq!(names(&subset), [".notdef", "B"]);
+        assert_eq!(subset.private[subset.glyphs[1].charstring.clone()], original.private[original.glyphs[2].charstring.clone()]);
+        // The subroutine is still there
+        assert!(subset.private.windows(10).any(|w| w == b"dup 0 5 RD"));
+    }
+
+    #[test]
+    fn seac_components_are_kept() {
+        let (data, length1, length2) = program();
+        let original = Type1Program::parse(&data, length1, length2).unwrap();
+        let (subset, [l1, l2, _]) = original.subset(&BTreeSet::from([3]));
+        let subset = Type1Program::parse(&subset, l1, l2).unwrap();
+        assert_eq!(names(&subset), [".notdef", "A", "B", "Aacute"]);
+    }
+
+    #[test]
+    fn hexadecimal_programs_are_rejected() {
+        let data = b"%!FontType1\ncurrentfile eexec\nD9D66F633B846AB2";
+        assert!(Type1Program::parse(data, 30, 16).is_err());
+    }
+}
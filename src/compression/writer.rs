@@ -0,0 +1,321 @@
+//! PDF 1.5 output: non-stream objects are packed into compressed object streams
+//! (/ObjStm) and the cross-reference table is written as a compressed stream.
+//!
+//! lopdf only writes classic xref tables with every object at the top level, which
+//! costs a lot in documents made of many small dictionaries (forms, outlines,
+//! annotations, fonts).
+
+use super::{filters, FlateEffort};
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream, StringFormat};
+use std::io::Write;
+
+type WriteResult<T> = std::io::Result<T>;
+
+/// Objects per object stream; readers have to inflate a whole stream to get one object
+const OBJECTS_PER_STREAM: usize = 100;
+
+/// Types lopdf keeps from the input file that are rebuilt on save
+const SKIPPED_TYPES: [&[u8]; 2] = [b"ObjStm", b"XRef"];
+
+/// Trailer keys that describe the old cross-reference section
+const XREF_KEYS: [&[u8]; 11] =
+    [b"Size", b"Prev", b"XRefStm", b"Type", b"W", b"Index", b"Filter", b"DecodeParms", b"Length", b"DL", b"F"];
+
+/// Where an object ended up in the output
+#[derive(Clone, Copy)]
+enum XrefEntry {
+    Free,
+    Offset(usize, u16),
+    Compressed(u32, usize),
+}
+
+/// Serialize `doc` with object streams and a cross-reference stream. The document
+/// must not be encrypted, since object streams would need encrypting too.
+pub(crate) fn save_with_object_streams(doc: &Document, effort: FlateEffort) -> WriteResult<Vec<u8>> {
+    let version = match doc.version.parse::<f32>() {
+        Ok(version) if version >= 1.5 => doc.version.clone(),
+        _ => "1.5".to_string(),
+    };
+    let mut out = Vec::new();
+    writeln!(out, "%PDF-{}", version)?;
+    // Binary comment so transfer tools treat the file as binary
+    out.extend_from_slice(b"%\xE2\xE3\xCF\xD3\n");
+
+    let objects: Vec<(ObjectId, &Object)> = doc
+        .objects
+        .iter()
+        .filter(|(_, object)| !is_skipped(object))
+        .map(|(id, object)| (*id, object))
+        .collect();
+    // Objects with a non-zero generation cannot be addressed inside object streams
+    let (packed, top_level): (Vec<_>, Vec<_>) =
+        objects.into_iter().partition(|(id, object)| !matches!(object, Object::Stream(_)) && id.1 == 0);
+
+    let mut next_id = doc.objects.keys().map(|id| id.0).max().unwrap_or(0) + 1;
+    let mut entries: Vec<(u32, XrefEntry)> = Vec::new();
+
+    for (id, object) in top_level {
+        entries.push((id.0, XrefEntry::Offset(out.len(), id.1)));
+        write_indirect(&mut out, id, object)?;
+    }
+
+    for chunk in packed.chunks(OBJECTS_PER_STREAM) {
+        let stream_id = next_id;
+        next_id += 1;
+        let mut header = Vec::new();
+        let mut body = Vec::new();
+        for (index, (id, object)) in chunk.iter().enumerate() {
+            write!(header, "{} {} ", id.0, body.len())?;
+            write_object(&mut body, object)?;
+            body.push(b'\n');
+            entries.push((id.0, XrefEntry::Compressed(stream_id, index)));
+        }
+        let first = header.len();
+        header.extend_from_slice(&body);
+
+        let mut dict = Dictionary::new();
+        dict.set("Type", Object::Name(b"ObjStm".to_vec()));
+        dict.set("N", Object::Integer(chunk.len() as i64));
+        dict.set("First", Object::Integer(first as i64));
+        dict.set("Filter", Object::Name(b"FlateDecode".to_vec()));
+        let compressed = match effort {
+            FlateEffort::Exhaustive => filters::deflate_exhaustive(&header),
+            FlateEffort::Off | FlateEffort::Best => filters::deflate(&header),
+        };
+        let stream = Stream::new(dict, compressed);
+        entries.push((stream_id, XrefEntry::Offset(out.len(), 0)));
+        write_indirect(&mut out, (stream_id, 0), &Object::Stream(stream))?;
+    }
+
+    // The cross-reference stream lists itself as well
+    let xref_id = next_id;
+    let xref_offset = out.len();
+    entries.push((xref_id, XrefEntry::Offset(xref_offset, 0)));
+    let size = xref_id + 1;
+    let mut table = vec![XrefEntry::Free; size as usize];
+    for (number, entry) in entries {
+        table[number as usize] = entry;
+    }
+
+    let offset_bytes = byte_width(out.len().max(table.len()) as u64);
+    let widths = [1, offset_bytes, 2];
+    let row_bytes: usize = widths.iter().sum();
+    let mut rows = Vec::with_capacity(table.len() * row_bytes);
+    for (number, entry) in table.iter().enumerate() {
+        let (kind, field2, field3) = match *entry {
+            // Object 0 heads the free list with generation 65535
+            XrefEntry::Free => (0, 0, if number == 0 { 0xFFFF } else { 0 }),
+            XrefEntry::Offset(offset, generation) => (1, offset as u64, generation as u64),
+            XrefEntry::Compressed(stream, index) => (2, stream as u64, index as u64),
+        };
+        rows.push(kind);
+        rows.extend_from_slice(&field2.to_be_bytes()[8 - offset_bytes..]);
+        rows.extend_from_slice(&(field3 as u16).to_be_bytes());
+    }
+
+    let mut dict = Dictionary::new();
+    for (key, value) in doc.trailer.iter() {
+        if !XREF_KEYS.contains(&key.as_slice()) {
+            dict.set(key.clone(), value.clone());
+        }
+    }
+    dict.set("Type", Object::Name(b"XRef".to_vec()));
+    dict.set("Size", Object::Integer(size as i64));
+    dict.set("W", Object::Array(widths.iter().map(|&w| Object::Integer(w as i64)).collect()));
+    dict.set("Filter", Object::Name(b"FlateDecode".to_vec()));
+    dict.set(
+        "DecodeParms",
+        Object::Dictionary(Dictionary::from_iter(vec![
+            ("Predictor", Object::Integer(12)),
+            ("Columns", Object::Integer(row_bytes as i64)),
+        ])),
+    );
+    let content = filters::deflate(&up_predict(&rows, row_bytes));
+    write_indirect(&mut out, (xref_id, 0), &Object::Stream(Stream::new(dict, content)))?;
+
+    write!(out, "startxref\n{}\n%%EOF\n", xref_offset)?;
+    Ok(out)
+}
+
+/// Cross-reference structures of the input, and its linearization parameters, which
+/// have no /Type and no longer describe the file once it is rewritten
+fn is_skipped(object: &Object) -> bool {
+    object.type_name().is_ok_and(|t| SKIPPED_TYPES.contains(&t.as_bytes()))
+        || object.as_dict().is_ok_and(|dict| dict.has(b"Linearized"))
+}
+
+/// PNG "Up" prediction on every row, as Predictor 12 declares. Mixing filter types
+/// per row is allowed, but some readers (lopdf among them) decode Average wrongly.
+fn up_predict(data: &[u8], row_bytes: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / row_bytes);
+    let mut previous = vec![0u8; row_bytes];
+    for row in data.chunks(row_bytes) {
+        out.push(2);
+        out.extend(row.iter().zip(&previous).map(|(current, above)| current.wrapping_sub(*above)));
+        previous.copy_from_slice(row);
+    }
+    out
+}
+
+/// Fewest bytes that can hold `value`
+fn byte_width(value: u64) -> usize {
+    ((64 - value.leading_zeros() as usize).div_ceil(8)).max(1)
+}
+
+fn write_indirect(out: &mut Vec<u8>, id: ObjectId, object: &Object) -> WriteResult<()> {
+    writeln!(out, "{} {} obj", id.0, id.1)?;
+    write_object(out, object)?;
+    out.extend_from_slice(b"\nendobj\n");
+    Ok(())
+}
+
+fn write_object(out: &mut Vec<u8>, object: &Object) -> WriteResult<()> {
+    match object {
+        Object::Null => out.extend_from_slice(b"null"),
+        Object::Boolean(value) => out.extend_from_slice(if *value { b"true" } else { b"false" }),
+        Object::Integer(value) => write!(out, "{}", value)?,
+        Object::Real(value) => write!(out, "{}", value)?,
+        Object::Name(name) => write_name(out, name),
+        Object::String(text, format) => write_string(out, text, format),
+        Object::Array(items) => {
+            out.push(b'[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(b' ');
+                }
+                write_object(out, item)?;
+            }
+            out.push(b']');
+        }
+        Object::Dictionary(dict) => write_dictionary(out, dict)?,
+        Object::Stream(stream) => {
+            let mut dict = stream.dict.clone();
+            dict.set("Length", Object::Integer(stream.content.len() as i64));
+            write_dictionary(out, &dict)?;
+            out.extend_from_slice(b"stream\n");
+            out.extend_from_slice(&stream.content);
+            out.extend_from_slice(b"\nendstream");
+        }
+        Object::Reference(id) => write!(out, "{} {} R", id.0, id.1)?,
+    }
+    Ok(())
+}
+
+fn write_dictionary(out: &mut Vec<u8>, dict: &Dictionary) -> WriteResult<()> {
+    out.extend_from_slice(b"<<");
+    for (key, value) in dict.iter() {
+        write_name(out, key);
+        out.push(b' ');
+        write_object(out, value)?;
+    }
+    out.extend_from_slice(b">>");
+    Ok(())
+}
+
+fn write_name(out: &mut Vec<u8>, name: &[u8]) {
+    out.push(b'/');
+    for &byte in name {
+        // Delimiters, whitespace and anything outside printable ASCII are written as #XX
+        if b" \t\n\r\x0C()<>[]{}/%#".contains(&byte) || !(33..=126).contains(&byte) {
+            out.extend_from_slice(format!("#{:02X}", byte).as_bytes());
+        } else {
+            out.push(byte);
+        }
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, text: &[u8], format: &StringFormat) {
+    match format {
+        StringFormat::Literal => {
+            out.push(b'(');
+            for &byte in text {
+                match byte {
+                    b'(' | b')' | b'\\' => out.extend_from_slice(&[b'\\', byte]),
+                    b'\r' => out.extend_from_slice(b"\\r"),
+                    _ => out.push(byte),
+                }
+            }
+            out.push(b')');
+        }
+        StringFormat::Hexadecimal => {
+            out.push(b'<');
+            for &byte in text {
+                out.extend_from_slice(format!("{:02X}", byte).as_bytes());
+            }
+            out.push(b'>');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{dictionary, xref};
+
+    /// A document with a linearization dictionary, a content stream, an object with
+    /// a non-zero generation and enough small dictionaries to fill two object streams
+    fn document() -> (Document, ObjectId, ObjectId, ObjectId) {
+        let mut doc = Document::with_version("1.4");
+        doc.add_object(dictionary! { "Linearized" => 1, "L" => 1000, "N" => 1 });
+        let content = doc.add_object(Stream::new(Dictionary::new(), b"0 0 m 10 10 l S".to_vec()));
+        let annots: Vec<Object> = (0..OBJECTS_PER_STREAM)
+            .map(|i| doc.add_object(dictionary! { "Type" => "Annot", "Subtype" => "Text", "Contents" => Object::string_literal(format!("Note {}", i)) }).into())
+            .collect();
+        let reused = (doc.max_id + 1, 2);
+        doc.max_id += 1;
+        doc.objects.insert(reused, Object::Dictionary(dictionary! { "Producer" => Object::string_literal("test") }));
+        doc.trailer.set("Info", reused);
+        let pages_id = doc.new_object_id();
+        let page = doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id, "Contents" => content, "Annots" => annots });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+        (doc, content, reused, page)
+    }
+
+    #[test]
+    fn saved_documents_load_with_every_object() {
+        let (doc, _, _, _) = document();
+        let saved = save_with_object_streams(&doc, FlateEffort::Best).unwrap();
+        let loaded = Document::load_mem(&saved).unwrap();
+
+        assert_eq!(loaded.version, "1.5");
+        for (id, object) in &doc.objects {
+            if object.as_dict().is_ok_and(|dict| dict.has(b"Linearized")) {
+                assert!(!loaded.objects.contains_key(id));
+                continue;
+            }
+            let copy = loaded.get_object(*id).unwrap();
+            match (object, copy) {
+                (Object::Stream(original), Object::Stream(copy)) => assert_eq!(original.content, copy.content),
+                (Object::Dictionary(original), Object::Dictionary(copy)) => assert!(original == copy, "object {:?} changed", id),
+                _ => panic!("object {:?} changed kind", id),
+            }
+        }
+        assert_eq!(loaded.trailer.get(b"Root").unwrap(), doc.trailer.get(b"Root").unwrap());
+    }
+
+    #[test]
+    fn every_object_is_listed_in_the_cross_reference_stream() {
+        let (doc, content, reused, page) = document();
+        let saved = save_with_object_streams(&doc, FlateEffort::Best).unwrap();
+        let loaded = Document::load_mem(&saved).unwrap();
+
+        for (id, object) in &doc.objects {
+            if object.as_dict().is_ok_and(|dict| dict.has(b"Linearized")) {
+                continue;
+            }
+            match loaded.reference_table.get(id.0) {
+                Some(xref::XrefEntry::Normal { generation, .. }) => assert_eq!(*generation, id.1),
+                Some(xref::XrefEntry::Compressed { .. }) => assert_eq!(id.1, 0),
+                other => panic!("object {:?} has cross-reference entry {:?}", id, other),
+            }
+        }
+        // Streams and objects with a non-zero generation stay at the top level
+        assert!(matches!(loaded.reference_table.get(content.0), Some(xref::XrefEntry::Normal { .. })));
+        assert!(matches!(loaded.reference_table.get(reused.0), Some(xref::XrefEntry::Normal { generation: 2, .. })));
+        assert!(matches!(loaded.reference_table.get(page.0), Some(xref::XrefEntry::Compressed { .. })));
+        let containers = loaded.objects.values().filter(|o| o.type_name().is_ok_and(|t| t == "ObjStm")).count();
+        assert_eq!(containers, 2);
+    }
+}
@@ -1,5 +1,11 @@
-use tonic::{Request, Response, Status};
-use crate::compression::{compress_pdf_with_config, CompressionConfig};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use crate::compression::{
+    compress_pdf_with_output, lossless_mode, CmykHandling, CompressionConfig, CompressionError, CompressionOutput, CompressionPreset,
+    FlateEffort, ImageAction, ImageReport, MetadataMode,
+};
+use crate::jobs::{JobResult, JobState, JobStatus, JobStore};
+use std::sync::Arc;
 
 // Import the generated proto code
 pub mod pb {
@@ -8,10 +14,287 @@ pub mod pb {
 
 use pb::compression_service_server::CompressionService;
 pub use pb::compression_service_server::CompressionServiceServer;
-use pb::{CompressRequest, CompressResponse};
+use pb::{compress_stream_request, compress_stream_response, CompressRequest, CompressResponse, CompressStreamRequest, CompressStreamResponse};
+
+/// Size of the chunks the compressed PDF is streamed back in
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+/// Largest PDF accepted by any RPC. Unary requests are also bound by the server's
+/// message size limit (4 MB unless raised)
+const MAX_INPUT_SIZE: usize = 512 * 1024 * 1024;
 
 #[derive(Debug, Default)]
-pub struct HelperService;
+pub struct HelperService {
+    jobs: Arc<JobStore>,
+}
+
+impl HelperService {
+    /// Service whose job methods use `jobs`, which may be shared with the REST API
+    pub fn new(jobs: Arc<JobStore>) -> Self {
+        HelperService { jobs }
+    }
+}
+
+impl From<CompressionError> for Status {
+    fn from(error: CompressionError) -> Self {
+        error_status(&error)
+    }
+}
+
+fn error_status(error: &CompressionError) -> Status {
+    let message = error.to_string();
+    match error {
+        CompressionError::MalformedPdf(_) => Status::invalid_argument(message),
+        CompressionError::Encrypted | CompressionError::Unsupported(_) => Status::failed_precondition(message),
+        CompressionError::ResourceLimit(_) => Status::resource_exhausted(message),
+        CompressionError::Io(_) => Status::internal(message),
+        CompressionError::Cancelled => Status::cancelled(message),
+    }
+}
+
+/// Start from the requested preset (or defaults) and apply every field that was set
+fn config_from_proto(proto_config: pb::CompressionConfig) -> CompressionConfig {
+    let preset = match proto_config.preset() {
+        pb::CompressionPreset::Unspecified => None,
+        pb::CompressionPreset::Screen => Some(CompressionPreset::Screen),
+        pb::CompressionPreset::Ebook => Some(CompressionPreset::Ebook),
+        pb::CompressionPreset::Printer => Some(CompressionPreset::Printer),
+        pb::CompressionPreset::Prepress => Some(CompressionPreset::Prepress),
+        pb::CompressionPreset::Archive => Some(CompressionPreset::Archive),
+    };
+    let mut config = preset.map(CompressionPreset::config).unwrap_or_default();
+    if let Some(quality) = proto_config.jpeg_quality {
+        config.jpeg_quality = quality.clamp(1, 100) as u8;
+    }
+    if let Some(max_dimension) = proto_config.max_dimension {
+        config.max_dimension = max_dimension;
+    }
+    if let Some(remove_metadata) = proto_config.remove_metadata {
+        config.metadata = if remove_metadata { MetadataMode::Strip } else { MetadataMode::Preserve };
+    }
+    match proto_config.metadata_mode() {
+        pb::MetadataMode::Unspecified => {}
+        pb::MetadataMode::Strip => config.metadata = MetadataMode::Strip,
+        pb::MetadataMode::Preserve => config.metadata = MetadataMode::Preserve,
+        pb::MetadataMode::Rewrite => config.metadata = MetadataMode::Rewrite,
+    }
+    if !proto_config.keep_info_keys.is_empty() {
+        config.keep_info_keys = proto_config.keep_info_keys.clone();
+    }
+    if proto_config.title.is_some() {
+        config.title = proto_config.title.clone();
+    }
+    if proto_config.author.is_some() {
+        config.author = proto_config.author.clone();
+    }
+    if proto_config.producer.is_some() {
+        config.producer = proto_config.producer.clone();
+    }
+    if let Some(preserve_cmyk) = proto_config.preserve_cmyk {
+        config.cmyk_handling = if preserve_cmyk { CmykHandling::Preserve } else { CmykHandling::ConvertToRgb };
+    }
+    if let Some(min_image_savings) = proto_config.min_image_savings {
+        config.min_image_savings = min_image_savings;
+    }
+    if proto_config.lossless.is_some() {
+        config.lossless = lossless_mode(proto_config.lossless);
+    }
+    if let Some(threshold_grayscale) = proto_config.threshold_grayscale {
+        config.threshold_grayscale = threshold_grayscale;
+    }
+    if proto_config.target_dpi.is_some() {
+        config.target_dpi = proto_config.target_dpi;
+    }
+    if proto_config.target_size.is_some() {
+        config.target_size = proto_config.target_size;
+    }
+    if proto_config.target_ratio.is_some() {
+        config.target_ratio = proto_config.target_ratio;
+    }
+    if let Some(min_quality) = proto_config.min_jpeg_quality {
+        config.min_jpeg_quality = min_quality.clamp(1, 100) as u8;
+    }
+    if let Some(subset_fonts) = proto_config.subset_fonts {
+        config.subset_fonts = subset_fonts;
+    }
+    if let Some(deduplicate) = proto_config.deduplicate {
+        config.deduplicate = deduplicate;
+    }
+    if let Some(object_streams) = proto_config.object_streams {
+        config.object_streams = object_streams;
+    }
+    if let Some(optimize_content) = proto_config.optimize_content {
+        config.optimize_content = optimize_content;
+    }
+    if let Some(content_decimals) = proto_config.content_decimals {
+        config.content_decimals = content_decimals.min(6) as u8;
+    }
+    match proto_config.flate_effort() {
+        pb::FlateEffort::Unspecified => {}
+        pb::FlateEffort::Off => config.flate_effort = FlateEffort::Off,
+        pb::FlateEffort::Best => config.flate_effort = FlateEffort::Best,
+        pb::FlateEffort::Exhaustive => config.flate_effort = FlateEffort::Exhaustive,
+    }
+    if let Some(thumbnails) = proto_config.remove_thumbnails {
+        config.cleanup.thumbnails = thumbnails;
+    }
+    if let Some(unused_resources) = proto_config.remove_unused_resources {
+        config.cleanup.unused_resources = unused_resources;
+    }
+    if let Some(piece_info) = proto_config.remove_piece_info {
+        config.cleanup.piece_info = piece_info;
+    }
+    if let Some(javascript) = proto_config.remove_javascript {
+        config.cleanup.javascript = javascript;
+    }
+    if let Some(orphan_forms) = proto_config.remove_orphan_forms {
+        config.cleanup.orphan_forms = orphan_forms;
+    }
+    if let Some(threads) = proto_config.threads.filter(|threads| *threads > 0) {
+        config.threads = Some(threads as usize);
+    }
+    config
+}
+
+fn image_report_to_proto(image: &ImageReport) -> pb::ImageReport {
+    let action = match image.action {
+        ImageAction::Recompressed => pb::ImageAction::Recompressed,
+        ImageAction::Kept => pb::ImageAction::Kept,
+        ImageAction::Skipped => pb::ImageAction::Skipped,
+        ImageAction::Failed => pb::ImageAction::Failed,
+    };
+    pb::ImageReport {
+        object_number: image.object_number,
+        generation: image.generation as u32,
+        original_width: image.original_width,
+        original_height: image.original_height,
+        new_width: image.new_width,
+        new_height: image.new_height,
+        original_filters: image.original_filters.clone(),
+        new_filters: image.new_filters.clone(),
+        color_space: image.color_space.clone(),
+        bytes_before: image.bytes_before,
+        bytes_after: image.bytes_after,
+        action: action as i32,
+        reason: image.reason.clone(),
+    }
+}
+
+fn job_status_to_proto(status: JobStatus) -> pb::JobStatus {
+    let state = match status.state {
+        JobState::Queued => pb::JobState::Queued,
+        JobState::Running => pb::JobState::Running,
+        JobState::Completed => pb::JobState::Completed,
+        JobState::Failed => pb::JobState::Failed,
+    };
+    pb::JobStatus {
+        id: status.id,
+        state: state as i32,
+        images_processed: status.images_processed as u64,
+        images_total: status.images_total as u64,
+        passes: status.passes as u32,
+        original_size: status.original_size,
+        compressed_size: status.compressed_size,
+        error: status.error,
+    }
+}
+
+fn compression_ratio(original_size: u64, compressed_size: u64) -> f32 {
+    if original_size > 0 {
+        ((original_size as f64 - compressed_size as f64) / original_size as f64) as f32 * 100.0
+    } else {
+        0.0
+    }
+}
+
+/// Run the compression on a blocking thread
+async fn compress(pdf_data: Vec<u8>, config: CompressionConfig) -> Result<CompressionOutput, Status> {
+    let original_size = pdf_data.len() as u64;
+
+    // Call the compression logic
+    // This is CPU intensive, so we spawn_blocking
+    let output = match tokio::task::spawn_blocking(move || {
+        compress_pdf_with_output(&pdf_data, config)
+    }).await {
+         Ok(Ok(output)) => output,
+         Ok(Err(e)) => return Err(e.into()),
+         Err(e) => return Err(Status::internal(format!("Join error: {}", e))),
+    };
+
+    let compressed_size = output.data.len() as u64;
+    tracing::info!(
+        input_bytes = original_size,
+        output_bytes = compressed_size,
+        reduction_percent = format_args!("{:.2}", compression_ratio(original_size, compressed_size)),
+        "Compression finished"
+    );
+    Ok(output)
+}
+
+/// Response with the statistics of `output`, without the PDF data
+fn statistics(original_size: u64, output: &CompressionOutput) -> CompressResponse {
+    let compressed_size = output.data.len() as u64;
+    CompressResponse {
+        compressed_pdf_data: Vec::new(),
+        original_size,
+        compressed_size,
+        compression_ratio: compression_ratio(original_size, compressed_size),
+        target_reached: output.target_reached,
+        achieved_ratio: output.target_reached.map(|_| output.achieved_ratio),
+        removed_items: output.cleanup.total() as u64,
+        bytes_reclaimed: output.bytes_reclaimed as u64,
+        images: output.report.images.iter().map(image_report_to_proto).collect(),
+        grew: output.report.grew,
+    }
+}
+
+/// Collect a chunked upload: an optional leading config, then the PDF
+async fn receive_upload(mut upload: Streaming<CompressStreamRequest>) -> Result<(Vec<u8>, CompressionConfig), Status> {
+    let mut config = None;
+    let mut pdf_data = Vec::new();
+    while let Some(message) = upload.message().await? {
+        match message.payload {
+            Some(compress_stream_request::Payload::Config(proto_config)) => {
+                if config.is_some() {
+                    return Err(Status::invalid_argument("The config must be the first message and sent only once"));
+                }
+                config = Some(config_from_proto(proto_config));
+            }
+            Some(compress_stream_request::Payload::Chunk(chunk)) => {
+                config.get_or_insert_with(CompressionConfig::default);
+                // Stop reading as soon as the upload is too large, rather than after buffering all of it
+                if pdf_data.len() + chunk.len() > MAX_INPUT_SIZE {
+                    return Err(input_too_large());
+                }
+                pdf_data.extend_from_slice(&chunk);
+            }
+            None => {}
+        }
+    }
+    Ok((pdf_data, config.unwrap_or_default()))
+}
+
+fn input_too_large() -> Status {
+    CompressionError::ResourceLimit(format!("input exceeds the limit of {} bytes", MAX_INPUT_SIZE)).into()
+}
+
+/// Stream the compressed PDF in chunks, followed by its statistics
+fn stream_output(original_size: u64, output: Arc<CompressionOutput>) -> ReceiverStream<Result<CompressStreamResponse, Status>> {
+    // The channel is bounded so chunks are only copied as fast as the client reads them
+    let (sender, receiver) = tokio::sync::mpsc::channel(4);
+    tokio::spawn(async move {
+        for chunk in output.data.chunks(STREAM_CHUNK_SIZE) {
+            let message = CompressStreamResponse { payload: Some(compress_stream_response::Payload::Chunk(chunk.to_vec())) };
+            if sender.send(Ok(message)).await.is_err() {
+                // The client went away
+                return;
+            }
+        }
+        let stats = statistics(original_size, &output);
+        let message = CompressStreamResponse { payload: Some(compress_stream_response::Payload::Stats(stats)) };
+        let _ = sender.send(Ok(message)).await;
+    });
+    ReceiverStream::new(receiver)
+}
 
 #[tonic::async_trait]
 impl CompressionService for HelperService {
@@ -20,47 +303,141 @@ impl CompressionService for HelperService {
         request: Request<CompressRequest>,
     ) -> Result<Response<CompressResponse>, Status> {
         let req = request.into_inner();
-        let pdf_data = req.pdf_data;
+        if req.pdf_data.len() > MAX_INPUT_SIZE {
+            return Err(input_too_large());
+        }
+        let original_size = req.pdf_data.len() as u64;
+        tracing::info!(input_bytes = original_size, "Received compression request");
+
+        // Parse configuration from request or use defaults
+        let config = req.config.map(config_from_proto).unwrap_or_default();
+
+        let output = compress(req.pdf_data, config).await?;
+        let response = CompressResponse { compressed_pdf_data: output.data.clone(), ..statistics(original_size, &output) };
+        Ok(Response::new(response))
+    }
+
+    type CompressPdfStreamStream = ReceiverStream<Result<CompressStreamResponse, Status>>;
+
+    async fn compress_pdf_stream(
+        &self,
+        request: Request<Streaming<CompressStreamRequest>>,
+    ) -> Result<Response<Self::CompressPdfStreamStream>, Status> {
+        let (pdf_data, config) = receive_upload(request.into_inner()).await?;
         let original_size = pdf_data.len() as u64;
+        tracing::info!(input_bytes = original_size, "Received streamed compression request");
 
-        println!("Received compression request: {} bytes", original_size);
+        let output = compress(pdf_data, config).await?;
+        Ok(Response::new(stream_output(original_size, Arc::new(output))))
+    }
 
-        // Parse configuration from request or use defaults
-        let config = if let Some(proto_config) = req.config {
-            CompressionConfig {
-                jpeg_quality: proto_config.jpeg_quality.unwrap_or(30) as u8,
-                max_dimension: proto_config.max_dimension.unwrap_or(600),
-                remove_metadata: proto_config.remove_metadata.unwrap_or(true),
-            }
-        } else {
-            CompressionConfig::default()
-        };
-
-        // Call the compression logic
-        // This is CPU intensive, so we spawn_blocking
-        let compressed_data = match tokio::task::spawn_blocking(move || {
-            compress_pdf_with_config(&pdf_data, config)
-        }).await {
-             Ok(Ok(data)) => data,
-             Ok(Err(e)) => return Err(Status::internal(format!("Compression failed: {}", e))),
-             Err(e) => return Err(Status::internal(format!("Join error: {}", e))),
-        };
-
-        let compressed_size = compressed_data.len() as u64;
-        let compression_ratio = if original_size > 0 {
-            ((original_size - compressed_size) as f32 / original_size as f32) * 100.0
-        } else {
-            0.0
-        };
-
-        println!("Compression finished: {} bytes -> {} bytes ({:.2}% reduction)", 
-                 original_size, compressed_size, compression_ratio);
-
-        Ok(Response::new(CompressResponse {
-            compressed_pdf_data: compressed_data.into(),
-            original_size,
-            compressed_size,
-            compression_ratio,
-        }))
+    async fn create_job(
+        &self,
+        request: Request<Streaming<CompressStreamRequest>>,
+    ) -> Result<Response<pb::JobStatus>, Status> {
+        let (pdf_data, config) = receive_upload(request.into_inner()).await?;
+        tracing::info!(input_bytes = pdf_data.len(), "Received compression job");
+        let status = self.jobs.submit(pdf_data, config).map_err(|e| Status::resource_exhausted(e.to_string()))?;
+        Ok(Response::new(job_status_to_proto(status)))
+    }
+
+    async fn get_job(&self, request: Request<pb::JobRequest>) -> Result<Response<pb::JobStatus>, Status> {
+        let id = request.into_inner().id;
+        let status = self.jobs.status(&id).ok_or_else(|| job_not_found(&id))?;
+        Ok(Response::new(job_status_to_proto(status)))
+    }
+
+    type GetJobResultStream = ReceiverStream<Result<CompressStreamResponse, Status>>;
+
+    async fn get_job_result(
+        &self,
+        request: Request<pb::JobRequest>,
+    ) -> Result<Response<Self::GetJobResultStream>, Status> {
+        let id = request.into_inner().id;
+        match self.jobs.result(&id).ok_or_else(|| job_not_found(&id))? {
+            JobResult::Pending => Err(Status::failed_precondition(format!("Job {} is not finished", id))),
+            JobResult::Failed(error) => Err(error_status(&error)),
+            JobResult::Completed { original_size, output } => Ok(Response::new(stream_output(original_size, output))),
+        }
+    }
+
+    async fn cancel_job(&self, request: Request<pb::JobRequest>) -> Result<Response<pb::CancelJobResponse>, Status> {
+        let id = request.into_inner().id;
+        if !self.jobs.cancel(&id) {
+            return Err(job_not_found(&id));
+        }
+        Ok(Response::new(pb::CancelJobResponse {}))
+    }
+}
+
+fn job_not_found(id: &str) -> Status {
+    Status::not_found(format!("No job with id {}", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pb::compression_service_client::CompressionServiceClient;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::{Channel, Server};
+    use tonic::Code;
+
+    /// A client connected to the service, served on a local port
+    async fn client() -> CompressionServiceClient<Channel> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        tokio::spawn(Server::builder().add_service(CompressionServiceServer::new(HelperService::default())).serve_with_incoming(incoming));
+        CompressionServiceClient::connect(format!("http://{}", address)).await.unwrap()
+    }
+
+    fn config() -> CompressStreamRequest {
+        CompressStreamRequest { payload: Some(compress_stream_request::Payload::Config(pb::CompressionConfig::default())) }
+    }
+
+    fn chunk(data: &[u8]) -> CompressStreamRequest {
+        CompressStreamRequest { payload: Some(compress_stream_request::Payload::Chunk(data.to_vec())) }
+    }
+
+    async fn upload_error(messages: Vec<CompressStreamRequest>) -> Status {
+        let mut client = client().await;
+        client.compress_pdf_stream(tokio_stream::iter(messages)).await.expect_err("the upload is rejected")
+    }
+
+    #[test]
+    fn errors_map_to_status_codes() {
+        let cases = [
+            (CompressionError::MalformedPdf("no header".to_string()), Code::InvalidArgument),
+            (CompressionError::Encrypted, Code::FailedPrecondition),
+            (CompressionError::Unsupported("PubSec security handler".to_string()), Code::FailedPrecondition),
+            (CompressionError::ResourceLimit("too large".to_string()), Code::ResourceExhausted),
+            (CompressionError::Io(std::io::Error::other("disk full")), Code::Internal),
+            (CompressionError::Cancelled, Code::Cancelled),
+        ];
+        for (error, code) in cases {
+            let status = error_status(&error);
+            assert_eq!(status.code(), code, "{}", error);
+            assert_eq!(status.message(), error.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn config_after_a_chunk_is_rejected() {
+        let status = upload_error(vec![chunk(b"%PDF-1.7\n"), config()]).await;
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn second_config_is_rejected() {
+        let status = upload_error(vec![config(), config(), chunk(b"%PDF-1.7\n")]).await;
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn config_first_is_accepted() {
+        // The upload is read in full and only the document is at fault
+        let status = upload_error(vec![config(), chunk(b"not a PDF")]).await;
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert!(!status.message().contains("config"));
     }
 }
@@ -0,0 +1,320 @@
+//! Asynchronous compression jobs, for documents that take longer than a request
+//! may stay open. Jobs are queued, compressed in the background and kept in memory
+//! until they are cancelled or make room for newer ones.
+//!
+//! The store is bounded: it holds at most `capacity` jobs, and when it is full the
+//! oldest finished job is dropped for a new one. At most `concurrency` jobs are
+//! compressed at the same time; the others wait in the queue.
+
+use crate::compression::{compress_pdf_with_progress, CompressionConfig, CompressionError, CompressionOutput, Progress};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+use tokio::sync::Semaphore;
+use tracing::Instrument;
+
+/// Jobs kept by the default store, finished or not
+const DEFAULT_CAPACITY: usize = 32;
+/// Jobs the default store compresses at the same time. Each one already uses every
+/// core for its images
+const DEFAULT_CONCURRENCY: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    /// Waiting for a free slot
+    Queued,
+    Running,
+    /// The result can be downloaded
+    Completed,
+    Failed,
+}
+
+/// Where a job stands
+#[derive(Debug, Clone, serde::Serialize, utoipa::ToSchema)]
+pub struct JobStatus {
+    pub id: String,
+    pub state: JobState,
+    /// Images of the current pass that are done, out of `images_total`
+    pub images_processed: usize,
+    /// Images found in the current pass; 0 until the document has been read
+    pub images_total: usize,
+    /// Compression passes started. More than one only in target size mode, where
+    /// every pass processes the images again
+    pub passes: usize,
+    pub original_size: u64,
+    /// Set once the job is completed
+    pub compressed_size: Option<u64>,
+    /// Why the job failed
+    pub error: Option<String>,
+}
+
+/// What a job has produced so far
+#[derive(Debug, Clone)]
+pub enum JobResult {
+    /// Still queued or running
+    Pending,
+    Completed { original_size: u64, output: Arc<CompressionOutput> },
+    Failed(Arc<CompressionError>),
+}
+
+/// Returned when every job in the store is still queued or running
+#[derive(Debug)]
+pub struct JobStoreFull {
+    capacity: usize,
+}
+
+impl fmt::Display for JobStoreFull {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The job queue is full ({} unfinished jobs), try again later", self.capacity)
+    }
+}
+
+impl std::error::Error for JobStoreFull {}
+
+#[derive(Debug)]
+struct Job {
+    created: Instant,
+    original_size: u64,
+    progress: Arc<Progress>,
+    started: bool,
+    outcome: Option<Result<Arc<CompressionOutput>, Arc<CompressionError>>>,
+}
+
+#[derive(Debug)]
+pub struct JobStore {
+    jobs: Mutex<HashMap<String, Job>>,
+    capacity: usize,
+    running: Semaphore,
+}
+
+impl Default for JobStore {
+    fn default() -> Self {
+        JobStore::new(DEFAULT_CAPACITY, DEFAULT_CONCURRENCY)
+    }
+}
+
+impl JobStore {
+    pub fn new(capacity: usize, concurrency: usize) -> Self {
+        JobStore {
+            jobs: Mutex::new(HashMap::new()),
+            capacity: capacity.max(1),
+            running: Semaphore::new(concurrency.max(1)),
+        }
+    }
+
+    /// Queue `input` for compression. Must be called within a Tokio runtime.
+    pub fn submit(self: &Arc<Self>, input: Vec<u8>, config: CompressionConfig) -> Result<JobStatus, JobStoreFull> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let progress = Arc::new(Progress::default());
+        let job = Job {
+            created: Instant::now(),
+            original_size: input.len() as u64,
+            progress: Arc::clone(&progress),
+            started: false,
+            outcome: None,
+        };
+        let status = {
+            let mut jobs = self.jobs();
+            if jobs.len() >= self.capacity {
+                // Make room by dropping the oldest finished job
+                let oldest = jobs
+                    .iter()
+                    .filter(|(_, job)| job.outcome.is_some())
+                    .min_by_key(|(_, job)| job.created)
+                    .map(|(id, _)| id.clone());
+                match oldest {
+                    Some(oldest) => jobs.remove(&oldest),
+                    None => return Err(JobStoreFull { capacity: self.capacity }),
+                };
+            }
+            let status = job_status(&id, &job);
+            jobs.insert(id.clone(), job);
+            status
+        };
+
+        let span = tracing::info_span!("job", id = %id);
+        let store = Arc::clone(self);
+        tokio::spawn(async move { store.run(id, input, config, progress).await }.instrument(span));
+        Ok(status)
+    }
+
+    pub fn status(&self, id: &str) -> Option<JobStatus> {
+        self.jobs().get(id).map(|job| job_status(id, job))
+    }
+
+    pub fn result(&self, id: &str) -> Option<JobResult> {
+        self.jobs().get(id).map(|job| match &job.outcome {
+            None => JobResult::Pending,
+            Some(Ok(output)) => JobResult::Completed { original_size: job.original_size, output: Arc::clone(output) },
+            Some(Err(error)) => JobResult::Failed(Arc::clone(error)),
+        })
+    }
+
+    /// Stop the job if it is still queued or running and drop it with its result.
+    /// Returns false for unknown jobs.
+    ///
+    /// The job is gone from the store at once, so its status and result are not
+    /// found from then on. A running job's compression only stops at its next
+    /// image, and holds its slot among the `concurrency` running jobs until then.
+    pub fn cancel(&self, id: &str) -> bool {
+        match self.jobs().remove(id) {
+            Some(job) => {
+                job.progress.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn run(&self, id: String, input: Vec<u8>, config: CompressionConfig, progress: Arc<Progress>) {
+        // The semaphore is never closed
+        let Ok(_permit) = self.running.acquire().await else { return };
+        if progress.is_cancelled() {
+            return;
+        }
+        if let Some(job) = self.jobs().get_mut(&id) {
+            job.started = true;
+        }
+
+        let span = tracing::Span::current();
+        let task_progress = Arc::clone(&progress);
+        let outcome = tokio::task::spawn_blocking(move || {
+            span.in_scope(|| compress_pdf_with_progress(&input, config, &task_progress))
+        })
+        .await
+        .unwrap_or_else(|e| Err(CompressionError::Io(std::io::Error::other(e))));
+        match &outcome {
+            Ok(output) => tracing::info!(output_bytes = output.data.len(), "Job completed"),
+            Err(CompressionError::Cancelled) => tracing::info!("Job cancelled"),
+            Err(e) => tracing::warn!(error = %e, "Job failed"),
+        }
+
+        // A cancelled job is no longer in the store
+        if let Some(job) = self.jobs().get_mut(&id) {
+            job.outcome = Some(outcome.map(Arc::new).map_err(Arc::new));
+        }
+    }
+
+    fn jobs(&self) -> MutexGuard<'_, HashMap<String, Job>> {
+        // The map stays consistent even if a holder panicked
+        self.jobs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn job_status(id: &str, job: &Job) -> JobStatus {
+    let state = match &job.outcome {
+        Some(Ok(_)) => JobState::Completed,
+        Some(Err(_)) => JobState::Failed,
+        None if job.started => JobState::Running,
+        None => JobState::Queued,
+    };
+    JobStatus {
+        id: id.to_string(),
+        state,
+        images_processed: job.progress.images_processed(),
+        images_total: job.progress.images_total(),
+        passes: job.progress.passes(),
+        original_size: job.original_size,
+        compressed_size: match &job.outcome {
+            Some(Ok(output)) => Some(output.data.len() as u64),
+            _ => None,
+        },
+        error: match &job.outcome {
+            Some(Err(error)) => Some(error.to_string()),
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{dictionary, Document, Object};
+    use std::time::Duration;
+
+    /// A one page document without images
+    fn blank_document() -> Vec<u8> {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let page = doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id, "MediaBox" => vec![0.into(), 0.into(), 10.into(), 10.into()] });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+        let mut data = Vec::new();
+        doc.save_to(&mut data).unwrap();
+        data
+    }
+
+    /// Poll the job until it is completed or failed
+    async fn finished(store: &JobStore, id: &str) -> JobStatus {
+        loop {
+            let status = store.status(id).expect("the job is in the store");
+            if matches!(status.state, JobState::Completed | JobState::Failed) {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn full_store_of_unfinished_jobs_rejects_new_ones() {
+        let store = Arc::new(JobStore::new(2, 1));
+        // Holding the only slot keeps every job queued
+        let _slot = store.running.acquire().await.unwrap();
+        store.submit(blank_document(), CompressionConfig::default()).unwrap();
+        store.submit(blank_document(), CompressionConfig::default()).unwrap();
+
+        assert!(store.submit(blank_document(), CompressionConfig::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn oldest_finished_job_makes_room() {
+        let store = Arc::new(JobStore::new(2, 1));
+        let oldest = store.submit(b"not a PDF".to_vec(), CompressionConfig::default()).unwrap().id;
+        assert_eq!(finished(&store, &oldest).await.state, JobState::Failed);
+        let newer = store.submit(blank_document(), CompressionConfig::default()).unwrap().id;
+        assert_eq!(finished(&store, &newer).await.state, JobState::Completed);
+
+        let newest = store.submit(blank_document(), CompressionConfig::default()).unwrap().id;
+
+        assert!(store.status(&oldest).is_none());
+        assert!(store.status(&newer).is_some());
+        assert!(store.status(&newest).is_some());
+    }
+
+    #[tokio::test]
+    async fn cancelled_queued_job_is_never_compressed() {
+        let store = Arc::new(JobStore::new(2, 1));
+        let slot = store.running.acquire().await.unwrap();
+        let id = store.submit(blank_document(), CompressionConfig::default()).unwrap().id;
+        let progress = Arc::clone(&store.jobs().get(&id).unwrap().progress);
+
+        assert!(store.cancel(&id));
+        assert!(store.status(&id).is_none());
+        assert!(!store.cancel(&id));
+
+        // The permit queue is fair, so the job gets the slot before the test does again
+        drop(slot);
+        let _slot = store.running.acquire().await.unwrap();
+        assert_eq!(progress.passes(), 0);
+    }
+
+    #[tokio::test]
+    async fn completed_job_has_a_result() {
+        let store = Arc::new(JobStore::default());
+        let input = blank_document();
+        let id = store.submit(input.clone(), CompressionConfig::default()).unwrap().id;
+
+        let status = finished(&store, &id).await;
+
+        assert_eq!(status.state, JobState::Completed);
+        assert_eq!(status.original_size, input.len() as u64);
+        let Some(JobResult::Completed { original_size, output }) = store.result(&id) else { panic!("the job has no result") };
+        assert_eq!(original_size, input.len() as u64);
+        assert_eq!(status.compressed_size, Some(output.data.len() as u64));
+        assert_eq!(status.passes, 1);
+        Document::load_mem(&output.data).unwrap();
+    }
+}
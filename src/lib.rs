@@ -1,3 +1,4 @@
 pub mod compression;
 pub mod grpc;
 pub mod api;
+pub mod jobs;
@@ -1,28 +1,42 @@
 use rustpdf::grpc;
 use rustpdf::api;
+use rustpdf::jobs::JobStore;
+use std::sync::Arc;
 use std::net::SocketAddr;
 use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize logging
-    tracing_subscriber::fmt()
-        .with_span_events(FmtSpan::CLOSE)
-        .init();
+    // Initialize logging. RUST_LOG selects what is logged (default: info), and
+    // LOG_FORMAT=json writes one JSON object per line for log aggregators.
+    // Closed spans are logged with their timings.
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+    if std::env::var("LOG_FORMAT").is_ok_and(|format| format.eq_ignore_ascii_case("json")) {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+
+    tracing::info!("Starting Rust PDF Compression Service...");
 
-    println!("Starting Rust PDF Compression Service...");
+    // Jobs submitted over either API can be queried over both
+    let jobs = Arc::new(JobStore::default());
 
     // gRPC Server setup
     let grpc_addr = "[::1]:50051".parse()?;
-    let grpc_service = grpc::HelperService::default();
+    let grpc_service = grpc::HelperService::new(Arc::clone(&jobs));
     
     // REST API setup
-    let app = api::app_router();
+    let app = api::app_router(jobs);
     let rest_addr = SocketAddr::from(([127, 0, 0, 1], 3000));
 
-    println!("gRPC listening on {}", grpc_addr);
-    println!("REST listening on http://{}", rest_addr);
-    println!("Swagger UI available at http://{}/swagger-ui/", rest_addr);
+    tracing::info!("gRPC listening on {}", grpc_addr);
+    tracing::info!("REST listening on http://{}", rest_addr);
+    tracing::info!("Swagger UI available at http://{}/swagger-ui/", rest_addr);
 
     // Run both servers
     // Note: In production you might want better graceful shutdown coordination
@@ -38,10 +52,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     tokio::select! {
         result = grpc_server => {
-             println!("gRPC server failed: {:?}", result);
+             tracing::error!("gRPC server failed: {:?}", result);
         },
         result = rest_server => {
-             println!("REST server failed: {:?}", result);
+             tracing::error!("REST server failed: {:?}", result);
         }
     }
 